@@ -13,12 +13,9 @@ use log::{
 };
 use env_logger::Builder;
 
-use mqtt_sn_tools_rs::mqttsn::constants::{
-    MQTT_SN_MAX_PAYLOAD_LENGTH, 
-    MQTT_SN_TOPIC_TYPE_NORMAL, 
-    MQTT_SN_TOPIC_TYPE_PREDEFINED, 
-    MQTT_SN_TOPIC_TYPE_SHORT,
-};
+use mqtt_sn_tools_rs::mqttsn::constants::MQTT_SN_FRWDENCAP_RADIUS_MASK;
+
+use mqtt_sn_tools_rs::mqttsn::flags::{QoS, TopicIdType};
 
 use mqtt_sn_tools_rs::mqttsn::pubsub::mqtt_sn_connect;
 use mqtt_sn_tools_rs::mqttsn::settings::{
@@ -27,6 +24,10 @@ use mqtt_sn_tools_rs::mqttsn::settings::{
 };
 
 use mqtt_sn_tools_rs::mqttsn::pubsub::{
+    GatewayInfo,
+    mqtt_sn_discover_gateway,
+    mqtt_sn_enable_frwdencap,
+    mqtt_sn_max_payload_length,
     mqtt_sn_send_publish,
     mqtt_sn_send_register,
     mqtt_sn_send_disconnect,
@@ -34,21 +35,37 @@ use mqtt_sn_tools_rs::mqttsn::pubsub::{
     mqtt_sn_receive_regack,
 };
 
+use std::net::SocketAddr;
+
 use mqtt_sn_tools_rs::mqttsn::network_abstractions::{
+    AddressFamily,
+    DtlsSecurity,
+    MulticastInterface,
+    MulticastOptions,
+    PeerAddress,
     SensorNetwork,
     SensorNetworkType,
     SensorNetworkInitArgs,
+    bind_address_for,
     create_sensor_network,
+    parse_psk_key,
+    resolve_address,
 };
 
 
-fn usage() {
+fn usage() -> ! {
     let defaults = default_settings();
     eprintln!("Usage: mqtt-sn-pub-rs [opts] -t <topic> -m <message>\n");
     eprintln!();
     eprintln!("  -d             Increase debug level by one. -d can occur multiple times.");
     eprintln!("  -f <file>      A file to send as the message payload.");
-    eprintln!("  -h <host>      MQTT-SN host to connect to. Defaults to '{}'.", defaults.mqtt_sn_host);
+    eprintln!("  -h <host>      MQTT-SN host to connect to, a name or an IP address. IPv6 ones may be in brackets. Defaults to '{}'.", defaults.mqtt_sn_host);
+    eprintln!("  -4             Reach the gateway over IPv4 only.");
+    eprintln!("  -6             Reach the gateway over IPv6 only.");
+    eprintln!("  --discover     Find the gateway with SEARCHGW instead of using -h. Broadcasts to the port given with -p, or sends to --mcast-group.");
+    eprintln!("  --mcast-group <group>  Multicast group, IPv4 or IPv6, to discover the gateway on instead of broadcasting.");
+    eprintln!("  --mcast-hops <hops>    TTL or hop limit of the multicast SEARCHGW. Defaults to {}.", defaults.multicast_hops);
+    eprintln!("  --mcast-if <interface> Interface to join the group on: an IPv4 address for IPv4 groups, an interface index for IPv6 ones.");
     eprintln!("  -i <clientid>  ID to use for this client. Defaults to 'mqtt-sn-tools-rs-' with process id.");
     eprintln!("  -k <keepalive> keep alive in seconds for this client. Defaults to {}.", defaults.keep_alive);
     eprintln!("  -e <sleep>     sleep duration in seconds when disconnecting. Defaults to {}.", defaults.sleep_duration);
@@ -56,16 +73,29 @@ fn usage() {
     eprintln!("  -l             Read from STDIN, one message per line.");
     eprintln!("  -n             Send a null (zero length) message.");
     eprintln!("  -p <port>      Network port to connect to. Defaults to '{}'.", defaults.mqtt_sn_port);
-    eprintln!("  -q <qos>       Quality of Service value (0, 1 or -1). Defaults to {}.", defaults.qos);
+    eprintln!("  -q <qos>       Quality of Service value (0, 1, 2 or -1). Defaults to {}.", defaults.qos);
     eprintln!("  -r             Message should be retained.");
     eprintln!("  -s             Read one whole message from STDIN.");
     eprintln!("  -t <topic>     MQTT-SN topic name to publish to.");
     eprintln!("  -T <topicid>   Pre-defined MQTT-SN topic ID to publish to.");
     eprintln!("  --fe           Enables Forwarder Encapsulation. MQTT-SN packets are encapsulated according to MQTT-SN Protocol Specification v1.2, chapter 5.5 Forwarder Encapsulation.");
-    eprintln!("  --wlnid        If Forwarder Encapsulation is enabled, wireless node ID for this client. Defaults to process id.");
+    eprintln!("  --wlnid        If Forwarder Encapsulation is enabled, wireless node ID for this client. Defaults to 0.");
+    eprintln!("  --radius <radius>  If Forwarder Encapsulation is enabled, broadcast radius of the encapsulated packets (0 to 3). Defaults to {}.", defaults.forwarder_radius);
     eprintln!("  --cport <port> Source port for outgoing packets. Uses port in ephemeral range if not specified or set to {}.", defaults.source_port);
+    eprintln!("  --transport <transport>  Transport to the gateway, udp, tcp or dtls. Defaults to udp.");
+    eprintln!("  --psk-identity <identity>  Identity of the DTLS pre-shared key.");
+    eprintln!("  --psk <key>           DTLS pre-shared key, in hex.");
+    eprintln!("  --cafile <file>       PEM file with the CAs the gateway certificate is checked against over DTLS.");
+    eprintln!("  --cert <file>         PEM file with the client certificate for DTLS.");
+    eprintln!("  --key <file>          PEM file with the private key of --cert. Defaults to the --cert file.");
+    eprintln!("  --will-topic <topic>  Topic for the Last Will and Testament message.");
+    eprintln!("  --will-message <msg>  Payload of the Last Will and Testament message.");
+    eprintln!("  --will-qos <qos>      QoS of the Last Will and Testament message (0, 1 or 2). Defaults to {}.", defaults.will_qos);
+    eprintln!("  --will-retain         Last Will and Testament message should be retained.");
     eprintln!("  --loop-freq    Frequency in Hz to send messages. Defaults to 0 (disabled).");
     eprintln!("  --count        Number of messages to send in loop. Defaults to 0 (loops forever).");
+    eprintln!("  --net-timeout  Milliseconds to wait for an answer before resending a packet. Defaults to {}.", defaults.network_timeout);
+    eprintln!("  --net-retries  Number of times an unanswered packet is resent. Defaults to {}.", defaults.network_retries);
     std::process::exit(1);
 }
 
@@ -87,6 +117,34 @@ fn parse_args() -> Settings{
                 i += 1;
                 settings.mqtt_sn_host = args[i].clone();
             }
+            "-4" => {
+                settings.address_family = AddressFamily::IPv4;
+            }
+            "-6" => {
+                settings.address_family = AddressFamily::IPv6;
+            }
+            "--discover" => {
+                settings.discover = true;
+            }
+            "--mcast-group" => {
+                i += 1;
+                settings.multicast_group = args[i].clone();
+            }
+            "--mcast-hops" => {
+                i += 1;
+                settings.multicast_hops = args[i].parse().unwrap();
+            }
+            "--mcast-if" => {
+                i += 1;
+                settings.multicast_interface = match (args[i].parse(), args[i].parse()) {
+                    (Ok(address), _) => Some(MulticastInterface::Address(address)),
+                    (_, Ok(index)) => Some(MulticastInterface::Index(index)),
+                    _ => {
+                        error!("Invalid multicast interface: {}", args[i]);
+                        usage();
+                    }
+                };
+            }
             "-i" => {
                 i += 1;
                 settings.client_id = args[i].clone();
@@ -116,7 +174,13 @@ fn parse_args() -> Settings{
             }
             "-q" => {
                 i += 1;
-                settings.qos = args[i].parse().unwrap();
+                settings.qos = match args[i].parse() {
+                    Ok(qos) => qos,
+                    Err(_) => {
+                        error!("Invalid QoS value: {}", args[i]);
+                        usage();
+                    }
+                };
             }
             "-r" => {
                 settings.retain = true;
@@ -139,10 +203,79 @@ fn parse_args() -> Settings{
                 i += 1;
                 settings.wireless_node_id = args[i].parse().unwrap();
             }
+            "--radius" => {
+                i += 1;
+                settings.forwarder_radius = match args[i].parse() {
+                    Ok(radius) if radius <= MQTT_SN_FRWDENCAP_RADIUS_MASK => radius,
+                    _ => {
+                        error!("Invalid radius: {}", args[i]);
+                        usage();
+                    }
+                };
+            }
             "--cport" => {
                 i += 1;
                 settings.source_port = args[i].parse().unwrap();
             }
+            "--transport" => {
+                i += 1;
+                settings.transport = match args[i].as_str() {
+                    "udp" => SensorNetworkType::UDP,
+                    "tcp" => SensorNetworkType::TCP,
+                    "dtls" => SensorNetworkType::DTLS,
+                    _ => {
+                        error!("Unknown transport: {}", args[i]);
+                        usage();
+                    }
+                };
+            }
+            "--psk-identity" => {
+                i += 1;
+                settings.dtls.psk_identity = args[i].clone();
+            }
+            "--psk" => {
+                i += 1;
+                settings.dtls.psk_key = match parse_psk_key(&args[i]) {
+                    Ok(key) => key,
+                    Err(e) => {
+                        error!("{}", e);
+                        usage();
+                    }
+                };
+            }
+            "--cafile" => {
+                i += 1;
+                settings.dtls.ca_file = args[i].clone();
+            }
+            "--cert" => {
+                i += 1;
+                settings.dtls.cert_file = args[i].clone();
+            }
+            "--key" => {
+                i += 1;
+                settings.dtls.key_file = args[i].clone();
+            }
+            "--will-topic" => {
+                i += 1;
+                settings.will_topic = args[i].clone();
+            }
+            "--will-message" => {
+                i += 1;
+                settings.will_message = args[i].clone();
+            }
+            "--will-qos" => {
+                i += 1;
+                settings.will_qos = match args[i].parse() {
+                    Ok(qos) => qos,
+                    Err(_) => {
+                        error!("Invalid will QoS value: {}", args[i]);
+                        usage();
+                    }
+                };
+            }
+            "--will-retain" => {
+                settings.will_retain = true;
+            }
             
             "--loop-freq" => {
                 i += 1;
@@ -152,6 +285,14 @@ fn parse_args() -> Settings{
                 i += 1;
                 settings.loop_count = args[i].parse::<u64>().unwrap();
             }
+            "--net-timeout" => {
+                i += 1;
+                settings.network_timeout = args[i].parse::<u64>().unwrap();
+            }
+            "--net-retries" => {
+                i += 1;
+                settings.network_retries = args[i].parse::<u8>().unwrap();
+            }
             _ => {
                 error!("Unknown option: {}", args[i]);
                 usage();
@@ -163,33 +304,26 @@ fn parse_args() -> Settings{
     // Check for missing arguments
     // The required arguments are topic_name or topic_id, and message or
     // file.
-    if (settings.topic == "" && settings.topic_id == 0) || ((settings.message == "" && !settings.null_message) && settings.file == "") {
+    if (settings.topic.is_empty() && settings.topic_id == 0) || ((settings.message.is_empty() && !settings.null_message) && settings.file.is_empty()) {
         error!("Missing required arguments.");
         usage();
     }
 
-    // Check for invalid arguments
-    // The QoS value must be 0, 1 or -1
-    if settings.qos != 0 && settings.qos != 1 && settings.qos != -1 {
-        error!("Invalid QoS value: {}", settings.qos);
-        usage();
-    }
-
     // Either topic or topic_id must be provided
-    if settings.topic != "" && settings.topic_id != 0 {
+    if !settings.topic.is_empty() && settings.topic_id != 0 {
         error!("Both topic and topic_id provided. Only one is allowed.");
         usage();
     }
 
     // Only a message or a file can be provided
-    if settings.message != "" && settings.file != "" {
+    if !settings.message.is_empty() && !settings.file.is_empty() {
         error!("Both message and file provided. Only one is allowed.");
         usage();
     }
 
     // Topic must be valid for QoS -1
     // That means either a short topic name or a pre-defined topic ID
-    if (settings.qos == -1) && (settings.topic_id == 0) && (settings.topic.len() != 2) {
+    if (settings.qos == QoS::MinusOne) && (settings.topic_id == 0) && (settings.topic.len() != 2) {
         error!("Either a short topic name or a pre-defined topic ID must be provided for QoS -1.");
         usage();
     }
@@ -200,6 +334,58 @@ fn parse_args() -> Settings{
         usage();
     }
 
+    // The will QoS value must be 0, 1 or 2
+    if settings.will_qos == QoS::MinusOne {
+        error!("Invalid will QoS value: {}", settings.will_qos);
+        usage();
+    }
+
+    // IPv6 has no broadcast, SEARCHGW goes to a multicast group
+    if settings.discover && settings.address_family == AddressFamily::IPv6 && settings.multicast_group.is_empty() {
+        error!("Gateway discovery over IPv6 needs a multicast group (--mcast-group).");
+        usage();
+    }
+
+    // The gateway is found by broadcasting over UDP, and a TCP
+    // connection always starts from an ephemeral port
+    if settings.transport == SensorNetworkType::TCP && settings.discover {
+        error!("Gateway discovery is only supported over UDP.");
+        usage();
+    }
+    if settings.transport == SensorNetworkType::TCP && settings.source_port != 0 {
+        error!("A source port can only be set over UDP.");
+        usage();
+    }
+
+    // DTLS needs something to authenticate the gateway with
+    let dtls_options = !settings.dtls.psk_identity.is_empty()
+        || !settings.dtls.psk_key.is_empty()
+        || !settings.dtls.ca_file.is_empty()
+        || !settings.dtls.cert_file.is_empty()
+        || !settings.dtls.key_file.is_empty();
+    if dtls_options && settings.transport != SensorNetworkType::DTLS {
+        error!("DTLS options need --transport dtls.");
+        usage();
+    }
+    if settings.dtls.psk_identity.is_empty() != settings.dtls.psk_key.is_empty() {
+        error!("A pre-shared key needs both --psk-identity and --psk.");
+        usage();
+    }
+    if !settings.dtls.key_file.is_empty() && settings.dtls.cert_file.is_empty() {
+        error!("Private key provided without client certificate.");
+        usage();
+    }
+    if settings.transport == SensorNetworkType::DTLS && settings.dtls.psk_key.is_empty() && settings.dtls.ca_file.is_empty() {
+        error!("DTLS needs a pre-shared key (--psk-identity and --psk) or a CA file (--cafile).");
+        usage();
+    }
+
+    // A will message makes no sense without a will topic
+    if settings.will_topic.is_empty() && !settings.will_message.is_empty() {
+        error!("Will message provided without will topic.");
+        usage();
+    }
+
     settings
 }
 
@@ -207,6 +393,8 @@ fn parse_args() -> Settings{
 // Placeholder for publish_file
 fn publish_file(sensor_net: &mut dyn SensorNetwork, settings: &Settings) {
     let mut message: String;
+    // Larger messages fit if the network carries long packets
+    let max_payload_length = mqtt_sn_max_payload_length(sensor_net);
     // Open the file
     // If it is -, read from STDIN
     // Otherwise, read from the file
@@ -222,33 +410,96 @@ fn publish_file(sensor_net: &mut dyn SensorNetwork, settings: &Settings) {
             let line = line.unwrap();
             // Check if the line is empty
             // If so, skip it
-            if line == "" {
+            if line.is_empty() {
                 continue;
             }
             // Check if the line is too long
             // If so, truncate it
-            if line.len() > MQTT_SN_MAX_PAYLOAD_LENGTH {
-                warn!("Line too long. Truncating to {} bytes.", MQTT_SN_MAX_PAYLOAD_LENGTH);
-                message  = line[..MQTT_SN_MAX_PAYLOAD_LENGTH].to_string();
+            if line.len() > max_payload_length {
+                warn!("Line too long. Truncating to {} bytes.", max_payload_length);
+                message  = line[..max_payload_length].to_string();
             } else {
                 message = line;
             }
             // Publish
-            mqtt_sn_send_publish(sensor_net, &settings, &message);
+            if let Err(e) = mqtt_sn_send_publish(sensor_net, settings, &message) {
+                error!("Could not publish the message: {}", e);
+                std::process::exit(1);
+            }
         }
     } else {
-        // Read the file up to MQTT_SN_MAX_PAYLOAD_LENGTH
-        let mut buffer = vec![0; MQTT_SN_MAX_PAYLOAD_LENGTH];
+        // Read the file up to max_payload_length
+        let mut buffer = vec![0; max_payload_length];
         let bytes_read = file.read(&mut buffer).expect("Failed to read file.");
 
         // Strip the buffer of any null bytes
-        if bytes_read < MQTT_SN_MAX_PAYLOAD_LENGTH {
+        if bytes_read < max_payload_length {
             buffer.truncate(bytes_read);
         }
         // Publish
         let message = String::from_utf8(buffer).expect("Failed to convert buffer to string.");
         // Publish
-        mqtt_sn_send_publish(sensor_net, &settings, message.as_str());
+        if let Err(e) = mqtt_sn_send_publish(sensor_net, settings, message.as_str()) {
+            error!("Could not publish the message: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Broadcast a SEARCHGW, or send it to the multicast group, and return
+// the address of the chosen gateway
+fn discover_gateway(settings: &Settings) -> SocketAddr {
+    let discovery_args = if settings.multicast_group.is_empty() {
+        SensorNetworkInitArgs::UDP {
+            source_address: format!("0.0.0.0:{}", settings.source_port),
+            destination_address: format!("255.255.255.255:{}", settings.mqtt_sn_port),
+            timeout: std::time::Duration::from_secs(1),
+            broadcast: true,
+        }
+    } else {
+        let group = match resolve_address(&settings.multicast_group, settings.mqtt_sn_port, settings.address_family) {
+            Ok(group) => group,
+            Err(e) => {
+                error!("Invalid multicast group: {}", e);
+                std::process::exit(1);
+            }
+        };
+        // Gateways answer to the group, on its port
+        SensorNetworkInitArgs::UDPMulticast {
+            source_address: bind_address_for(&group, settings.mqtt_sn_port).to_string(),
+            group_address: group.to_string(),
+            timeout: std::time::Duration::from_secs(1),
+            options: MulticastOptions {
+                hops: settings.multicast_hops,
+                interface: settings.multicast_interface,
+                ..MulticastOptions::default()
+            },
+        }
+    };
+    let boxed_discovery_net = create_sensor_network(SensorNetworkType::UDP, discovery_args);
+    let mut boxed_discovery_net = match boxed_discovery_net {
+        Ok(discovery_net) => discovery_net,
+        Err(e) => {
+            error!("Could not open the network for discovery: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let discovery_net = &mut *boxed_discovery_net;
+    if let Err(e) = discovery_net.initialize() {
+        error!("Could not open the network for discovery: {}", e);
+        std::process::exit(1);
+    }
+
+    match mqtt_sn_discover_gateway(discovery_net, settings) {
+        Ok(GatewayInfo { address: Some(PeerAddress::Socket(address)), .. }) => address,
+        Ok(GatewayInfo { address: Some(address), .. }) => {
+            error!("Invalid gateway address: {}", address);
+            std::process::exit(1);
+        }
+        _ => {
+            error!("Gateway discovery failed.");
+            std::process::exit(1);
+        }
     }
 }
 
@@ -275,35 +526,94 @@ fn main(){
     // Print the settings
     debug!("{:?}", settings);
 
+    // Talk to the gateway through a forwarder, if requested
+    if settings.forwarder_encapsulation {
+        mqtt_sn_enable_frwdencap(&settings);
+    }
+
+    // Find the gateway, if requested, or resolve the host given with -h
+    let destination = if settings.discover {
+        let destination = discover_gateway(&settings);
+        info!("Using gateway at {}", destination);
+        destination
+    } else {
+        match resolve_address(&settings.mqtt_sn_host, settings.mqtt_sn_port, settings.address_family) {
+            Ok(destination) => destination,
+            Err(e) => {
+                error!("Could not resolve the gateway address: {}", e);
+                std::process::exit(1);
+            }
+        }
+    };
+    let destination_address = destination.to_string();
+
     // First create a connection
-    let sensor_net_args = SensorNetworkInitArgs::UDP {
-        source_address: format!("0.0.0.0:{}", settings.source_port),
-        destination_address: format!("{}:{}", settings.mqtt_sn_host, settings.mqtt_sn_port),
-        timeout: settings.timeout,
+    let sensor_net_args = match settings.transport {
+        SensorNetworkType::TCP => SensorNetworkInitArgs::TCP {
+            destination_address,
+            timeout: std::time::Duration::from_millis(settings.network_timeout),
+        },
+        // The gateway certificate is checked against the name given with
+        // -h, or its address if it was discovered
+        SensorNetworkType::DTLS => SensorNetworkInitArgs::DTLS {
+            source_address: bind_address_for(&destination, settings.source_port).to_string(),
+            destination_address,
+            timeout: std::time::Duration::from_millis(settings.network_timeout),
+            security: DtlsSecurity {
+                server_name: if settings.discover {
+                    destination.ip().to_string()
+                } else {
+                    settings.mqtt_sn_host.trim_start_matches('[').trim_end_matches(']').to_string()
+                },
+                ..settings.dtls.clone()
+            },
+        },
+        _ => SensorNetworkInitArgs::UDP {
+            source_address: bind_address_for(&destination, settings.source_port).to_string(),
+            destination_address,
+            timeout: std::time::Duration::from_millis(settings.network_timeout),
+            broadcast: false,
+        },
+    };
+    let mut boxed_sensor_net = match create_sensor_network(settings.transport, sensor_net_args) {
+        Ok(sensor_net) => sensor_net,
+        Err(e) => {
+            error!("Could not open the network: {}", e);
+            std::process::exit(1);
+        }
     };
-    let mut boxed_sensor_net = create_sensor_network(SensorNetworkType::UDP, sensor_net_args);
 
     let sensor_net = &mut *boxed_sensor_net;
-    sensor_net.initialize();
+    if let Err(e) = sensor_net.initialize() {
+        error!("Could not open the network: {}", e);
+        std::process::exit(1);
+    }
 
-    if settings.qos >= 0 {
+    if settings.qos != QoS::MinusOne {
         // Send a CONNECT message
-        mqtt_sn_connect(sensor_net, &settings);
+        if let Err(e) = mqtt_sn_connect(sensor_net, &settings) {
+            error!("Could not connect to the gateway: {}", e);
+            std::process::exit(1);
+        }
         
         // Then check if the topic is a pre-defined topic ID
         if settings.topic_id != 0 {
             // Use a pre-defined topic ID
-            settings.topic_id_type = MQTT_SN_TOPIC_TYPE_PREDEFINED;
+            settings.topic_id_type = TopicIdType::Predefined;
         } else if settings.topic.len() == 2 {
             // Use a short topic name
-            settings.topic_id_type = MQTT_SN_TOPIC_TYPE_SHORT;
+            settings.topic_id_type = TopicIdType::Short;
             // Convert the 2 character topic name into a 2 byte topic ID
             settings.topic_id = ((settings.topic.as_bytes()[0] as u16) << 8) | (settings.topic.as_bytes()[1] as u16);
-        } else if settings.qos >= 0 {
+        } else if settings.qos != QoS::MinusOne {
             // Send a REGISTER message
-            mqtt_sn_send_register(sensor_net, &settings);
-            mqtt_sn_receive_regack(sensor_net, &settings);
-            settings.topic_id_type = MQTT_SN_TOPIC_TYPE_NORMAL;
+            if let Err(e) = mqtt_sn_send_register(sensor_net, &settings)
+                .and_then(|_| mqtt_sn_receive_regack(sensor_net, &settings))
+            {
+                error!("Could not register the topic: {}", e);
+                std::process::exit(1);
+            }
+            settings.topic_id_type = TopicIdType::Normal;
         }
 
         if settings.loop_count  > 0 {
@@ -319,27 +629,33 @@ fn main(){
                 settings.loop_count -= 1;
             }
             // Publish the message to the topic
-            if settings.file != "" {
+            if !settings.file.is_empty() {
                 publish_file(sensor_net, &settings);
             } else {
-                mqtt_sn_send_publish(sensor_net, &settings, ""); 
+                if let Err(e) = mqtt_sn_send_publish(sensor_net, &settings, "") {
+                    error!("Could not publish the message: {}", e);
+                    std::process::exit(1);
+                }
             }
 
             if settings.loop_frequency == 0 {
                 break;
             }
             // Sleep for the loop frequency
-            let sleep_time_in_us = 1_000_000 / settings.loop_frequency as u64;
-            std::thread::sleep(std::time::Duration::from_micros(sleep_time_in_us as u64));
+            let sleep_time_in_us = 1_000_000 / settings.loop_frequency;
+            std::thread::sleep(std::time::Duration::from_micros(sleep_time_in_us));
 
             if settings.loop_count == 0 {
                 break;
             }
         }
         // Disconnect
-        if settings.qos >= 0 {
-            mqtt_sn_send_disconnect(sensor_net, &settings);
-            mqtt_sn_receive_disconnect(sensor_net, &settings);
+        if settings.qos != QoS::MinusOne {
+            if let Err(e) = mqtt_sn_send_disconnect(sensor_net, &settings)
+                .and_then(|_| mqtt_sn_receive_disconnect(sensor_net, &settings))
+            {
+                warn!("Could not disconnect cleanly: {}", e);
+            }
         }
     }
 }
\ No newline at end of file
@@ -13,12 +13,9 @@ use log::{
 };
 use env_logger::Builder;
 
-use mqtt_sn_tools_rs::mqttsn::constants::{
-    MQTT_SN_MAX_PAYLOAD_LENGTH, 
-    MQTT_SN_TOPIC_TYPE_NORMAL, 
-    MQTT_SN_TOPIC_TYPE_PREDEFINED, 
-    MQTT_SN_TOPIC_TYPE_SHORT,
-};
+use mqtt_sn_tools_rs::mqttsn::constants::MQTT_SN_FRWDENCAP_RADIUS_MASK;
+
+use mqtt_sn_tools_rs::mqttsn::flags::{QoS, TopicIdType};
 
 use mqtt_sn_tools_rs::mqttsn::pubsub::mqtt_sn_connect;
 use mqtt_sn_tools_rs::mqttsn::settings::{
@@ -27,6 +24,8 @@ use mqtt_sn_tools_rs::mqttsn::settings::{
 };
 
 use mqtt_sn_tools_rs::mqttsn::pubsub::{
+    mqtt_sn_enable_frwdencap,
+    mqtt_sn_max_payload_length,
     mqtt_sn_send_publish,
     mqtt_sn_send_register,
     mqtt_sn_send_disconnect,
@@ -42,7 +41,7 @@ use mqtt_sn_tools_rs::mqttsn::network_abstractions::{
 };
 
 
-fn usage() {
+fn usage() -> ! {
     let defaults = default_settings();
     eprintln!("Usage: mqtt-sn-serial-pub-rs [opts] -t <topic> -m <message>\n");
     eprintln!();
@@ -56,18 +55,23 @@ fn usage() {
     eprintln!("  -n             Send a null (zero length) message.");
     eprintln!("  -p <port>      Serial port to connect to. Defaults to '{}'.", defaults.serial_port);
     eprintln!("  -b <baudrate>  Baud rate for serial connection. Defaults to {}.", defaults.baudrate);
-    eprintln!("  -q <qos>       Quality of Service value (0, 1 or -1). Defaults to {}.", defaults.qos);
+    eprintln!("  -q <qos>       Quality of Service value (0, 1, 2 or -1). Defaults to {}.", defaults.qos);
     eprintln!("  -r             Message should be retained.");
     eprintln!("  -s             Read one whole message from STDIN.");
     eprintln!("  -t <topic>     MQTT-SN topic name to publish to.");
     eprintln!("  -T <topicid>   Pre-defined MQTT-SN topic ID to publish to.");
     eprintln!("  --fe           Enables Forwarder Encapsulation. MQTT-SN packets are encapsulated according to MQTT-SN Protocol Specification v1.2, chapter 5.5 Forwarder Encapsulation.");
-    eprintln!("  --wlnid        If Forwarder Encapsulation is enabled, wireless node ID for this client. Defaults to process id.");
+    eprintln!("  --wlnid        If Forwarder Encapsulation is enabled, wireless node ID for this client. Defaults to 0.");
+    eprintln!("  --radius <radius>  If Forwarder Encapsulation is enabled, broadcast radius of the encapsulated packets (0 to 3). Defaults to {}.", defaults.forwarder_radius);
     eprintln!("  --cport <port> Source port for outgoing packets. Uses port in ephemeral range if not specified or set to {}.", defaults.source_port);
+    eprintln!("  --will-topic <topic>  Topic for the Last Will and Testament message.");
+    eprintln!("  --will-message <msg>  Payload of the Last Will and Testament message.");
+    eprintln!("  --will-qos <qos>      QoS of the Last Will and Testament message (0, 1 or 2). Defaults to {}.", defaults.will_qos);
+    eprintln!("  --will-retain         Last Will and Testament message should be retained.");
     eprintln!("  --loop-freq    Frequency in Hz to send messages. Defaults to 0 (disabled).");
     eprintln!("  --count        Number of messages to send in loop. Defaults to 0 (loops forever).");
-    eprintln!("  --net-timeout  The timeout given to the network backend for reading operations.");
-    eprintln!("  --net-retries  The number of retries for network operations.");
+    eprintln!("  --net-timeout  Milliseconds to wait for an answer before resending a packet. Defaults to {}.", defaults.network_timeout);
+    eprintln!("  --net-retries  Number of times an unanswered packet is resent. Defaults to {}.", defaults.network_retries);
     std::process::exit(1);
 }
 
@@ -118,7 +122,13 @@ fn parse_args() -> Settings{
             }
             "-q" => {
                 i += 1;
-                settings.qos = args[i].parse().unwrap();
+                settings.qos = match args[i].parse() {
+                    Ok(qos) => qos,
+                    Err(_) => {
+                        error!("Invalid QoS value: {}", args[i]);
+                        usage();
+                    }
+                };
             }
             "-r" => {
                 settings.retain = true;
@@ -142,10 +152,41 @@ fn parse_args() -> Settings{
                 i += 1;
                 settings.wireless_node_id = args[i].parse().unwrap();
             }
+            "--radius" => {
+                i += 1;
+                settings.forwarder_radius = match args[i].parse() {
+                    Ok(radius) if radius <= MQTT_SN_FRWDENCAP_RADIUS_MASK => radius,
+                    _ => {
+                        error!("Invalid radius: {}", args[i]);
+                        usage();
+                    }
+                };
+            }
             "--cport" => {
                 i += 1;
                 settings.source_port = args[i].parse().unwrap();
             }
+            "--will-topic" => {
+                i += 1;
+                settings.will_topic = args[i].clone();
+            }
+            "--will-message" => {
+                i += 1;
+                settings.will_message = args[i].clone();
+            }
+            "--will-qos" => {
+                i += 1;
+                settings.will_qos = match args[i].parse() {
+                    Ok(qos) => qos,
+                    Err(_) => {
+                        error!("Invalid will QoS value: {}", args[i]);
+                        usage();
+                    }
+                };
+            }
+            "--will-retain" => {
+                settings.will_retain = true;
+            }
             
             "--loop-freq" => {
                 i += 1;
@@ -174,33 +215,26 @@ fn parse_args() -> Settings{
     // Check for missing arguments
     // The required arguments are topic_name or topic_id, and message or
     // file.
-    if (settings.topic == "" && settings.topic_id == 0) || ((settings.message == "" && !settings.null_message) && settings.file == "") {
+    if (settings.topic.is_empty() && settings.topic_id == 0) || ((settings.message.is_empty() && !settings.null_message) && settings.file.is_empty()) {
         error!("Missing required arguments.");
         usage();
     }
 
-    // Check for invalid arguments
-    // The QoS value must be 0, 1 or -1
-    if settings.qos != 0 && settings.qos != 1 && settings.qos != -1 {
-        error!("Invalid QoS value: {}", settings.qos);
-        usage();
-    }
-
     // Either topic or topic_id must be provided
-    if settings.topic != "" && settings.topic_id != 0 {
+    if !settings.topic.is_empty() && settings.topic_id != 0 {
         error!("Both topic and topic_id provided. Only one is allowed.");
         usage();
     }
 
     // Only a message or a file can be provided
-    if settings.message != "" && settings.file != "" {
+    if !settings.message.is_empty() && !settings.file.is_empty() {
         error!("Both message and file provided. Only one is allowed.");
         usage();
     }
 
     // Topic must be valid for QoS -1
     // That means either a short topic name or a pre-defined topic ID
-    if (settings.qos == -1) && (settings.topic_id == 0) && (settings.topic.len() != 2) {
+    if (settings.qos == QoS::MinusOne) && (settings.topic_id == 0) && (settings.topic.len() != 2) {
         error!("Either a short topic name or a pre-defined topic ID must be provided for QoS -1.");
         usage();
     }
@@ -211,6 +245,18 @@ fn parse_args() -> Settings{
         usage();
     }
 
+    // The will QoS value must be 0, 1 or 2
+    if settings.will_qos == QoS::MinusOne {
+        error!("Invalid will QoS value: {}", settings.will_qos);
+        usage();
+    }
+
+    // A will message makes no sense without a will topic
+    if settings.will_topic.is_empty() && !settings.will_message.is_empty() {
+        error!("Will message provided without will topic.");
+        usage();
+    }
+
     settings
 }
 
@@ -218,6 +264,8 @@ fn parse_args() -> Settings{
 // Placeholder for publish_file
 fn publish_file(sensor_net: &mut dyn SensorNetwork, settings: &Settings) {
     let mut message: String;
+    // Larger messages fit if the network carries long packets
+    let max_payload_length = mqtt_sn_max_payload_length(sensor_net);
     // Open the file
     // If it is -, read from STDIN
     // Otherwise, read from the file
@@ -233,33 +281,39 @@ fn publish_file(sensor_net: &mut dyn SensorNetwork, settings: &Settings) {
             let line = line.unwrap();
             // Check if the line is empty
             // If so, skip it
-            if line == "" {
+            if line.is_empty() {
                 continue;
             }
             // Check if the line is too long
             // If so, truncate it
-            if line.len() > MQTT_SN_MAX_PAYLOAD_LENGTH {
-                warn!("Line too long. Truncating to {} bytes.", MQTT_SN_MAX_PAYLOAD_LENGTH);
-                message  = line[..MQTT_SN_MAX_PAYLOAD_LENGTH].to_string();
+            if line.len() > max_payload_length {
+                warn!("Line too long. Truncating to {} bytes.", max_payload_length);
+                message  = line[..max_payload_length].to_string();
             } else {
                 message = line;
             }
             // Publish
-            mqtt_sn_send_publish(sensor_net, &settings, &message);
+            if let Err(e) = mqtt_sn_send_publish(sensor_net, settings, &message) {
+                error!("Could not publish the message: {}", e);
+                std::process::exit(1);
+            }
         }
     } else {
-        // Read the file up to MQTT_SN_MAX_PAYLOAD_LENGTH
-        let mut buffer = vec![0; MQTT_SN_MAX_PAYLOAD_LENGTH];
+        // Read the file up to max_payload_length
+        let mut buffer = vec![0; max_payload_length];
         let bytes_read = file.read(&mut buffer).expect("Failed to read file.");
 
-        // Truncate the buffer if the file is shorter than MQTT_SN_MAX_PAYLOAD_LENGTH
-        if bytes_read < MQTT_SN_MAX_PAYLOAD_LENGTH {
+        // Truncate the buffer if the file is shorter than max_payload_length
+        if bytes_read < max_payload_length {
             buffer.truncate(bytes_read);
         }
         // Publish
         let message = String::from_utf8(buffer).expect("Failed to convert buffer to string.");
         // Publish
-        mqtt_sn_send_publish(sensor_net, &settings, message.as_str());
+        if let Err(e) = mqtt_sn_send_publish(sensor_net, settings, message.as_str()) {
+            error!("Could not publish the message: {}", e);
+            std::process::exit(1);
+        }
     }
 }
 
@@ -296,29 +350,50 @@ fn main(){
             flow_control: serialport::FlowControl::None,
             timeout: std::time::Duration::from_millis(settings.network_timeout),
         };
-    let mut boxed_sensor_net = create_sensor_network(SensorNetworkType::SerialPort, sensor_net_args);
+    let mut boxed_sensor_net = match create_sensor_network(SensorNetworkType::SerialPort, sensor_net_args) {
+        Ok(sensor_net) => sensor_net,
+        Err(e) => {
+            error!("Could not open the serial port: {}", e);
+            std::process::exit(1);
+        }
+    };
 
     let sensor_net = &mut *boxed_sensor_net;
-    sensor_net.initialize();
+    if let Err(e) = sensor_net.initialize() {
+        error!("Could not open the serial port: {}", e);
+        std::process::exit(1);
+    }
+
+    // Talk to the gateway through a forwarder, if requested
+    if settings.forwarder_encapsulation {
+        mqtt_sn_enable_frwdencap(&settings);
+    }
 
-    if settings.qos >= 0 {
+    if settings.qos != QoS::MinusOne {
         // Send a CONNECT message
-        mqtt_sn_connect(sensor_net, &settings);
+        if let Err(e) = mqtt_sn_connect(sensor_net, &settings) {
+            error!("Could not connect to the gateway: {}", e);
+            std::process::exit(1);
+        }
         
         // Then check if the topic is a pre-defined topic ID
         if settings.topic_id != 0 {
             // Use a pre-defined topic ID
-            settings.topic_id_type = MQTT_SN_TOPIC_TYPE_PREDEFINED;
+            settings.topic_id_type = TopicIdType::Predefined;
         } else if settings.topic.len() == 2 {
             // Use a short topic name
-            settings.topic_id_type = MQTT_SN_TOPIC_TYPE_SHORT;
+            settings.topic_id_type = TopicIdType::Short;
             // Convert the 2 character topic name into a 2 byte topic ID
             settings.topic_id = ((settings.topic.as_bytes()[0] as u16) << 8) | (settings.topic.as_bytes()[1] as u16);
-        } else if settings.qos >= 0 {
+        } else if settings.qos != QoS::MinusOne {
             // Send a REGISTER message
-            mqtt_sn_send_register(sensor_net, &settings);
-            mqtt_sn_receive_regack(sensor_net, &settings);
-            settings.topic_id_type = MQTT_SN_TOPIC_TYPE_NORMAL;
+            if let Err(e) = mqtt_sn_send_register(sensor_net, &settings)
+                .and_then(|_| mqtt_sn_receive_regack(sensor_net, &settings))
+            {
+                error!("Could not register the topic: {}", e);
+                std::process::exit(1);
+            }
+            settings.topic_id_type = TopicIdType::Normal;
         }
 
         let mut sleep_time_in_us = 0;
@@ -329,7 +404,7 @@ fn main(){
 
         if settings.loop_frequency > 0 {
             info!("Loop frequency set to {} Hz.", settings.loop_frequency);
-            sleep_time_in_us = 1_000_000 / settings.loop_frequency as u64;
+            sleep_time_in_us = 1_000_000 / settings.loop_frequency;
         }
 
         loop {
@@ -337,26 +412,32 @@ fn main(){
                 settings.loop_count -= 1;
             }
             // Publish the message to the topic
-            if settings.file != "" {
+            if !settings.file.is_empty() {
                 publish_file(sensor_net, &settings);
             } else {
-                mqtt_sn_send_publish(sensor_net, &settings, ""); 
+                if let Err(e) = mqtt_sn_send_publish(sensor_net, &settings, "") {
+                    error!("Could not publish the message: {}", e);
+                    std::process::exit(1);
+                }
             }
 
             if settings.loop_frequency == 0 {
                 break;
             }
             // Sleep for the loop frequency
-            std::thread::sleep(std::time::Duration::from_micros(sleep_time_in_us as u64));
+            std::thread::sleep(std::time::Duration::from_micros(sleep_time_in_us));
 
             if settings.loop_count == 0 {
                 break;
             }
         }
         // Disconnect
-        if settings.qos >= 0 {
-            mqtt_sn_send_disconnect(sensor_net, &settings);
-            mqtt_sn_receive_disconnect(sensor_net, &settings);
+        if settings.qos != QoS::MinusOne {
+            if let Err(e) = mqtt_sn_send_disconnect(sensor_net, &settings)
+                .and_then(|_| mqtt_sn_receive_disconnect(sensor_net, &settings))
+            {
+                warn!("Could not disconnect cleanly: {}", e);
+            }
         }
     }
 }
\ No newline at end of file
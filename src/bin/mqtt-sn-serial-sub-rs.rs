@@ -1,5 +1,9 @@
 extern crate mqtt_sn_tools_rs;
 
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use log::{
     warn,
     error,
@@ -10,18 +14,21 @@ use log::{
 use env_logger::Builder;
 
 use mqtt_sn_tools_rs::mqttsn::constants::{
-    MQTT_SN_FLAG_QOS_MASK,
-    MQTT_SN_FLAG_QOS_1,
     MQTT_SN_ACCEPTED,
+    MQTT_SN_FRWDENCAP_RADIUS_MASK,
 };
 
+use mqtt_sn_tools_rs::mqttsn::flags::QoS;
+
+use mqtt_sn_tools_rs::mqttsn::error::MqttSnError;
+
 use mqtt_sn_tools_rs::mqttsn::settings::{
     Settings,
     default_settings,
 };
 
 use mqtt_sn_tools_rs::mqttsn::pubsub::{
-    mqtt_sn_connect, mqtt_sn_receive_disconnect, mqtt_sn_receive_publish, mqtt_sn_receive_suback, mqtt_sn_send_disconnect, mqtt_sn_send_puback, mqtt_sn_send_subscribe_topic_id, mqtt_sn_send_subscribe_topic_name
+    mqtt_sn_connect, mqtt_sn_enable_frwdencap, mqtt_sn_go_to_sleep, mqtt_sn_handle_qos2_publish, mqtt_sn_receive_disconnect, mqtt_sn_receive_publish, mqtt_sn_receive_suback, mqtt_sn_send_disconnect, mqtt_sn_send_puback, mqtt_sn_send_subscribe_topic_id, mqtt_sn_send_subscribe_topic_name, mqtt_sn_unsubscribe_all, mqtt_sn_wake_up
 };
 
 use mqtt_sn_tools_rs::mqttsn::network_abstractions::{
@@ -32,7 +39,7 @@ use mqtt_sn_tools_rs::mqttsn::network_abstractions::{
 };
 
 
-fn usage() {
+fn usage() -> ! {
     let defaults = default_settings();
     eprintln!("Usage: mqtt-sn-sub-rs [opts] -t <topic>\n");
     eprintln!("\n");
@@ -42,14 +49,23 @@ fn usage() {
     eprintln!("  -i <clientid>  ID to use for this client. Defaults to 'mqtt-sn-tools-' with process id.");
     eprintln!("  -k <keepalive> keep alive in seconds for this client. Defaults to {}.", defaults.keep_alive);
     eprintln!("  -e <sleep>     sleep duration in seconds when disconnecting. Defaults to {}.", defaults.sleep_duration);
+    eprintln!("  --sleep-cycle  Behave as a sleeping client: sleep for the -e duration, then wake up to receive buffered messages.");
     eprintln!("  -p <port>      Serial port to connect to. Defaults to '{}'.", defaults.serial_port);
     eprintln!("  -b <baudrate>  Baudrate for serial port. Defaults to '{}'.", defaults.baudrate);
-    eprintln!("  -q <qos>       QoS level to subscribe with (0 or 1). Defaults to {}.", defaults.qos);
+    eprintln!("  -q <qos>       QoS level to subscribe with (0, 1 or 2). Defaults to {}.", defaults.qos);
     eprintln!("  -t <topic>     MQTT-SN topic name to subscribe to. It may repeat multiple times.");
     eprintln!("  -T <topicid>   Pre-defined MQTT-SN topic ID to subscribe to. It may repeat multiple times.");
+    eprintln!("  --unsubscribe  Unsubscribe from every topic before disconnecting (on exit or Ctrl-C).");
     eprintln!("  --fe           Enables Forwarder Encapsulation. Mqtt-sn packets are encapsulated according to MQTT-SN Protocol Specification v1.2, chapter 5.5 Forwarder Encapsulation.");
-    eprintln!("  --wlnid        If Forwarder Encapsulation is enabled, wireless node ID for this client. Defaults to process id (truncating if necessary).");
+    eprintln!("  --wlnid        If Forwarder Encapsulation is enabled, wireless node ID for this client. Defaults to 0.");
+    eprintln!("  --radius <radius>  If Forwarder Encapsulation is enabled, broadcast radius of the encapsulated packets (0 to 3). Defaults to {}.", defaults.forwarder_radius);
     eprintln!("  --cport <port> Source port for outgoing packets. Uses port in ephemeral range if not specified or set to {}.", defaults.source_port);
+    eprintln!("  --will-topic <topic>  Topic for the Last Will and Testament message.");
+    eprintln!("  --will-message <msg>  Payload of the Last Will and Testament message.");
+    eprintln!("  --will-qos <qos>      QoS of the Last Will and Testament message (0, 1 or 2). Defaults to {}.", defaults.will_qos);
+    eprintln!("  --will-retain         Last Will and Testament message should be retained.");
+    eprintln!("  --net-timeout  Milliseconds to wait for an answer before resending a packet. Defaults to {}.", defaults.network_timeout);
+    eprintln!("  --net-retries  Number of times an unanswered packet is resent. Defaults to {}.", defaults.network_retries);
     eprintln!("  -v             Print messages verbosely, showing the topic name.");
     eprintln!("  -V             Print messages verbosely, showing current time and the topic name.");
     std::process::exit(1);
@@ -92,7 +108,13 @@ fn parse_args() -> Settings{
             },
             "-q" => {
                 i += 1;
-                settings.qos = args[i].parse::<i8>().expect("Failed to parse QoS.");
+                settings.qos = match args[i].parse() {
+                    Ok(qos) => qos,
+                    Err(_) => {
+                        error!("Invalid QoS value: {}", args[i]);
+                        usage();
+                    }
+                };
             },
             "-t" => {
                 i += 1;
@@ -102,6 +124,12 @@ fn parse_args() -> Settings{
                 i += 1;
                 settings.topic_id_list.push(args[i].parse::<u16>().expect("Failed to parse topic ID."));
             },
+            "--unsubscribe" => {
+                settings.unsubscribe_on_exit = true;
+            },
+            "--sleep-cycle" => {
+                settings.sleep_cycle = true;
+            },
             "--fe" => {
                 settings.forwarder_encapsulation = true;
             },
@@ -109,16 +137,55 @@ fn parse_args() -> Settings{
                 i += 1;
                 settings.wireless_node_id = args[i].parse().unwrap();
             },
+            "--radius" => {
+                i += 1;
+                settings.forwarder_radius = match args[i].parse() {
+                    Ok(radius) if radius <= MQTT_SN_FRWDENCAP_RADIUS_MASK => radius,
+                    _ => {
+                        error!("Invalid radius: {}", args[i]);
+                        usage();
+                    }
+                };
+            },
             "--cport" => {
                 i += 1;
                 settings.source_port = args[i].parse::<u16>().expect("Failed to parse source port.");
             },
+            "--will-topic" => {
+                i += 1;
+                settings.will_topic = args[i].clone();
+            },
+            "--will-message" => {
+                i += 1;
+                settings.will_message = args[i].clone();
+            },
+            "--will-qos" => {
+                i += 1;
+                settings.will_qos = match args[i].parse() {
+                    Ok(qos) => qos,
+                    Err(_) => {
+                        error!("Invalid will QoS value: {}", args[i]);
+                        usage();
+                    }
+                };
+            },
+            "--will-retain" => {
+                settings.will_retain = true;
+            },
             "-v" => {
                 settings.verbose = true;
             },
             "-V" => {
                 settings.verbose_time = true;
             },
+            "--net-timeout" => {
+                i += 1;
+                settings.network_timeout = args[i].parse::<u64>().expect("Failed to parse network timeout.");
+            },
+            "--net-retries" => {
+                i += 1;
+                settings.network_retries = args[i].parse::<u8>().expect("Failed to parse network retries.");
+            },
             _ => {
                 error!("Unknown option: {}", args[i]);
                 usage();
@@ -129,25 +196,37 @@ fn parse_args() -> Settings{
 
     // Check for missing arguments
     
-    // The QoS value must be 0, 1 or -1
-    if settings.qos != 0 && settings.qos != 1 && settings.qos != -1 {
-        error!("Invalid QoS value: {}", settings.qos);
-        usage();
-    }
-
     // Either topic or topic_id must be provided
-    if settings.topic != "" && settings.topic_id != 0 {
+    if !settings.topic.is_empty() && settings.topic_id != 0 {
         error!("Both topic and topic_id provided. Only one is allowed.");
         usage();
     }
 
     // Topic must be valid for QoS -1
     // That means either a short topic name or a pre-defined topic ID
-    if (settings.qos == -1) && (settings.topic_id == 0) && (settings.topic.len() != 2) {
+    if (settings.qos == QoS::MinusOne) && (settings.topic_id == 0) && (settings.topic.len() != 2) {
         error!("Either a short topic name or a pre-defined topic ID must be provided for QoS -1.");
         usage();
     }
 
+    // Sleeping needs a sleep duration
+    if settings.sleep_cycle && settings.sleep_duration == 0 {
+        error!("Sleep cycle mode requires a sleep duration (-e).");
+        usage();
+    }
+
+    // The will QoS value must be 0, 1 or 2
+    if settings.will_qos == QoS::MinusOne {
+        error!("Invalid will QoS value: {}", settings.will_qos);
+        usage();
+    }
+
+    // A will message makes no sense without a will topic
+    if settings.will_topic.is_empty() && !settings.will_message.is_empty() {
+        error!("Will message provided without will topic.");
+        usage();
+    }
+
     settings
 }
 
@@ -177,7 +256,7 @@ fn main(){
     debug!("{:?}", settings);
     // First open a connection
     let mut boxed_sensor_network: Box<dyn SensorNetwork> = 
-        create_sensor_network(
+        match create_sensor_network(
             SensorNetworkType::SerialPort,
             SensorNetworkInitArgs::SerialPort {
                 port_name: settings.serial_port.clone(),
@@ -185,73 +264,158 @@ fn main(){
                 parity: serialport::Parity::None,
                 data_bits: serialport::DataBits::Eight,
                 flow_control: serialport::FlowControl::None,
-                timeout: std::time::Duration::from_millis(settings.network_timeout as u64),
+                timeout: std::time::Duration::from_millis(settings.network_timeout),
             }
-        );
+        ) {
+            Ok(sensor_net) => sensor_net,
+            Err(e) => {
+                error!("Could not open the serial port: {}", e);
+                std::process::exit(1);
+            }
+        };
 
     let sensor_net = &mut *boxed_sensor_network;
-    sensor_net.initialize();
+    if let Err(e) = sensor_net.initialize() {
+        error!("Could not open the serial port: {}", e);
+        std::process::exit(1);
+    }
+
+    // Talk to the gateway through a forwarder, if requested
+    if settings.forwarder_encapsulation {
+        mqtt_sn_enable_frwdencap(&settings);
+    }
 
     
 
     // Send a CONNECT message
     debug!("Sending CONNECT message");
-    mqtt_sn_connect(sensor_net, &settings); 
+    if let Err(e) = mqtt_sn_connect(sensor_net, &settings) {
+        error!("Could not connect to the gateway: {}", e);
+        std::process::exit(1);
+    }
 
     // Subscribe to the topics by topic name
     for topic in settings.topic_list.iter() {
         debug!("Subscribing to topic: {}", topic);
-        mqtt_sn_send_subscribe_topic_name(sensor_net, &settings, topic);
-        let topic_id = mqtt_sn_receive_suback(sensor_net, &settings);
-
-        if topic_id != 0  && topic.len() > 2 {
-            settings.topic_map.insert(topic_id, topic.clone());
+        match mqtt_sn_send_subscribe_topic_name(sensor_net, &settings, topic)
+            .and_then(|_| mqtt_sn_receive_suback(sensor_net, &settings))
+        {
+            Ok(topic_id) => {
+                if topic_id != 0  && topic.len() > 2 {
+                    settings.topic_map.insert(topic_id, topic.clone());
+                }
+            },
+            Err(e) => error!("Could not subscribe to topic {}: {}", topic, e),
         }
     }
 
     // Subscribe to the topics by topic ID
     for topic_id in settings.topic_id_list.iter() {
         debug!("Subscribing to topic ID: {}", topic_id);
-        mqtt_sn_send_subscribe_topic_id(sensor_net, &settings, *topic_id);
-        mqtt_sn_receive_suback(sensor_net, &settings);
+        if let Err(e) = mqtt_sn_send_subscribe_topic_id(sensor_net, &settings, *topic_id)
+            .and_then(|_| mqtt_sn_receive_suback(sensor_net, &settings))
+        {
+            error!("Could not subscribe to topic ID {}: {}", topic_id, e);
+        }
     }
 
-    loop {
-        // Receive messages
-        debug!("Waiting for a message");
-        let unsafe_packet = mqtt_sn_receive_publish(sensor_net, &settings);
+    // Stop receiving on Ctrl-C, so the session can be closed cleanly
+    let running = Arc::new(AtomicBool::new(true));
+    let handler_running = running.clone();
+    ctrlc::set_handler(move || {
+        handler_running.store(false, Ordering::SeqCst);
+    }).expect("Failed to set Ctrl-C handler.");
 
-        let packet = match unsafe_packet {
-            Some(packet) => packet,
-            None => {
-                warn!("Received an empty packet. Ignoring.");
-                continue;
+    if settings.sleep_cycle {
+        // Emulate a sleeping client: go to sleep, then wake up to receive
+        // the messages buffered by the gateway meanwhile
+        while running.load(Ordering::SeqCst) {
+            if let Err(e) = mqtt_sn_go_to_sleep(sensor_net, &settings) {
+                error!("Could not go to sleep: {}", e);
+                break;
             }
-        };
+            let wake_up_time = Instant::now() + Duration::from_secs(settings.sleep_duration);
+            while running.load(Ordering::SeqCst) && Instant::now() < wake_up_time {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            match mqtt_sn_wake_up(sensor_net, &settings) {
+                Ok(packets) => {
+                    if settings.single_message && !packets.is_empty() {
+                        break;
+                    }
+                },
+                Err(e) => {
+                    error!("Could not wake up: {}", e);
+                    break;
+                }
+            }
+        }
 
-        if packet.data.len() == 0 {
-            warn!("Received an empty packet. Ignoring.");
-            continue;    
+        // Back to the active state, unsubscribing needs it
+        if settings.unsubscribe_on_exit {
+            if let Err(e) = mqtt_sn_connect(sensor_net, &settings) {
+                warn!("Could not reconnect to unsubscribe: {}", e);
+            }
         }
+        // The final DISCONNECT must not put the client to sleep again
+        settings.sleep_duration = 0;
+    } else {
+        while running.load(Ordering::SeqCst) {
+            // Receive messages
+            debug!("Waiting for a message");
+            let unsafe_packet = mqtt_sn_receive_publish(sensor_net, &settings);
+
+            let packet = match unsafe_packet {
+                Ok(packet) => packet,
+                Err(MqttSnError::Timeout) => continue,
+                Err(MqttSnError::Disconnected) => {
+                    error!("Disconnected by the gateway");
+                    std::process::exit(1);
+                },
+                Err(e) => {
+                    warn!("Failed to receive a message: {}", e);
+                    continue;
+                }
+            };
+
+            if packet.data.is_empty() {
+                warn!("Received an empty packet. Ignoring.");
+                continue;    
+            }
         
-        let msg_qos = packet.flags & MQTT_SN_FLAG_QOS_MASK;
-        if msg_qos == MQTT_SN_FLAG_QOS_1 {
-            // Send a PUBACK
-            mqtt_sn_send_puback(sensor_net, &packet, MQTT_SN_ACCEPTED);
-        //} else if msg_qos == MQTT_SN_FLAG_QOS_2 {
-        //    // Send a PUBREC
-        //    mqtt_sn_send_pubrec(sensor_net, &settings, &packet);
-        }
+            let msg_qos = packet.flags.qos();
+            if msg_qos == QoS::AtLeastOnce {
+                // Send a PUBACK
+                if let Err(e) = mqtt_sn_send_puback(sensor_net, &packet, MQTT_SN_ACCEPTED) {
+                    warn!("Could not acknowledge the message: {}", e);
+                }
+            } else if msg_qos == QoS::ExactlyOnce {
+                // Send a PUBREC and complete the handshake
+                if let Err(e) = mqtt_sn_handle_qos2_publish(sensor_net, &settings, &packet) {
+                    warn!("Could not acknowledge the message: {}", e);
+                }
+            }
+
+            if settings.single_message {
+                break;
+            }
 
-        if settings.single_message {
-            break;
         }
+    }
 
+    // Unsubscribe from everything, if requested
+    if settings.unsubscribe_on_exit {
+        if let Err(e) = mqtt_sn_unsubscribe_all(sensor_net, &settings) {
+            warn!("Could not unsubscribe: {}", e);
+        }
     }
 
     // Send a DISCONNECT message
-    mqtt_sn_send_disconnect(sensor_net, &settings);
     debug!("Sending DISCONNECT message");
-    mqtt_sn_receive_disconnect(sensor_net, &settings);
+    if let Err(e) = mqtt_sn_send_disconnect(sensor_net, &settings)
+        .and_then(|_| mqtt_sn_receive_disconnect(sensor_net, &settings))
+    {
+        warn!("Could not disconnect cleanly: {}", e);
+    }
     
 }
@@ -1,7 +1,12 @@
 extern crate mqtt_sn_tools_rs;
 
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use log::{
     warn,
+    info,
     error,
     debug,
     LevelFilter
@@ -10,46 +15,80 @@ use log::{
 use env_logger::Builder;
 
 use mqtt_sn_tools_rs::mqttsn::constants::{
-    MQTT_SN_FLAG_QOS_MASK,
-    MQTT_SN_FLAG_QOS_1,
     MQTT_SN_ACCEPTED,
+    MQTT_SN_FRWDENCAP_RADIUS_MASK,
 };
 
+use mqtt_sn_tools_rs::mqttsn::flags::QoS;
+
+use mqtt_sn_tools_rs::mqttsn::error::MqttSnError;
+
 use mqtt_sn_tools_rs::mqttsn::settings::{
     Settings,
     default_settings,
 };
 
 use mqtt_sn_tools_rs::mqttsn::pubsub::{
-    mqtt_sn_connect, mqtt_sn_receive_disconnect, mqtt_sn_receive_publish, mqtt_sn_receive_suback, mqtt_sn_send_disconnect, mqtt_sn_send_puback, mqtt_sn_send_subscribe_topic_id, mqtt_sn_send_subscribe_topic_name
+    GatewayInfo, mqtt_sn_discover_gateway, mqtt_sn_connect, mqtt_sn_enable_frwdencap, mqtt_sn_go_to_sleep, mqtt_sn_handle_qos2_publish, mqtt_sn_receive_disconnect, mqtt_sn_receive_publish, mqtt_sn_receive_suback, mqtt_sn_send_disconnect, mqtt_sn_send_puback, mqtt_sn_send_subscribe_topic_id, mqtt_sn_send_subscribe_topic_name, mqtt_sn_unsubscribe_all, mqtt_sn_wake_up
 };
 
+use std::net::SocketAddr;
+
 use mqtt_sn_tools_rs::mqttsn::network_abstractions::{
+    AddressFamily,
+    DtlsSecurity,
+    MulticastInterface,
+    MulticastOptions,
+    PeerAddress,
     SensorNetwork,
     SensorNetworkInitArgs,
     SensorNetworkType,
+    bind_address_for,
     create_sensor_network,
+    parse_psk_key,
+    resolve_address,
 };
 
 
-fn usage() {
+fn usage() -> ! {
     let defaults = default_settings();
     eprintln!("Usage: mqtt-sn-sub-rs [opts] -t <topic>\n");
     eprintln!("\n");
     eprintln!("  -1             exit after receiving a single message.");
     eprintln!("  -c             disable 'clean session' (store subscription and pending messages when client disconnects).");
     eprintln!("  -d             Increase debug level by one. -d can occur multiple times.");
-    eprintln!("  -h <host>      MQTT-SN host to connect to. Defaults to '{}'.", defaults.mqtt_sn_host);
+    eprintln!("  -h <host>      MQTT-SN host to connect to, a name or an IP address. IPv6 ones may be in brackets. Defaults to '{}'.", defaults.mqtt_sn_host);
+    eprintln!("  -4             Reach the gateway over IPv4 only.");
+    eprintln!("  -6             Reach the gateway over IPv6 only.");
+    eprintln!("  --discover     Find the gateway with SEARCHGW instead of using -h. Broadcasts to the port given with -p, or sends to --mcast-group.");
+    eprintln!("  --mcast-group <group>  Multicast group, IPv4 or IPv6, to discover the gateway on instead of broadcasting.");
+    eprintln!("  --mcast-hops <hops>    TTL or hop limit of the multicast SEARCHGW. Defaults to {}.", defaults.multicast_hops);
+    eprintln!("  --mcast-if <interface> Interface to join the group on: an IPv4 address for IPv4 groups, an interface index for IPv6 ones.");
     eprintln!("  -i <clientid>  ID to use for this client. Defaults to 'mqtt-sn-tools-' with process id.");
     eprintln!("  -k <keepalive> keep alive in seconds for this client. Defaults to {}.", defaults.keep_alive);
     eprintln!("  -e <sleep>     sleep duration in seconds when disconnecting. Defaults to {}.", defaults.sleep_duration);
+    eprintln!("  --sleep-cycle  Behave as a sleeping client: sleep for the -e duration, then wake up to receive buffered messages.");
     eprintln!("  -p <port>      Network port to connect to. Defaults to '{}'.", defaults.mqtt_sn_port);
-    eprintln!("  -q <qos>       QoS level to subscribe with (0 or 1). Defaults to {}.", defaults.qos);
+    eprintln!("  -q <qos>       QoS level to subscribe with (0, 1 or 2). Defaults to {}.", defaults.qos);
     eprintln!("  -t <topic>     MQTT-SN topic name to subscribe to. It may repeat multiple times.");
     eprintln!("  -T <topicid>   Pre-defined MQTT-SN topic ID to subscribe to. It may repeat multiple times.");
+    eprintln!("  --unsubscribe  Unsubscribe from every topic before disconnecting (on exit or Ctrl-C).");
     eprintln!("  --fe           Enables Forwarder Encapsulation. Mqtt-sn packets are encapsulated according to MQTT-SN Protocol Specification v1.2, chapter 5.5 Forwarder Encapsulation.");
-    eprintln!("  --wlnid        If Forwarder Encapsulation is enabled, wireless node ID for this client. Defaults to process id (truncating if necessary).");
+    eprintln!("  --wlnid        If Forwarder Encapsulation is enabled, wireless node ID for this client. Defaults to 0.");
+    eprintln!("  --radius <radius>  If Forwarder Encapsulation is enabled, broadcast radius of the encapsulated packets (0 to 3). Defaults to {}.", defaults.forwarder_radius);
     eprintln!("  --cport <port> Source port for outgoing packets. Uses port in ephemeral range if not specified or set to {}.", defaults.source_port);
+    eprintln!("  --transport <transport>  Transport to the gateway, udp, tcp or dtls. Defaults to udp.");
+    eprintln!("  --psk-identity <identity>  Identity of the DTLS pre-shared key.");
+    eprintln!("  --psk <key>           DTLS pre-shared key, in hex.");
+    eprintln!("  --cafile <file>       PEM file with the CAs the gateway certificate is checked against over DTLS.");
+    eprintln!("  --cert <file>         PEM file with the client certificate for DTLS.");
+    eprintln!("  --key <file>          PEM file with the private key of --cert. Defaults to the --cert file.");
+    eprintln!("  --will-topic <topic>  Topic for the Last Will and Testament message.");
+    eprintln!("  --will-message <msg>  Payload of the Last Will and Testament message.");
+    eprintln!("  --will-qos <qos>      QoS of the Last Will and Testament message (0, 1 or 2). Defaults to {}.", defaults.will_qos);
+    eprintln!("  --will-retain         Last Will and Testament message should be retained.");
+    eprintln!("  --net-timeout  Milliseconds to wait for an answer before resending a packet. Defaults to {}.", defaults.network_timeout);
+    eprintln!("  --net-retries  Number of times an unanswered packet is resent. Defaults to {}.", defaults.network_retries);
     eprintln!("  -v             Print messages verbosely, showing the topic name.");
     eprintln!("  -V             Print messages verbosely, showing current time and the topic name.");
     std::process::exit(1);
@@ -74,6 +113,34 @@ fn parse_args() -> Settings{
                 i += 1;
                 settings.mqtt_sn_host = args[i].clone();
             },
+            "-4" => {
+                settings.address_family = AddressFamily::IPv4;
+            },
+            "-6" => {
+                settings.address_family = AddressFamily::IPv6;
+            },
+            "--discover" => {
+                settings.discover = true;
+            },
+            "--mcast-group" => {
+                i += 1;
+                settings.multicast_group = args[i].clone();
+            },
+            "--mcast-hops" => {
+                i += 1;
+                settings.multicast_hops = args[i].parse().unwrap();
+            },
+            "--mcast-if" => {
+                i += 1;
+                settings.multicast_interface = match (args[i].parse(), args[i].parse()) {
+                    (Ok(address), _) => Some(MulticastInterface::Address(address)),
+                    (_, Ok(index)) => Some(MulticastInterface::Index(index)),
+                    _ => {
+                        error!("Invalid multicast interface: {}", args[i]);
+                        usage();
+                    }
+                };
+            },
             "-i" => {
                 i += 1;
                 settings.client_id = args[i].clone();
@@ -92,7 +159,13 @@ fn parse_args() -> Settings{
             },
             "-q" => {
                 i += 1;
-                settings.qos = args[i].parse::<i8>().expect("Failed to parse QoS.");
+                settings.qos = match args[i].parse() {
+                    Ok(qos) => qos,
+                    Err(_) => {
+                        error!("Invalid QoS value: {}", args[i]);
+                        usage();
+                    }
+                };
             },
             "-t" => {
                 i += 1;
@@ -102,6 +175,12 @@ fn parse_args() -> Settings{
                 i += 1;
                 settings.topic_id_list.push(args[i].parse::<u16>().expect("Failed to parse topic ID."));
             },
+            "--unsubscribe" => {
+                settings.unsubscribe_on_exit = true;
+            },
+            "--sleep-cycle" => {
+                settings.sleep_cycle = true;
+            },
             "--fe" => {
                 settings.forwarder_encapsulation = true;
             },
@@ -109,16 +188,93 @@ fn parse_args() -> Settings{
                 i += 1;
                 settings.wireless_node_id = args[i].parse().unwrap();
             },
+            "--radius" => {
+                i += 1;
+                settings.forwarder_radius = match args[i].parse() {
+                    Ok(radius) if radius <= MQTT_SN_FRWDENCAP_RADIUS_MASK => radius,
+                    _ => {
+                        error!("Invalid radius: {}", args[i]);
+                        usage();
+                    }
+                };
+            },
             "--cport" => {
                 i += 1;
                 settings.source_port = args[i].parse::<u16>().expect("Failed to parse source port.");
             },
+            "--transport" => {
+                i += 1;
+                settings.transport = match args[i].as_str() {
+                    "udp" => SensorNetworkType::UDP,
+                    "tcp" => SensorNetworkType::TCP,
+                    "dtls" => SensorNetworkType::DTLS,
+                    _ => {
+                        error!("Unknown transport: {}", args[i]);
+                        usage();
+                    }
+                };
+            },
+            "--psk-identity" => {
+                i += 1;
+                settings.dtls.psk_identity = args[i].clone();
+            },
+            "--psk" => {
+                i += 1;
+                settings.dtls.psk_key = match parse_psk_key(&args[i]) {
+                    Ok(key) => key,
+                    Err(e) => {
+                        error!("{}", e);
+                        usage();
+                    }
+                };
+            },
+            "--cafile" => {
+                i += 1;
+                settings.dtls.ca_file = args[i].clone();
+            },
+            "--cert" => {
+                i += 1;
+                settings.dtls.cert_file = args[i].clone();
+            },
+            "--key" => {
+                i += 1;
+                settings.dtls.key_file = args[i].clone();
+            },
+            "--will-topic" => {
+                i += 1;
+                settings.will_topic = args[i].clone();
+            },
+            "--will-message" => {
+                i += 1;
+                settings.will_message = args[i].clone();
+            },
+            "--will-qos" => {
+                i += 1;
+                settings.will_qos = match args[i].parse() {
+                    Ok(qos) => qos,
+                    Err(_) => {
+                        error!("Invalid will QoS value: {}", args[i]);
+                        usage();
+                    }
+                };
+            },
+            "--will-retain" => {
+                settings.will_retain = true;
+            },
             "-v" => {
                 settings.verbose = true;
             },
             "-V" => {
                 settings.verbose_time = true;
             },
+            "--net-timeout" => {
+                i += 1;
+                settings.network_timeout = args[i].parse::<u64>().expect("Failed to parse network timeout.");
+            },
+            "--net-retries" => {
+                i += 1;
+                settings.network_retries = args[i].parse::<u8>().expect("Failed to parse network retries.");
+            },
             _ => {
                 error!("Unknown option: {}", args[i]);
                 usage();
@@ -129,28 +285,137 @@ fn parse_args() -> Settings{
 
     // Check for missing arguments
     
-    // The QoS value must be 0, 1 or -1
-    if settings.qos != 0 && settings.qos != 1 && settings.qos != -1 {
-        error!("Invalid QoS value: {}", settings.qos);
-        usage();
-    }
-
     // Either topic or topic_id must be provided
-    if settings.topic != "" && settings.topic_id != 0 {
+    if !settings.topic.is_empty() && settings.topic_id != 0 {
         error!("Both topic and topic_id provided. Only one is allowed.");
         usage();
     }
 
     // Topic must be valid for QoS -1
     // That means either a short topic name or a pre-defined topic ID
-    if (settings.qos == -1) && (settings.topic_id == 0) && (settings.topic.len() != 2) {
+    if (settings.qos == QoS::MinusOne) && (settings.topic_id == 0) && (settings.topic.len() != 2) {
         error!("Either a short topic name or a pre-defined topic ID must be provided for QoS -1.");
         usage();
     }
 
+    // Sleeping needs a sleep duration
+    if settings.sleep_cycle && settings.sleep_duration == 0 {
+        error!("Sleep cycle mode requires a sleep duration (-e).");
+        usage();
+    }
+
+    // The will QoS value must be 0, 1 or 2
+    if settings.will_qos == QoS::MinusOne {
+        error!("Invalid will QoS value: {}", settings.will_qos);
+        usage();
+    }
+
+    // IPv6 has no broadcast, SEARCHGW goes to a multicast group
+    if settings.discover && settings.address_family == AddressFamily::IPv6 && settings.multicast_group.is_empty() {
+        error!("Gateway discovery over IPv6 needs a multicast group (--mcast-group).");
+        usage();
+    }
+
+    // The gateway is found by broadcasting over UDP, and a TCP
+    // connection always starts from an ephemeral port
+    if settings.transport == SensorNetworkType::TCP && settings.discover {
+        error!("Gateway discovery is only supported over UDP.");
+        usage();
+    }
+    if settings.transport == SensorNetworkType::TCP && settings.source_port != 0 {
+        error!("A source port can only be set over UDP.");
+        usage();
+    }
+
+    // DTLS needs something to authenticate the gateway with
+    let dtls_options = !settings.dtls.psk_identity.is_empty()
+        || !settings.dtls.psk_key.is_empty()
+        || !settings.dtls.ca_file.is_empty()
+        || !settings.dtls.cert_file.is_empty()
+        || !settings.dtls.key_file.is_empty();
+    if dtls_options && settings.transport != SensorNetworkType::DTLS {
+        error!("DTLS options need --transport dtls.");
+        usage();
+    }
+    if settings.dtls.psk_identity.is_empty() != settings.dtls.psk_key.is_empty() {
+        error!("A pre-shared key needs both --psk-identity and --psk.");
+        usage();
+    }
+    if !settings.dtls.key_file.is_empty() && settings.dtls.cert_file.is_empty() {
+        error!("Private key provided without client certificate.");
+        usage();
+    }
+    if settings.transport == SensorNetworkType::DTLS && settings.dtls.psk_key.is_empty() && settings.dtls.ca_file.is_empty() {
+        error!("DTLS needs a pre-shared key (--psk-identity and --psk) or a CA file (--cafile).");
+        usage();
+    }
+
+    // A will message makes no sense without a will topic
+    if settings.will_topic.is_empty() && !settings.will_message.is_empty() {
+        error!("Will message provided without will topic.");
+        usage();
+    }
+
     settings
 }
 
+// Broadcast a SEARCHGW, or send it to the multicast group, and return
+// the address of the chosen gateway
+fn discover_gateway(settings: &Settings) -> SocketAddr {
+    let discovery_args = if settings.multicast_group.is_empty() {
+        SensorNetworkInitArgs::UDP {
+            source_address: format!("0.0.0.0:{}", settings.source_port),
+            destination_address: format!("255.255.255.255:{}", settings.mqtt_sn_port),
+            timeout: std::time::Duration::from_secs(1),
+            broadcast: true,
+        }
+    } else {
+        let group = match resolve_address(&settings.multicast_group, settings.mqtt_sn_port, settings.address_family) {
+            Ok(group) => group,
+            Err(e) => {
+                error!("Invalid multicast group: {}", e);
+                std::process::exit(1);
+            }
+        };
+        // Gateways answer to the group, on its port
+        SensorNetworkInitArgs::UDPMulticast {
+            source_address: bind_address_for(&group, settings.mqtt_sn_port).to_string(),
+            group_address: group.to_string(),
+            timeout: std::time::Duration::from_secs(1),
+            options: MulticastOptions {
+                hops: settings.multicast_hops,
+                interface: settings.multicast_interface,
+                ..MulticastOptions::default()
+            },
+        }
+    };
+    let boxed_discovery_net = create_sensor_network(SensorNetworkType::UDP, discovery_args);
+    let mut boxed_discovery_net = match boxed_discovery_net {
+        Ok(discovery_net) => discovery_net,
+        Err(e) => {
+            error!("Could not open the network for discovery: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let discovery_net = &mut *boxed_discovery_net;
+    if let Err(e) = discovery_net.initialize() {
+        error!("Could not open the network for discovery: {}", e);
+        std::process::exit(1);
+    }
+
+    match mqtt_sn_discover_gateway(discovery_net, settings) {
+        Ok(GatewayInfo { address: Some(PeerAddress::Socket(address)), .. }) => address,
+        Ok(GatewayInfo { address: Some(address), .. }) => {
+            error!("Invalid gateway address: {}", address);
+            std::process::exit(1);
+        }
+        _ => {
+            error!("Gateway discovery failed.");
+            std::process::exit(1);
+        }
+    }
+}
+
 fn main(){
     // Print the usage if no arguments are provided
     if std::env::args().len() == 1 {
@@ -175,75 +440,199 @@ fn main(){
 
     // Print the settings
     debug!("{:?}", settings);
+    // Talk to the gateway through a forwarder, if requested
+    if settings.forwarder_encapsulation {
+        mqtt_sn_enable_frwdencap(&settings);
+    }
+
+    // Find the gateway, if requested, or resolve the host given with -h
+    let destination = if settings.discover {
+        let destination = discover_gateway(&settings);
+        info!("Using gateway at {}", destination);
+        destination
+    } else {
+        match resolve_address(&settings.mqtt_sn_host, settings.mqtt_sn_port, settings.address_family) {
+            Ok(destination) => destination,
+            Err(e) => {
+                error!("Could not resolve the gateway address: {}", e);
+                std::process::exit(1);
+            }
+        }
+    };
+    let destination_address = destination.to_string();
+
     // First open a connection
-    let mut boxed_sensor_network: Box<dyn SensorNetwork> = create_sensor_network(SensorNetworkType::UDP, SensorNetworkInitArgs::UDP {
-        source_address: format!("0.0.0.0:{}", settings.source_port),
-        destination_address: format!("{}:{}", settings.mqtt_sn_host, settings.mqtt_sn_port),
-        timeout: settings.timeout as u64,
-    });
+    let sensor_net_args = match settings.transport {
+        SensorNetworkType::TCP => SensorNetworkInitArgs::TCP {
+            destination_address,
+            timeout: std::time::Duration::from_millis(settings.network_timeout),
+        },
+        // The gateway certificate is checked against the name given with
+        // -h, or its address if it was discovered
+        SensorNetworkType::DTLS => SensorNetworkInitArgs::DTLS {
+            source_address: bind_address_for(&destination, settings.source_port).to_string(),
+            destination_address,
+            timeout: std::time::Duration::from_millis(settings.network_timeout),
+            security: DtlsSecurity {
+                server_name: if settings.discover {
+                    destination.ip().to_string()
+                } else {
+                    settings.mqtt_sn_host.trim_start_matches('[').trim_end_matches(']').to_string()
+                },
+                ..settings.dtls.clone()
+            },
+        },
+        _ => SensorNetworkInitArgs::UDP {
+            source_address: bind_address_for(&destination, settings.source_port).to_string(),
+            destination_address,
+            timeout: std::time::Duration::from_millis(settings.network_timeout),
+            broadcast: false,
+        },
+    };
+    let mut boxed_sensor_network: Box<dyn SensorNetwork> = match create_sensor_network(settings.transport, sensor_net_args) {
+        Ok(sensor_net) => sensor_net,
+        Err(e) => {
+            error!("Could not open the network: {}", e);
+            std::process::exit(1);
+        }
+    };
 
     let sensor_net = &mut *boxed_sensor_network;
-    sensor_net.initialize();
+    if let Err(e) = sensor_net.initialize() {
+        error!("Could not open the network: {}", e);
+        std::process::exit(1);
+    }
 
     
 
     // Send a CONNECT message
     debug!("Sending CONNECT message");
-    mqtt_sn_connect(sensor_net, &settings); 
+    if let Err(e) = mqtt_sn_connect(sensor_net, &settings) {
+        error!("Could not connect to the gateway: {}", e);
+        std::process::exit(1);
+    }
 
     // Subscribe to the topics by topic name
     for topic in settings.topic_list.iter() {
         debug!("Subscribing to topic: {}", topic);
-        mqtt_sn_send_subscribe_topic_name(sensor_net, &settings, topic);
-        let topic_id = mqtt_sn_receive_suback(sensor_net, &settings);
-
-        if topic_id != 0  && topic.len() > 2 {
-            settings.topic_map.insert(topic_id, topic.clone());
+        match mqtt_sn_send_subscribe_topic_name(sensor_net, &settings, topic)
+            .and_then(|_| mqtt_sn_receive_suback(sensor_net, &settings))
+        {
+            Ok(topic_id) => {
+                if topic_id != 0  && topic.len() > 2 {
+                    settings.topic_map.insert(topic_id, topic.clone());
+                }
+            },
+            Err(e) => error!("Could not subscribe to topic {}: {}", topic, e),
         }
     }
 
     // Subscribe to the topics by topic ID
     for topic_id in settings.topic_id_list.iter() {
         debug!("Subscribing to topic ID: {}", topic_id);
-        mqtt_sn_send_subscribe_topic_id(sensor_net, &settings, *topic_id);
-        mqtt_sn_receive_suback(sensor_net, &settings);
+        if let Err(e) = mqtt_sn_send_subscribe_topic_id(sensor_net, &settings, *topic_id)
+            .and_then(|_| mqtt_sn_receive_suback(sensor_net, &settings))
+        {
+            error!("Could not subscribe to topic ID {}: {}", topic_id, e);
+        }
     }
 
-    loop {
-        // Receive messages
-        debug!("Waiting for a message");
-        let unsafe_packet = mqtt_sn_receive_publish(sensor_net, &settings);
+    // Stop receiving on Ctrl-C, so the session can be closed cleanly
+    let running = Arc::new(AtomicBool::new(true));
+    let handler_running = running.clone();
+    ctrlc::set_handler(move || {
+        handler_running.store(false, Ordering::SeqCst);
+    }).expect("Failed to set Ctrl-C handler.");
 
-        let packet = match unsafe_packet {
-            Some(packet) => packet,
-            None => {
-                warn!("Received an empty packet. Ignoring.");
-                continue;
+    if settings.sleep_cycle {
+        // Emulate a sleeping client: go to sleep, then wake up to receive
+        // the messages buffered by the gateway meanwhile
+        while running.load(Ordering::SeqCst) {
+            if let Err(e) = mqtt_sn_go_to_sleep(sensor_net, &settings) {
+                error!("Could not go to sleep: {}", e);
+                break;
             }
-        };
+            let wake_up_time = Instant::now() + Duration::from_secs(settings.sleep_duration);
+            while running.load(Ordering::SeqCst) && Instant::now() < wake_up_time {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            match mqtt_sn_wake_up(sensor_net, &settings) {
+                Ok(packets) => {
+                    if settings.single_message && !packets.is_empty() {
+                        break;
+                    }
+                },
+                Err(e) => {
+                    error!("Could not wake up: {}", e);
+                    break;
+                }
+            }
+        }
 
-        if packet.data.len() == 0 {
-            warn!("Received an empty packet. Ignoring.");
-            continue;    
+        // Back to the active state, unsubscribing needs it
+        if settings.unsubscribe_on_exit {
+            if let Err(e) = mqtt_sn_connect(sensor_net, &settings) {
+                warn!("Could not reconnect to unsubscribe: {}", e);
+            }
         }
+        // The final DISCONNECT must not put the client to sleep again
+        settings.sleep_duration = 0;
+    } else {
+        while running.load(Ordering::SeqCst) {
+            // Receive messages
+            debug!("Waiting for a message");
+            let unsafe_packet = mqtt_sn_receive_publish(sensor_net, &settings);
+
+            let packet = match unsafe_packet {
+                Ok(packet) => packet,
+                Err(MqttSnError::Timeout) => continue,
+                Err(MqttSnError::Disconnected) => {
+                    error!("Disconnected by the gateway");
+                    std::process::exit(1);
+                },
+                Err(e) => {
+                    warn!("Failed to receive a message: {}", e);
+                    continue;
+                }
+            };
+
+            if packet.data.is_empty() {
+                warn!("Received an empty packet. Ignoring.");
+                continue;    
+            }
         
-        let msg_qos = packet.flags & MQTT_SN_FLAG_QOS_MASK;
-        if msg_qos == MQTT_SN_FLAG_QOS_1 {
-            // Send a PUBACK
-            mqtt_sn_send_puback(sensor_net, &packet, MQTT_SN_ACCEPTED);
-        //} else if msg_qos == MQTT_SN_FLAG_QOS_2 {
-        //    // Send a PUBREC
-        //    mqtt_sn_send_pubrec(sensor_net, &settings, &packet);
-        }
-        if settings.single_message {
-            break;
+            let msg_qos = packet.flags.qos();
+            if msg_qos == QoS::AtLeastOnce {
+                // Send a PUBACK
+                if let Err(e) = mqtt_sn_send_puback(sensor_net, &packet, MQTT_SN_ACCEPTED) {
+                    warn!("Could not acknowledge the message: {}", e);
+                }
+            } else if msg_qos == QoS::ExactlyOnce {
+                // Send a PUBREC and complete the handshake
+                if let Err(e) = mqtt_sn_handle_qos2_publish(sensor_net, &settings, &packet) {
+                    warn!("Could not acknowledge the message: {}", e);
+                }
+            }
+            if settings.single_message {
+                break;
+            }
+
         }
+    }
 
+    // Unsubscribe from everything, if requested
+    if settings.unsubscribe_on_exit {
+        if let Err(e) = mqtt_sn_unsubscribe_all(sensor_net, &settings) {
+            warn!("Could not unsubscribe: {}", e);
+        }
     }
 
     // Send a DISCONNECT message
-    mqtt_sn_send_disconnect(sensor_net, &settings);
     debug!("Sending DISCONNECT message");
-    mqtt_sn_receive_disconnect(sensor_net, &settings);
+    if let Err(e) = mqtt_sn_send_disconnect(sensor_net, &settings)
+        .and_then(|_| mqtt_sn_receive_disconnect(sensor_net, &settings))
+    {
+        warn!("Could not disconnect cleanly: {}", e);
+    }
     
 }
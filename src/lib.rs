@@ -1 +1,7 @@
-pub mod mqttsn;
\ No newline at end of file
+// Without the std feature only the packet codec is built, for firmware
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+pub mod mqttsn;
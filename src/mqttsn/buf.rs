@@ -0,0 +1,140 @@
+// Buffers for the packet codec
+//
+// The encoders write through BufMut, the few methods of bytes::BufMut they
+// need. It is implemented for slices and heapless vectors, so packets can be
+// encoded without an allocator, and for Vec when the alloc feature is on.
+//
+// Variable length fields are read into FieldBuf types. With alloc they are
+// Vecs, without it heapless vectors holding what fits in a packet with a
+// one byte length field.
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+pub trait BufMut {
+    // Bytes that can still be written
+    fn remaining_mut(&self) -> usize;
+    // Panics if the buffer is full, like bytes::BufMut
+    fn put_slice(&mut self, src: &[u8]);
+
+    fn put_u8(&mut self, n: u8) {
+        self.put_slice(&[n]);
+    }
+
+    fn put_u16(&mut self, n: u16) {
+        self.put_slice(&n.to_be_bytes());
+    }
+}
+
+// Writing advances the slice, which ends up holding the unwritten part
+impl BufMut for &mut [u8] {
+    fn remaining_mut(&self) -> usize {
+        self.len()
+    }
+
+    fn put_slice(&mut self, src: &[u8]) {
+        let (head, tail) = core::mem::take(self).split_at_mut(src.len());
+        head.copy_from_slice(src);
+        *self = tail;
+    }
+}
+
+impl<const N: usize> BufMut for heapless::Vec<u8, N> {
+    fn remaining_mut(&self) -> usize {
+        N - self.len()
+    }
+
+    fn put_slice(&mut self, src: &[u8]) {
+        self.extend_from_slice(src).expect("heapless::Vec is full");
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl BufMut for Vec<u8> {
+    fn remaining_mut(&self) -> usize {
+        isize::MAX as usize - self.len()
+    }
+
+    fn put_slice(&mut self, src: &[u8]) {
+        self.extend_from_slice(src);
+    }
+}
+
+// Counts the bytes written instead of storing them, giving the exact size
+// an encoder needs whatever its length field says
+#[derive(Debug, Default)]
+pub struct LengthCounter {
+    pub written: usize,
+}
+
+impl BufMut for LengthCounter {
+    fn remaining_mut(&self) -> usize {
+        usize::MAX - self.written
+    }
+
+    fn put_slice(&mut self, src: &[u8]) {
+        self.written += src.len();
+    }
+}
+
+// A variable length field copied out of a received packet
+pub trait FieldBuf: Sized {
+    // None if bytes is longer than the capacity
+    fn from_field(bytes: &[u8]) -> Option<Self>;
+    // Longest field it holds
+    fn capacity() -> usize;
+}
+
+impl<const N: usize> FieldBuf for heapless::Vec<u8, N> {
+    fn from_field(bytes: &[u8]) -> Option<Self> {
+        heapless::Vec::from_slice(bytes).ok()
+    }
+
+    fn capacity() -> usize {
+        N
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl FieldBuf for Vec<u8> {
+    fn from_field(bytes: &[u8]) -> Option<Self> {
+        Some(bytes.to_vec())
+    }
+
+    fn capacity() -> usize {
+        usize::MAX
+    }
+}
+
+#[cfg(feature = "alloc")]
+mod fields {
+    use alloc::vec::Vec;
+
+    pub type ClientIdBuf = Vec<u8>;
+    pub type TopicBuf = Vec<u8>;
+    pub type PayloadBuf = Vec<u8>;
+    pub type GwAddressBuf = Vec<u8>;
+    pub type WirelessNodeIdBuf = Vec<u8>;
+    pub type InnerPacketBuf = Vec<u8>;
+}
+
+#[cfg(not(feature = "alloc"))]
+mod fields {
+    use crate::mqttsn::constants::{
+        MQTT_SN_MAX_CLIENT_ID_LENGTH,
+        MQTT_SN_MAX_PACKET_LENGTH,
+        MQTT_SN_MAX_PAYLOAD_LENGTH,
+        MQTT_SN_MAX_TOPIC_LENGTH,
+        MQTT_SN_MAX_WIRELESS_NODE_ID_LENGTH,
+    };
+
+    pub type ClientIdBuf = heapless::Vec<u8, MQTT_SN_MAX_CLIENT_ID_LENGTH>;
+    pub type TopicBuf = heapless::Vec<u8, MQTT_SN_MAX_TOPIC_LENGTH>;
+    pub type PayloadBuf = heapless::Vec<u8, MQTT_SN_MAX_PAYLOAD_LENGTH>;
+    // GWINFO leaves three bytes for the header
+    pub type GwAddressBuf = heapless::Vec<u8, { MQTT_SN_MAX_PACKET_LENGTH - 3 }>;
+    pub type WirelessNodeIdBuf = heapless::Vec<u8, MQTT_SN_MAX_WIRELESS_NODE_ID_LENGTH>;
+    pub type InnerPacketBuf = heapless::Vec<u8, MQTT_SN_MAX_PACKET_LENGTH>;
+}
+
+pub use fields::*;
@@ -0,0 +1,121 @@
+// This module defines a client owning everything needed to talk to one
+// gateway: the sensor network, the settings and the session state.
+//
+// Programs simulating many devices can create one client per device,
+// the free functions in pubsub all share a single session per thread.
+
+use crate::mqttsn::error::MqttSnError;
+use crate::mqttsn::network_abstractions::SensorNetwork;
+use crate::mqttsn::packet_types::{PublishPacket, Topic};
+use crate::mqttsn::pubsub::GatewayInfo;
+use crate::mqttsn::session::Session;
+use crate::mqttsn::settings::Settings;
+
+pub struct Client<N: SensorNetwork> {
+    network: N,
+    settings: Settings,
+    session: Session,
+}
+
+impl<N: SensorNetwork> Client<N> {
+    // The network must be initialized already
+    pub fn new(network: N, settings: Settings) -> Client<N> {
+        let mut session = Session::new();
+        if settings.forwarder_encapsulation {
+            session.enable_frwdencap(&settings);
+        }
+        Client {
+            network,
+            settings,
+            session,
+        }
+    }
+
+    pub fn network(&mut self) -> &mut N {
+        &mut self.network
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    // Changes take effect on the next packet, e.g. the QoS of the next publish
+    pub fn settings_mut(&mut self) -> &mut Settings {
+        &mut self.settings
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    pub fn into_network(self) -> N {
+        self.network
+    }
+
+    // Searches for a gateway for settings.discovery_timeout seconds. The
+    // network must be able to reach the gateways, e.g. by broadcast.
+    pub fn discover_gateway(&mut self) -> Result<GatewayInfo, MqttSnError> {
+        self.session.discover_gateway(&mut self.network, &self.settings)
+    }
+
+    pub fn connect(&mut self) -> Result<(), MqttSnError> {
+        self.session.connect(&mut self.network, &self.settings)
+    }
+
+    // Replace the will given in CONNECT with the one in the settings
+    pub fn update_will_topic(&mut self) -> Result<(), MqttSnError> {
+        self.session.update_will_topic(&mut self.network, &self.settings)
+    }
+
+    pub fn update_will_message(&mut self) -> Result<(), MqttSnError> {
+        self.session.update_will_message(&mut self.network, &self.settings)
+    }
+
+    // Returns the topic ID given by the gateway
+    pub fn register(&mut self, topic: &str) -> Result<u16, MqttSnError> {
+        self.session.register(&mut self.network, &self.settings, topic)
+    }
+
+    // Publishes with the QoS and retain flag in the settings. Topic names
+    // are registered first if needed.
+    pub fn publish(&mut self, topic: &Topic, message: &str) -> Result<(), MqttSnError> {
+        self.session.publish(&mut self.network, &self.settings, topic, message)
+    }
+
+    // Returns the topic ID given by the gateway, 0 for wildcards and short
+    // topic names
+    pub fn subscribe(&mut self, topic: &Topic) -> Result<u16, MqttSnError> {
+        self.session.subscribe(&mut self.network, &self.settings, topic)
+    }
+
+    pub fn unsubscribe(&mut self, topic: &Topic) -> Result<(), MqttSnError> {
+        self.session.send_unsubscribe(&mut self.network, topic)?;
+        self.session.receive_unsuback(&mut self.network, &self.settings)
+    }
+
+    // Waits up to settings.timeout seconds for the next message, already
+    // acknowledged when returned. QoS 2 duplicates give None.
+    pub fn poll(&mut self) -> Result<Option<PublishPacket>, MqttSnError> {
+        self.session.poll(&mut self.network, &self.settings)
+    }
+
+    // Asks the gateway to buffer messages for settings.sleep_duration seconds
+    pub fn go_to_sleep(&mut self) -> Result<(), MqttSnError> {
+        self.session.go_to_sleep(&mut self.network, &self.settings)
+    }
+
+    // Returns the messages the gateway buffered while asleep, already
+    // acknowledged
+    pub fn wake_up(&mut self) -> Result<Vec<PublishPacket>, MqttSnError> {
+        self.session.wake_up(&mut self.network, &self.settings)
+    }
+
+    // Goes to sleep, sleeps for settings.sleep_duration seconds and wakes up
+    pub fn sleep_cycle(&mut self) -> Result<Vec<PublishPacket>, MqttSnError> {
+        self.session.sleep_cycle(&mut self.network, &self.settings)
+    }
+
+    pub fn disconnect(&mut self) -> Result<(), MqttSnError> {
+        self.session.disconnect(&mut self.network, &self.settings)
+    }
+}
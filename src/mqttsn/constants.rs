@@ -7,9 +7,19 @@ pub const MQTT_SN_DEFAULT_SERIAL_PORT: &str = "/dev/ttyUSB0";
 pub const MQTT_SN_DEFAULT_BAUDRATE: u32 = 115200;
 pub const MQTT_SN_DEFAULT_TIMEOUT: u64 = 10;
 pub const MQTT_SN_DEFAULT_KEEP_ALIVE: u16 = 3;
+pub const MQTT_SN_DEFAULT_SEARCH_RADIUS: u8 = 1;
+pub const MQTT_SN_DEFAULT_DISCOVERY_TIMEOUT: u64 = 5;
+// Tretry in milliseconds and Nretry
+pub const MQTT_SN_DEFAULT_RETRY_TIMEOUT: u64 = 1000;
+pub const MQTT_SN_DEFAULT_RETRIES: u8 = 3;
+// Seconds to wait before retrying a request rejected because of congestion
+pub const MQTT_SN_DEFAULT_T_WAIT: u64 = 300;
 
 // Limits
 pub const MQTT_SN_MAX_PACKET_LENGTH: usize = 255;
+// Largest packet using the three byte length field
+pub const MQTT_SN_MAX_LONG_PACKET_LENGTH: usize = 65535;
+pub const MQTT_SN_LONG_LENGTH_PREFIX: u8 = 0x01;
 pub const MQTT_SN_MAX_PAYLOAD_LENGTH: usize = MQTT_SN_MAX_PACKET_LENGTH - 7;
 pub const MQTT_SN_MAX_TOPIC_LENGTH: usize = MQTT_SN_MAX_PACKET_LENGTH - 6;
 pub const MQTT_SN_MAX_CLIENT_ID_LENGTH: usize = 23;
@@ -63,6 +73,10 @@ pub const MQTT_SN_FLAG_QOS_N1: u8 = 0x60;
 pub const MQTT_SN_FLAG_RETAIN: u8 = 0x10;
 pub const MQTT_SN_FLAG_WILL: u8 = 0x08;
 pub const MQTT_SN_FLAG_CLEAN: u8 = 0x04;
+pub const MQTT_SN_FLAG_TOPIC_TYPE_MASK: u8 = 0x03;
+
+// Forwarder encapsulation ctrl byte
+pub const MQTT_SN_FRWDENCAP_RADIUS_MASK: u8 = 0x03;
 
 // Topic types
 pub const MQTT_SN_TOPIC_TYPE_NORMAL: u8 = 0x00;
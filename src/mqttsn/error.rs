@@ -0,0 +1,182 @@
+// Errors returned by the library
+//
+// Nothing in the library panics or ends the process, so a long-running
+// program can decide what to do when the gateway or the network fails.
+//
+// DecodeError and EncodeError belong to the no_std codec, MqttSnError to
+// the client and needs the std feature.
+
+use core::fmt;
+
+use crate::mqttsn::packet_types::mqtt_sn_packet_type_to_str;
+#[cfg(feature = "std")]
+use crate::mqttsn::packet_types::ReturnCode;
+
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub enum MqttSnError {
+    // The sensor network could not be opened, or failed to send or receive
+    Transport(std::io::Error),
+    // No answer arrived in time, even after resending the request
+    Timeout,
+    // A malformed packet, or one not expected at this point
+    ProtocolViolation(String),
+    // The gateway answered the request of type msg_type with an error
+    Rejected { msg_type: u8, return_code: ReturnCode },
+    // The gateway ended the session with a DISCONNECT
+    Disconnected,
+    // Something the protocol cannot carry, like a client ID too long
+    InvalidArgument(String),
+    // A received packet could not be decoded
+    Decode(DecodeError),
+    // A packet did not fit in the buffer given to encode it
+    Encode(EncodeError),
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for MqttSnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MqttSnError::Transport(e) => write!(f, "network error: {}", e),
+            MqttSnError::Timeout => write!(f, "timed out waiting for the gateway"),
+            MqttSnError::ProtocolViolation(reason) => write!(f, "protocol violation: {}", reason),
+            MqttSnError::Rejected { msg_type, return_code } => {
+                write!(f, "{} {}", mqtt_sn_packet_type_to_str(*msg_type), return_code)
+            }
+            MqttSnError::Disconnected => write!(f, "disconnected by the gateway"),
+            MqttSnError::InvalidArgument(reason) => write!(f, "invalid argument: {}", reason),
+            MqttSnError::Decode(e) => write!(f, "{}", e),
+            MqttSnError::Encode(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for MqttSnError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MqttSnError::Transport(e) => Some(e),
+            MqttSnError::Decode(e) => Some(e),
+            MqttSnError::Encode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// Read timeouts show up as TimedOut or WouldBlock, depending on the platform
+#[cfg(feature = "std")]
+impl From<std::io::Error> for MqttSnError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => MqttSnError::Timeout,
+            _ => MqttSnError::Transport(e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<serialport::Error> for MqttSnError {
+    fn from(e: serialport::Error) -> Self {
+        MqttSnError::Transport(e.into())
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<DecodeError> for MqttSnError {
+    fn from(e: DecodeError) -> Self {
+        MqttSnError::Decode(e)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<EncodeError> for MqttSnError {
+    fn from(e: EncodeError) -> Self {
+        MqttSnError::Encode(e)
+    }
+}
+
+// Why a packet could not be decoded, and where. The offset counts from
+// the first byte of the buffer given to the decoder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    // None if the buffer ends before the message type
+    pub msg_type: Option<u8>,
+    pub offset: usize,
+    pub kind: DecodeErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeErrorKind {
+    // The length field is missing or incomplete
+    InvalidLength,
+    // The declared length goes past the end of the buffer
+    Truncated { declared: usize, available: usize },
+    // The declared length leaves out fields the message type requires
+    TooShort { length: usize, min_length: usize },
+    // The message type is not the one being decoded
+    UnexpectedType(u8),
+    // The message type is not defined by MQTT-SN v1.2
+    UnknownType(u8),
+    // A field is longer than the fixed capacity buffer meant to hold it
+    FieldTooLong { length: usize, capacity: usize },
+    // The flags byte holds the reserved topic ID type
+    InvalidFlags(u8),
+}
+
+impl DecodeError {
+    pub fn new(msg_type: u8, offset: usize, kind: DecodeErrorKind) -> Self {
+        DecodeError { msg_type: Some(msg_type), offset, kind }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.msg_type {
+            Some(msg_type) => write!(f, "malformed {} packet at byte {}: ", mqtt_sn_packet_type_to_str(msg_type), self.offset)?,
+            None => write!(f, "malformed packet at byte {}: ", self.offset)?,
+        }
+        match &self.kind {
+            DecodeErrorKind::InvalidLength => write!(f, "invalid length field"),
+            DecodeErrorKind::Truncated { declared, available } => {
+                write!(f, "{} bytes declared, only {} received", declared, available)
+            }
+            DecodeErrorKind::TooShort { length, min_length } => {
+                write!(f, "{} bytes long, at least {} expected", length, min_length)
+            }
+            DecodeErrorKind::UnexpectedType(msg_type) => {
+                write!(f, "unexpected message type {}", mqtt_sn_packet_type_to_str(*msg_type))
+            }
+            DecodeErrorKind::UnknownType(msg_type) => write!(f, "unknown message type 0x{:02X}", msg_type),
+            DecodeErrorKind::FieldTooLong { length, capacity } => {
+                write!(f, "{} bytes long field, at most {} supported", length, capacity)
+            }
+            DecodeErrorKind::InvalidFlags(flags) => write!(f, "invalid flags 0x{:02X}", flags),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+// The buffer given to encode_to_slice is shorter than the packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodeError {
+    pub msg_type: u8,
+    pub needed: usize,
+    pub available: usize,
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} packet needs {} bytes, only {} available in the buffer",
+            mqtt_sn_packet_type_to_str(self.msg_type),
+            self.needed,
+            self.available
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for EncodeError {}
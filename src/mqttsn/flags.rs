@@ -0,0 +1,223 @@
+// Typed flags
+//
+// The flags byte of CONNECT, WILLTOPIC, PUBLISH, SUBSCRIBE and the others
+// carrying one, along with the QoS and topic ID type it holds. Flags can
+// only be built from valid values, the reserved topic ID type included.
+
+use core::fmt;
+use core::str::FromStr;
+
+use crate::mqttsn::constants::{
+    MQTT_SN_FLAG_CLEAN,
+    MQTT_SN_FLAG_DUP,
+    MQTT_SN_FLAG_QOS_0,
+    MQTT_SN_FLAG_QOS_1,
+    MQTT_SN_FLAG_QOS_2,
+    MQTT_SN_FLAG_QOS_MASK,
+    MQTT_SN_FLAG_QOS_N1,
+    MQTT_SN_FLAG_RETAIN,
+    MQTT_SN_FLAG_TOPIC_TYPE_MASK,
+    MQTT_SN_FLAG_WILL,
+    MQTT_SN_TOPIC_TYPE_NORMAL,
+    MQTT_SN_TOPIC_TYPE_PREDEFINED,
+    MQTT_SN_TOPIC_TYPE_SHORT,
+};
+
+// Quality of Service. MinusOne is QoS -1, publishing without connecting,
+// only to pre-defined topic IDs and short topic names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QoS {
+    #[default]
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+    MinusOne,
+}
+
+impl QoS {
+    // The QoS bits of the flags byte
+    pub fn bits(self) -> u8 {
+        match self {
+            QoS::AtMostOnce => MQTT_SN_FLAG_QOS_0,
+            QoS::AtLeastOnce => MQTT_SN_FLAG_QOS_1,
+            QoS::ExactlyOnce => MQTT_SN_FLAG_QOS_2,
+            QoS::MinusOne => MQTT_SN_FLAG_QOS_N1,
+        }
+    }
+
+    // Every value of the two QoS bits is valid
+    pub fn from_bits(flags: u8) -> QoS {
+        match flags & MQTT_SN_FLAG_QOS_MASK {
+            MQTT_SN_FLAG_QOS_0 => QoS::AtMostOnce,
+            MQTT_SN_FLAG_QOS_1 => QoS::AtLeastOnce,
+            MQTT_SN_FLAG_QOS_2 => QoS::ExactlyOnce,
+            _ => QoS::MinusOne,
+        }
+    }
+
+    // QoS 1 and 2 are acknowledged by the receiver
+    pub fn is_acknowledged(self) -> bool {
+        matches!(self, QoS::AtLeastOnce | QoS::ExactlyOnce)
+    }
+}
+
+impl From<QoS> for i8 {
+    fn from(qos: QoS) -> i8 {
+        match qos {
+            QoS::AtMostOnce => 0,
+            QoS::AtLeastOnce => 1,
+            QoS::ExactlyOnce => 2,
+            QoS::MinusOne => -1,
+        }
+    }
+}
+
+// Gives back the value if it is not a QoS
+impl TryFrom<i8> for QoS {
+    type Error = i8;
+
+    fn try_from(qos: i8) -> Result<QoS, i8> {
+        match qos {
+            0 => Ok(QoS::AtMostOnce),
+            1 => Ok(QoS::AtLeastOnce),
+            2 => Ok(QoS::ExactlyOnce),
+            -1 => Ok(QoS::MinusOne),
+            _ => Err(qos),
+        }
+    }
+}
+
+// Parses 0, 1, 2 or -1, as given on the command line
+impl FromStr for QoS {
+    type Err = ParseQoSError;
+
+    fn from_str(s: &str) -> Result<QoS, ParseQoSError> {
+        let qos = s.trim().parse::<i8>().map_err(|_| ParseQoSError)?;
+        QoS::try_from(qos).map_err(|_| ParseQoSError)
+    }
+}
+
+impl fmt::Display for QoS {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", i8::from(*self))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseQoSError;
+
+impl fmt::Display for ParseQoSError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "QoS must be 0, 1, 2 or -1")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseQoSError {}
+
+// How the topic of a PUBLISH, SUBSCRIBE or UNSUBSCRIBE is given
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TopicIdType {
+    // A topic ID registered with REGISTER, or a topic name in SUBSCRIBE
+    #[default]
+    Normal,
+    Predefined,
+    // Two character topic name
+    Short,
+}
+
+impl TopicIdType {
+    pub fn bits(self) -> u8 {
+        match self {
+            TopicIdType::Normal => MQTT_SN_TOPIC_TYPE_NORMAL,
+            TopicIdType::Predefined => MQTT_SN_TOPIC_TYPE_PREDEFINED,
+            TopicIdType::Short => MQTT_SN_TOPIC_TYPE_SHORT,
+        }
+    }
+
+    // None for the reserved value 0b11
+    pub fn from_bits(flags: u8) -> Option<TopicIdType> {
+        match flags & MQTT_SN_FLAG_TOPIC_TYPE_MASK {
+            MQTT_SN_TOPIC_TYPE_NORMAL => Some(TopicIdType::Normal),
+            MQTT_SN_TOPIC_TYPE_PREDEFINED => Some(TopicIdType::Predefined),
+            MQTT_SN_TOPIC_TYPE_SHORT => Some(TopicIdType::Short),
+            _ => None,
+        }
+    }
+}
+
+// The flags byte. Fields a packet type does not use are left cleared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Flags(u8);
+
+impl Flags {
+    pub const fn new() -> Flags {
+        Flags(0)
+    }
+
+    // None if the topic ID type is the reserved one
+    pub fn from_bits(bits: u8) -> Option<Flags> {
+        TopicIdType::from_bits(bits).map(|_| Flags(bits))
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn dup(self) -> bool {
+        self.0 & MQTT_SN_FLAG_DUP != 0
+    }
+
+    pub fn qos(self) -> QoS {
+        QoS::from_bits(self.0)
+    }
+
+    pub fn retain(self) -> bool {
+        self.0 & MQTT_SN_FLAG_RETAIN != 0
+    }
+
+    pub fn will(self) -> bool {
+        self.0 & MQTT_SN_FLAG_WILL != 0
+    }
+
+    pub fn clean_session(self) -> bool {
+        self.0 & MQTT_SN_FLAG_CLEAN != 0
+    }
+
+    pub fn topic_id_type(self) -> TopicIdType {
+        // Flags never hold the reserved value
+        TopicIdType::from_bits(self.0).unwrap_or_default()
+    }
+
+    pub fn with_dup(self, dup: bool) -> Flags {
+        self.with_bit(MQTT_SN_FLAG_DUP, dup)
+    }
+
+    pub fn with_qos(self, qos: QoS) -> Flags {
+        Flags((self.0 & !MQTT_SN_FLAG_QOS_MASK) | qos.bits())
+    }
+
+    pub fn with_retain(self, retain: bool) -> Flags {
+        self.with_bit(MQTT_SN_FLAG_RETAIN, retain)
+    }
+
+    pub fn with_will(self, will: bool) -> Flags {
+        self.with_bit(MQTT_SN_FLAG_WILL, will)
+    }
+
+    pub fn with_clean_session(self, clean_session: bool) -> Flags {
+        self.with_bit(MQTT_SN_FLAG_CLEAN, clean_session)
+    }
+
+    pub fn with_topic_id_type(self, topic_id_type: TopicIdType) -> Flags {
+        Flags((self.0 & !MQTT_SN_FLAG_TOPIC_TYPE_MASK) | topic_id_type.bits())
+    }
+
+    fn with_bit(self, bit: u8, set: bool) -> Flags {
+        if set {
+            Flags(self.0 | bit)
+        } else {
+            Flags(self.0 & !bit)
+        }
+    }
+}
@@ -1,5 +1,18 @@
+// Packet codec, no_std
+pub mod buf;
 pub mod constants;
+pub mod error;
+pub mod flags;
 pub mod packet_types;
+
+// Client, built on top of the codec
+#[cfg(feature = "std")]
 pub mod pubsub;
+#[cfg(feature = "std")]
 pub mod settings;
-pub mod network_abstractions;
\ No newline at end of file
+#[cfg(feature = "std")]
+pub mod network_abstractions;
+#[cfg(feature = "std")]
+pub mod session;
+#[cfg(feature = "std")]
+pub mod client;
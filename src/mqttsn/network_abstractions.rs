@@ -5,27 +5,143 @@
 // by implementing the SensorNetwork trait. It is inspired by the
 // network abstractions in the PAHO MQTT-SN client library.
 
-use log::{debug, error, info};
-use std::{net::UdpSocket, time::Duration};
+use log::{debug, error, info, warn};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+// Socket options std leaves out
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+// DTLS
+use openssl::error::ErrorStack;
+use openssl::ssl::{HandshakeError, Ssl, SslContext, SslFiletype, SslMethod, SslOptions, SslStream, SslVerifyMode};
 // SerialPort
 use serialport::{DataBits, FlowControl, Parity, SerialPort};
 use std::io::prelude::*;
 use std::io::Read;
+
+use crate::mqttsn::constants::{MQTT_SN_MAX_LONG_PACKET_LENGTH, MQTT_SN_MAX_PACKET_LENGTH};
+use crate::mqttsn::error::MqttSnError;
+use crate::mqttsn::packet_types::mqtt_sn_decode_length;
+
+// Largest UDP payload over IPv4
+const UDP_MAX_DATAGRAM_LENGTH: usize = 65507;
+// Buffer used by UDP receive, long packets need receive_into
+const UDP_RECEIVE_BUFFER_LENGTH: usize = 1024;
+// Largest plaintext a DTLS record carries
+const DTLS_MAX_RECORD_LENGTH: usize = 16384;
+// Handshake messages are split to fit in datagrams of this size
+const DTLS_MTU: u32 = 1400;
+// Bytes a record adds to its plaintext: the 13 byte header, and with the
+// costliest cipher allowed (CBC with SHA-384) a 16 byte IV, a 48 byte MAC
+// and up to 16 bytes of padding
+const DTLS_MAX_RECORD_OVERHEAD: usize = 13 + 16 + 48 + 16;
+// Time given to the DTLS handshake, and how often its progress is checked
+// so that lost flights are resent
+const DTLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const DTLS_HANDSHAKE_POLL: Duration = Duration::from_millis(250);
+
 // SensorNetwork trait
 pub trait SensorNetwork {
-    fn initialize(&self);
+    fn initialize(&self) -> Result<(), MqttSnError>;
     fn get_description(&self) -> String;
     fn receive(&mut self) -> Result<Vec<u8>, std::io::Error>;
     fn send(&mut self, data: &[u8]) -> Result<usize, std::io::Error>;
     fn get_timeout(&self) -> u64;
-    fn close(&self);
+    fn close(&self) -> Result<(), MqttSnError>;
+    // Largest frame the network can carry. Packets longer than
+    // MQTT_SN_MAX_PACKET_LENGTH are only sent if the network allows it.
+    fn get_max_packet_length(&self) -> usize {
+        MQTT_SN_MAX_PACKET_LENGTH
+    }
+    // Address of the peer that sent the last received packet, if the
+    // network is able to tell.
+    fn get_last_sender(&self) -> Option<PeerAddress> {
+        None
+    }
+    // Receives a packet into the caller's buffer and returns its length.
+    // Networks should override it to skip the copy made here.
+    fn receive_into(&mut self, buffer: &mut [u8]) -> Result<usize, std::io::Error> {
+        let packet = self.receive()?;
+        if packet.len() > buffer.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Received {} bytes, buffer only holds {}", packet.len(), buffer.len()),
+            ));
+        }
+        buffer[..packet.len()].copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+    // Like receive_into, also returning the peer that sent the packet if
+    // the network is able to tell.
+    fn receive_from(&mut self, buffer: &mut [u8]) -> Result<(usize, Option<PeerAddress>), std::io::Error> {
+        let size = self.receive_into(buffer)?;
+        Ok((size, self.get_last_sender()))
+    }
+    // Sends a packet to the given peer. Networks with a single peer send
+    // everything to it, whatever the address.
+    fn send_to(&mut self, data: &[u8], _peer: &PeerAddress) -> Result<usize, std::io::Error> {
+        self.send(data)
+    }
+}
+
+// Address of a peer on a sensor network
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PeerAddress {
+    // UDP and TCP peers
+    Socket(SocketAddr),
+    // Name of the serial port the peer is attached to
+    SerialPort(String),
+    // Wireless node behind a forwarder, from the FRWDENCAP header, or a
+    // gateway address from GWINFO that is not a socket address
+    WirelessNode(Vec<u8>),
+}
+
+impl PeerAddress {
+    // The address a gateway puts in GWINFO. An IPv4 or IPv6 address followed
+    // by the port, both in network byte order, is a socket address. Anything
+    // else is the gateway address on the wireless network.
+    pub fn from_gw_add(gw_add: &[u8]) -> PeerAddress {
+        match gw_add.len() {
+            6 => {
+                let ip = Ipv4Addr::new(gw_add[0], gw_add[1], gw_add[2], gw_add[3]);
+                let port = u16::from_be_bytes([gw_add[4], gw_add[5]]);
+                PeerAddress::Socket(SocketAddr::new(IpAddr::V4(ip), port))
+            }
+            18 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&gw_add[..16]);
+                let port = u16::from_be_bytes([gw_add[16], gw_add[17]]);
+                PeerAddress::Socket(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+            }
+            _ => PeerAddress::WirelessNode(gw_add.to_vec()),
+        }
+    }
+}
+
+impl fmt::Display for PeerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddress::Socket(address) => write!(f, "{}", address),
+            PeerAddress::SerialPort(port_name) => write!(f, "{}", port_name),
+            PeerAddress::WirelessNode(wireless_node_id) => {
+                write!(f, "0x")?;
+                for byte in wireless_node_id {
+                    write!(f, "{:02X}", byte)?;
+                }
+                Ok(())
+            }
+        }
+    }
 }
 
 // SensorNetworkType and SensorNetworkInitArgs
 
 // An enum to represent the different types of sensor networks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SensorNetworkType {
     UDP,
+    TCP,
+    DTLS,
     SerialPort,
 }
 
@@ -34,7 +150,24 @@ pub enum SensorNetworkInitArgs {
     UDP {
         source_address: String,
         destination_address: String,
-        timeout: u64,
+        timeout: Duration,
+        broadcast: bool,
+    },
+    UDPMulticast {
+        source_address: String,
+        group_address: String,
+        timeout: Duration,
+        options: MulticastOptions,
+    },
+    TCP {
+        destination_address: String,
+        timeout: Duration,
+    },
+    DTLS {
+        source_address: String,
+        destination_address: String,
+        timeout: Duration,
+        security: DtlsSecurity,
     },
     SerialPort {
         port_name: String,
@@ -46,25 +179,122 @@ pub enum SensorNetworkInitArgs {
     },
 }
 
+// Address resolution
+
+// IP version used to reach the gateway
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddressFamily {
+    // Whatever the host resolves to first
+    #[default]
+    Any,
+    IPv4,
+    IPv6,
+}
+
+// Resolves a host name or an IP literal, IPv6 ones with or without
+// brackets, to the first address of the requested family.
+pub fn resolve_address(host: &str, port: u16, family: AddressFamily) -> Result<SocketAddr, MqttSnError> {
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    (host, port)
+        .to_socket_addrs()
+        .map_err(MqttSnError::Transport)?
+        .find(|address| match family {
+            AddressFamily::Any => true,
+            AddressFamily::IPv4 => address.is_ipv4(),
+            AddressFamily::IPv6 => address.is_ipv6(),
+        })
+        .ok_or_else(|| MqttSnError::InvalidArgument(format!(
+            "No {} address found for {}",
+            match family {
+                AddressFamily::IPv4 => "IPv4",
+                AddressFamily::IPv6 => "IPv6",
+                AddressFamily::Any => "IP",
+            },
+            host
+        )))
+}
+
+// The wildcard address of the same family as destination, to bind a
+// socket able to reach it
+pub fn bind_address_for(destination: &SocketAddr, port: u16) -> SocketAddr {
+    let ip = match destination {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    SocketAddr::new(ip, port)
+}
+
 // A SensorNetwork factory function
 
 // A function to create a sensor network based on the type
 pub fn create_sensor_network(
     network_type: SensorNetworkType,
     init_args: SensorNetworkInitArgs,
-) -> Box<dyn SensorNetwork> {
+) -> Result<Box<dyn SensorNetwork>, MqttSnError> {
     match network_type {
         SensorNetworkType::UDP => match init_args {
             SensorNetworkInitArgs::UDP {
                 source_address,
                 destination_address,
                 timeout,
-            } => Box::new(UDPSensorNetwork::new(
+                broadcast,
+            } => {
+                if broadcast {
+                    Ok(Box::new(UDPSensorNetwork::new_broadcast(
+                        &source_address,
+                        &destination_address,
+                        timeout,
+                    )?))
+                } else {
+                    Ok(Box::new(UDPSensorNetwork::new(
+                        &source_address,
+                        &destination_address,
+                        timeout,
+                    )?))
+                }
+            }
+            SensorNetworkInitArgs::UDPMulticast {
+                source_address,
+                group_address,
+                timeout,
+                options,
+            } => Ok(Box::new(UDPSensorNetwork::new_multicast(
+                &source_address,
+                &group_address,
+                timeout,
+                options,
+            )?)),
+            _ => Err(MqttSnError::InvalidArgument(String::from(
+                "Invalid initialization arguments for a UDP sensor network",
+            ))),
+        },
+        SensorNetworkType::TCP => match init_args {
+            SensorNetworkInitArgs::TCP {
+                destination_address,
+                timeout,
+            } => Ok(Box::new(TCPSensorNetwork::new(&destination_address, timeout)?)),
+            _ => Err(MqttSnError::InvalidArgument(String::from(
+                "Invalid initialization arguments for a TCP sensor network",
+            ))),
+        },
+        SensorNetworkType::DTLS => match init_args {
+            SensorNetworkInitArgs::DTLS {
+                source_address,
+                destination_address,
+                timeout,
+                security,
+            } => Ok(Box::new(DTLSSensorNetwork::new(
                 &source_address,
                 &destination_address,
                 timeout,
-            )),
-            _ => panic!("Invalid initialization arguments"),
+                &security,
+            )?)),
+            _ => Err(MqttSnError::InvalidArgument(String::from(
+                "Invalid initialization arguments for a DTLS sensor network",
+            ))),
         },
         SensorNetworkType::SerialPort => match init_args {
             SensorNetworkInitArgs::SerialPort {
@@ -74,55 +304,196 @@ pub fn create_sensor_network(
                 data_bits,
                 flow_control,
                 timeout,
-            } => Box::new(SerialPortSensorNetwork::new(
+            } => Ok(Box::new(SerialPortSensorNetwork::new(
                 port_name,
                 baud_rate,
                 parity,
                 data_bits,
                 flow_control,
                 timeout,
-            )),
-            _ => panic!("Invalid initialization arguments"),
+            )?)),
+            _ => Err(MqttSnError::InvalidArgument(String::from(
+                "Invalid initialization arguments for a serial port sensor network",
+            ))),
         },
     }
 }
 
 // UDPSensorNetwork
+// In broadcast and multicast modes the socket is not connected, so
+// packets are sent to the destination (a broadcast address or a group)
+// and received from anyone.
 pub struct UDPSensorNetwork {
     source_address: String,
     destination_address: String,
     socket: UdpSocket,
-    timeout: u64,
+    timeout: Duration,
+    mode: UDPMode,
+    last_sender: Option<SocketAddr>,
+}
+
+enum UDPMode {
+    Connected,
+    Broadcast,
+    Multicast {
+        group: SocketAddr,
+        options: MulticastOptions,
+    },
+}
+
+// Interface a multicast group is joined on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MulticastInterface {
+    // IPv4 address of the interface
+    Address(Ipv4Addr),
+    // Interface index, IPv6 groups are joined by index
+    Index(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MulticastOptions {
+    // TTL over IPv4, hop limit over IPv6
+    pub hops: u32,
+    // None lets the system choose
+    pub interface: Option<MulticastInterface>,
+    // Also receive what this host sends to the group
+    pub loopback: bool,
+}
+
+impl Default for MulticastOptions {
+    fn default() -> Self {
+        MulticastOptions {
+            hops: 1,
+            interface: None,
+            loopback: true,
+        }
+    }
 }
 
 impl UDPSensorNetwork {
-    pub fn new(source_address: &str, destination_address: &str, timeout: u64) -> UDPSensorNetwork {
-        UDPSensorNetwork {
+    pub fn new(source_address: &str, destination_address: &str, timeout: Duration) -> Result<UDPSensorNetwork, MqttSnError> {
+        Ok(UDPSensorNetwork {
             source_address: String::from(source_address),
             destination_address: String::from(destination_address),
-            socket: UdpSocket::bind(source_address).expect("Could not bind to address"),
+            socket: UdpSocket::bind(source_address).map_err(MqttSnError::Transport)?,
+            timeout,
+            mode: UDPMode::Connected,
+            last_sender: None,
+        })
+    }
+
+    pub fn new_broadcast(source_address: &str, destination_address: &str, timeout: Duration) -> Result<UDPSensorNetwork, MqttSnError> {
+        Ok(UDPSensorNetwork {
+            mode: UDPMode::Broadcast,
+            ..UDPSensorNetwork::new(source_address, destination_address, timeout)?
+        })
+    }
+
+    // The group is joined by initialize. The socket may share its port
+    // with other listeners of the group on the same host.
+    pub fn new_multicast(
+        source_address: &str,
+        group_address: &str,
+        timeout: Duration,
+        options: MulticastOptions,
+    ) -> Result<UDPSensorNetwork, MqttSnError> {
+        let group: SocketAddr = group_address
+            .parse()
+            .ok()
+            .filter(|group: &SocketAddr| group.ip().is_multicast())
+            .ok_or_else(|| MqttSnError::InvalidArgument(format!("Not a multicast group: {}", group_address)))?;
+        let source: SocketAddr = source_address
+            .parse()
+            .map_err(|_| MqttSnError::InvalidArgument(format!("Invalid source address: {}", source_address)))?;
+
+        let socket = Socket::new(Domain::for_address(source), Type::DGRAM, Some(Protocol::UDP))
+            .map_err(MqttSnError::Transport)?;
+        socket
+            .set_reuse_address(true)
+            .map_err(MqttSnError::Transport)?;
+        socket
+            .bind(&source.into())
+            .map_err(MqttSnError::Transport)?;
+
+        Ok(UDPSensorNetwork {
+            source_address: String::from(source_address),
+            destination_address: group.to_string(),
+            socket: socket.into(),
             timeout,
+            mode: UDPMode::Multicast { group, options },
+            last_sender: None,
+        })
+    }
+
+    fn join_group(&self, group: &SocketAddr, options: &MulticastOptions) -> Result<(), std::io::Error> {
+        let socket = SockRef::from(&self.socket);
+        match (group.ip(), options.interface) {
+            (IpAddr::V4(_), Some(MulticastInterface::Index(_))) | (IpAddr::V6(_), Some(MulticastInterface::Address(_))) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Multicast interface does not match the address family of group {}", group.ip()),
+                ));
+            }
+            (IpAddr::V4(ip), interface) => {
+                let interface = match interface {
+                    Some(MulticastInterface::Address(address)) => address,
+                    _ => Ipv4Addr::UNSPECIFIED,
+                };
+                socket.join_multicast_v4(&ip, &interface)?;
+                if !interface.is_unspecified() {
+                    socket.set_multicast_if_v4(&interface)?;
+                }
+                socket.set_multicast_ttl_v4(options.hops)?;
+                socket.set_multicast_loop_v4(options.loopback)?;
+            }
+            (IpAddr::V6(ip), interface) => {
+                let index = match interface {
+                    Some(MulticastInterface::Index(index)) => index,
+                    _ => 0,
+                };
+                socket.join_multicast_v6(&ip, index)?;
+                if index != 0 {
+                    socket.set_multicast_if_v6(index)?;
+                }
+                socket.set_multicast_hops_v6(options.hops)?;
+                socket.set_multicast_loop_v6(options.loopback)?;
+            }
         }
+        Ok(())
     }
 }
 
 impl SensorNetwork for UDPSensorNetwork {
     fn get_timeout(&self) -> u64 {
-        self.timeout
+        self.timeout.as_millis() as u64
     }
 
-    fn initialize(&self) {
-        // Connect to the destination address
-        self.socket
-            .connect(&self.destination_address)
-            .expect("Could not connect to destination address");
+    fn initialize(&self) -> Result<(), MqttSnError> {
+        match &self.mode {
+            UDPMode::Connected => {
+                // Connect to the destination address
+                self.socket
+                    .connect(&self.destination_address)
+                    .map_err(MqttSnError::Transport)?;
+            }
+            UDPMode::Broadcast => {
+                self.socket
+                    .set_broadcast(true)
+                    .map_err(MqttSnError::Transport)?;
+            }
+            UDPMode::Multicast { group, options } => {
+                self.join_group(group, options)
+                    .map_err(MqttSnError::Transport)?;
+            }
+        }
 
         // Set the timeout
-        if self.timeout > 0 {
+        if !self.timeout.is_zero() {
             self.socket
-                .set_read_timeout(Some(std::time::Duration::from_secs(self.timeout)))
-                .expect("Could not set read timeout");
+                .set_read_timeout(Some(self.timeout))
+                .map_err(MqttSnError::Transport)?;
         }
+        Ok(())
     }
 
     fn get_description(&self) -> String {
@@ -133,7 +504,11 @@ impl SensorNetwork for UDPSensorNetwork {
     }
 
     fn send(&mut self, data: &[u8]) -> Result<usize, std::io::Error> {
-        match self.socket.send(data) {
+        let result = match self.mode {
+            UDPMode::Connected => self.socket.send(data),
+            _ => self.socket.send_to(data, &self.destination_address),
+        };
+        match result {
             Ok(size) => {
                 info!("Sent {} bytes", size);
                 Ok(size)
@@ -146,13 +521,507 @@ impl SensorNetwork for UDPSensorNetwork {
     }
 
     fn receive(&mut self) -> Result<Vec<u8>, std::io::Error> {
-        let mut buffer = [0; 1024];
-        let (size, _) = self.socket.recv_from(&mut buffer)?;
-        Ok(buffer[0..size].to_vec())
+        let mut buffer = vec![0; UDP_RECEIVE_BUFFER_LENGTH];
+        let size = self.receive_into(&mut buffer)?;
+        buffer.truncate(size);
+        Ok(buffer)
     }
 
-    fn close(&self) {
-        // Nothing to do here
+    // Datagrams longer than the buffer are cut short
+    fn receive_into(&mut self, buffer: &mut [u8]) -> Result<usize, std::io::Error> {
+        self.receive_from(buffer).map(|(size, _)| size)
+    }
+
+    fn receive_from(&mut self, buffer: &mut [u8]) -> Result<(usize, Option<PeerAddress>), std::io::Error> {
+        let (size, sender) = self.socket.recv_from(buffer)?;
+        self.last_sender = Some(sender);
+        Ok((size, Some(PeerAddress::Socket(sender))))
+    }
+
+    // Goes to the peer even if the socket is connected to another one
+    fn send_to(&mut self, data: &[u8], peer: &PeerAddress) -> Result<usize, std::io::Error> {
+        let address = match peer {
+            PeerAddress::Socket(address) => address,
+            peer => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Cannot send to {} over UDP", peer),
+                ));
+            }
+        };
+        match self.socket.send_to(data, address) {
+            Ok(size) => {
+                info!("Sent {} bytes to {}", size, address);
+                Ok(size)
+            }
+            Err(e) => {
+                error!("Error sending data to {}: {}", address, e);
+                Err(e)
+            }
+        }
+    }
+
+    fn close(&self) -> Result<(), MqttSnError> {
+        if let UDPMode::Multicast { group, options } = &self.mode {
+            let result = match group.ip() {
+                IpAddr::V4(ip) => match options.interface {
+                    Some(MulticastInterface::Address(address)) => self.socket.leave_multicast_v4(&ip, &address),
+                    _ => self.socket.leave_multicast_v4(&ip, &Ipv4Addr::UNSPECIFIED),
+                },
+                IpAddr::V6(ip) => match options.interface {
+                    Some(MulticastInterface::Index(index)) => self.socket.leave_multicast_v6(&ip, index),
+                    _ => self.socket.leave_multicast_v6(&ip, 0),
+                },
+            };
+            result.map_err(MqttSnError::Transport)?;
+        }
+        Ok(())
+    }
+
+    fn get_max_packet_length(&self) -> usize {
+        UDP_MAX_DATAGRAM_LENGTH
+    }
+
+    fn get_last_sender(&self) -> Option<PeerAddress> {
+        self.last_sender.map(PeerAddress::Socket)
+    }
+}
+
+// TCPSensorNetwork
+// The gateway is reached over a TCP connection carrying MQTT-SN packets
+// back to back. Received bytes are kept until a whole packet, as told by
+// its length field, has arrived.
+pub struct TCPSensorNetwork {
+    destination_address: String,
+    stream: TcpStream,
+    timeout: Duration,
+    // Bytes received past the last returned packet
+    pending: Vec<u8>,
+}
+
+impl TCPSensorNetwork {
+    pub fn new(destination_address: &str, timeout: Duration) -> Result<TCPSensorNetwork, MqttSnError> {
+        Ok(TCPSensorNetwork {
+            destination_address: String::from(destination_address),
+            stream: TcpStream::connect(destination_address).map_err(MqttSnError::Transport)?,
+            timeout,
+            pending: Vec::new(),
+        })
+    }
+
+    // Length of the first packet in pending, None until all of it arrived
+    fn pending_packet_length(&self) -> Result<Option<usize>, std::io::Error> {
+        match mqtt_sn_decode_length(&self.pending) {
+            // A packet always goes past its length field, anything else
+            // means the packet boundaries are lost
+            Some((length, header_length)) if (length as usize) <= header_length => Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("Invalid packet length {} in the TCP stream, closing the connection", length),
+            )),
+            Some((length, _)) if self.pending.len() >= length as usize => Ok(Some(length as usize)),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl SensorNetwork for TCPSensorNetwork {
+    fn get_timeout(&self) -> u64 {
+        self.timeout.as_millis() as u64
+    }
+
+    fn initialize(&self) -> Result<(), MqttSnError> {
+        // Packets are small, send them right away
+        self.stream
+            .set_nodelay(true)
+            .map_err(MqttSnError::Transport)?;
+
+        // Set the timeout
+        if !self.timeout.is_zero() {
+            self.stream
+                .set_read_timeout(Some(self.timeout))
+                .map_err(MqttSnError::Transport)?;
+        }
+        Ok(())
+    }
+
+    fn get_description(&self) -> String {
+        format!("TCP Sensor Network: Destination: {}", self.destination_address)
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<usize, std::io::Error> {
+        match self.stream.write_all(data) {
+            Ok(()) => {
+                info!("Sent {} bytes", data.len());
+                Ok(data.len())
+            }
+            Err(e) => {
+                error!("Error sending data: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    fn receive(&mut self) -> Result<Vec<u8>, std::io::Error> {
+        let mut buffer = vec![0; MQTT_SN_MAX_LONG_PACKET_LENGTH];
+        let size = self.receive_into(&mut buffer)?;
+        buffer.truncate(size);
+        Ok(buffer)
+    }
+
+    // On a timeout the bytes of a partly received packet are kept for
+    // the next call
+    fn receive_into(&mut self, buffer: &mut [u8]) -> Result<usize, std::io::Error> {
+        loop {
+            let packet_length = match self.pending_packet_length() {
+                Ok(packet_length) => packet_length,
+                Err(e) => {
+                    // Nothing more can be read from the stream
+                    self.pending.clear();
+                    let _ = self.stream.shutdown(Shutdown::Both);
+                    return Err(e);
+                }
+            };
+            if let Some(length) = packet_length {
+                if length > buffer.len() {
+                    // Skip the packet, the next one still starts in sync
+                    self.pending.drain(..length);
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Received {} bytes, buffer only holds {}", length, buffer.len()),
+                    ));
+                }
+                buffer[..length].copy_from_slice(&self.pending[..length]);
+                self.pending.drain(..length);
+                return Ok(length);
+            }
+
+            let mut chunk = [0u8; 1024];
+            let size = self.stream.read(&mut chunk)?;
+            if size == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "Connection closed by the gateway",
+                ));
+            }
+            debug!("Bytes read: {}", size);
+            self.pending.extend_from_slice(&chunk[..size]);
+        }
+    }
+
+    fn close(&self) -> Result<(), MqttSnError> {
+        self.stream
+            .shutdown(Shutdown::Both)
+            .map_err(MqttSnError::Transport)
+    }
+
+    // The stream carries packets of any length
+    fn get_max_packet_length(&self) -> usize {
+        MQTT_SN_MAX_LONG_PACKET_LENGTH
+    }
+
+    fn get_last_sender(&self) -> Option<PeerAddress> {
+        self.stream.peer_addr().ok().map(PeerAddress::Socket)
+    }
+}
+
+// DTLSSensorNetwork
+// MQTT-SN over DTLS 1.2, on a UDP socket connected to the gateway. Every
+// packet travels in its own record. The handshake is done when the
+// network is created, and close_notify is sent when it is dropped.
+pub struct DTLSSensorNetwork {
+    source_address: String,
+    destination_address: String,
+    timeout: Duration,
+    stream: SslStream<DatagramChannel>,
+}
+
+// Credentials for the DTLS handshake, empty fields are not used. A
+// pre-shared key, a CA file or both must be given.
+#[derive(Clone, Default)]
+pub struct DtlsSecurity {
+    // Identity the gateway knows the pre-shared key by
+    pub psk_identity: String,
+    pub psk_key: Vec<u8>,
+    // PEM file with the CAs the gateway certificate must chain to
+    pub ca_file: String,
+    // PEM files with the client certificate and its private key, the key
+    // is read from cert_file if key_file is empty
+    pub cert_file: String,
+    pub key_file: String,
+    // Name or IP address the gateway certificate must be issued to
+    pub server_name: String,
+}
+
+// The key stays out of the logs
+impl fmt::Debug for DtlsSecurity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DtlsSecurity")
+            .field("psk_identity", &self.psk_identity)
+            .field("psk_key", &format_args!("<{} bytes>", self.psk_key.len()))
+            .field("ca_file", &self.ca_file)
+            .field("cert_file", &self.cert_file)
+            .field("key_file", &self.key_file)
+            .field("server_name", &self.server_name)
+            .finish()
+    }
+}
+
+// Decodes a pre-shared key written in hex, the way gateways take it
+pub fn parse_psk_key(key: &str) -> Result<Vec<u8>, MqttSnError> {
+    let invalid = || MqttSnError::InvalidArgument(String::from(
+        "The pre-shared key must be an even number of hex digits",
+    ));
+    if key.is_empty() || !key.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    (0..key.len())
+        .step_by(2)
+        .map(|i| {
+            key.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect()
+}
+
+// A connected UDP socket, read and written one datagram at a time as
+// DTLS expects
+struct DatagramChannel {
+    socket: UdpSocket,
+}
+
+impl Read for DatagramChannel {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, std::io::Error> {
+        self.socket.recv(buffer)
+    }
+}
+
+impl Write for DatagramChannel {
+    fn write(&mut self, data: &[u8]) -> Result<usize, std::io::Error> {
+        self.socket.send(data)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+impl DTLSSensorNetwork {
+    pub fn new(
+        source_address: &str,
+        destination_address: &str,
+        timeout: Duration,
+        security: &DtlsSecurity,
+    ) -> Result<DTLSSensorNetwork, MqttSnError> {
+        let ssl = DTLSSensorNetwork::client_ssl(security)?;
+        let socket = UdpSocket::bind(source_address).map_err(MqttSnError::Transport)?;
+        socket
+            .connect(destination_address)
+            .map_err(MqttSnError::Transport)?;
+
+        // Waiting on the socket in short steps gives OpenSSL the chance
+        // to resend a flight the gateway did not answer
+        socket
+            .set_read_timeout(Some(DTLS_HANDSHAKE_POLL))
+            .map_err(MqttSnError::Transport)?;
+        let deadline = Instant::now() + DTLS_HANDSHAKE_TIMEOUT;
+        let mut handshake = ssl.connect(DatagramChannel { socket });
+        let stream = loop {
+            match handshake {
+                Ok(stream) => break stream,
+                Err(HandshakeError::WouldBlock(stream)) if Instant::now() < deadline => {
+                    handshake = stream.handshake();
+                }
+                Err(HandshakeError::WouldBlock(_)) => {
+                    warn!("DTLS handshake with {} timed out", destination_address);
+                    return Err(MqttSnError::Timeout);
+                }
+                Err(HandshakeError::SetupFailure(e)) => return Err(dtls_setup_error(e)),
+                Err(HandshakeError::Failure(stream)) => {
+                    return Err(MqttSnError::Transport(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionRefused,
+                        format!("DTLS handshake with {} failed: {}", destination_address, stream.error()),
+                    )));
+                }
+            }
+        };
+        stream
+            .get_ref()
+            .socket
+            .set_read_timeout(None)
+            .map_err(MqttSnError::Transport)?;
+        debug!(
+            "DTLS session with {} using {}",
+            destination_address,
+            stream.ssl().current_cipher().map(|cipher| cipher.name()).unwrap_or("no cipher")
+        );
+
+        Ok(DTLSSensorNetwork {
+            source_address: String::from(source_address),
+            destination_address: String::from(destination_address),
+            timeout,
+            stream,
+        })
+    }
+
+    fn client_ssl(security: &DtlsSecurity) -> Result<Ssl, MqttSnError> {
+        let has_psk = !security.psk_identity.is_empty() && !security.psk_key.is_empty();
+        if !has_psk && security.ca_file.is_empty() {
+            return Err(MqttSnError::InvalidArgument(String::from(
+                "DTLS needs a pre-shared key or a CA file to check the gateway against",
+            )));
+        }
+
+        let mut context = SslContext::builder(SslMethod::dtls_client()).map_err(dtls_setup_error)?;
+        // The socket cannot tell its MTU, DTLS_MTU is set instead
+        context.set_options(SslOptions::NO_QUERY_MTU);
+
+        if has_psk {
+            let identity = security.psk_identity.clone().into_bytes();
+            let key = security.psk_key.clone();
+            context.set_psk_client_callback(move |_, _, identity_buffer, key_buffer| {
+                // The identity goes NUL terminated
+                if identity.len() >= identity_buffer.len() || key.len() > key_buffer.len() {
+                    error!("The DTLS pre-shared key or its identity is too long");
+                    return Ok(0);
+                }
+                identity_buffer[..identity.len()].copy_from_slice(&identity);
+                identity_buffer[identity.len()] = 0;
+                key_buffer[..key.len()].copy_from_slice(&key);
+                Ok(key.len())
+            });
+        }
+
+        if security.ca_file.is_empty() {
+            // Nothing to check a certificate against, only the key can
+            // authenticate the gateway
+            context.set_cipher_list("PSK").map_err(dtls_setup_error)?;
+        } else {
+            if has_psk {
+                context.set_cipher_list("DEFAULT:PSK").map_err(dtls_setup_error)?;
+            }
+            context
+                .set_ca_file(&security.ca_file)
+                .map_err(|e| dtls_file_error(&security.ca_file, e))?;
+            context.set_verify(SslVerifyMode::PEER);
+        }
+
+        if !security.cert_file.is_empty() {
+            let key_file = if security.key_file.is_empty() {
+                &security.cert_file
+            } else {
+                &security.key_file
+            };
+            context
+                .set_certificate_chain_file(&security.cert_file)
+                .map_err(|e| dtls_file_error(&security.cert_file, e))?;
+            context
+                .set_private_key_file(key_file, SslFiletype::PEM)
+                .map_err(|e| dtls_file_error(key_file, e))?;
+            context
+                .check_private_key()
+                .map_err(|e| dtls_file_error(key_file, e))?;
+        }
+
+        let context = context.build();
+        let mut ssl = Ssl::new(&context).map_err(dtls_setup_error)?;
+        ssl.set_mtu(DTLS_MTU).map_err(dtls_setup_error)?;
+        if !security.server_name.is_empty() {
+            match security.server_name.parse::<IpAddr>() {
+                Ok(ip) => ssl.param_mut().set_ip(ip),
+                Err(_) => ssl
+                    .set_hostname(&security.server_name)
+                    .and_then(|_| ssl.param_mut().set_host(&security.server_name)),
+            }
+            .map_err(dtls_setup_error)?;
+        }
+        Ok(ssl)
+    }
+}
+
+fn dtls_setup_error(e: ErrorStack) -> MqttSnError {
+    MqttSnError::InvalidArgument(format!("Could not set up DTLS: {}", e))
+}
+
+fn dtls_file_error(file: &str, e: ErrorStack) -> MqttSnError {
+    MqttSnError::InvalidArgument(format!("Could not load {}: {}", file, e))
+}
+
+impl SensorNetwork for DTLSSensorNetwork {
+    fn get_timeout(&self) -> u64 {
+        self.timeout.as_millis() as u64
+    }
+
+    fn initialize(&self) -> Result<(), MqttSnError> {
+        // Set the timeout
+        if !self.timeout.is_zero() {
+            self.stream
+                .get_ref()
+                .socket
+                .set_read_timeout(Some(self.timeout))
+                .map_err(MqttSnError::Transport)?;
+        }
+        Ok(())
+    }
+
+    fn get_description(&self) -> String {
+        format!(
+            "DTLS Sensor Network: Source: {}, Destination: {}, Cipher: {}",
+            self.source_address,
+            self.destination_address,
+            self.stream.ssl().current_cipher().map(|cipher| cipher.name()).unwrap_or("none")
+        )
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<usize, std::io::Error> {
+        match self.stream.write(data) {
+            Ok(size) => {
+                info!("Sent {} bytes", size);
+                Ok(size)
+            }
+            Err(e) => {
+                error!("Error sending data: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    fn receive(&mut self) -> Result<Vec<u8>, std::io::Error> {
+        let mut buffer = vec![0; DTLS_MAX_RECORD_LENGTH];
+        let size = self.receive_into(&mut buffer)?;
+        buffer.truncate(size);
+        Ok(buffer)
+    }
+
+    fn receive_into(&mut self, buffer: &mut [u8]) -> Result<usize, std::io::Error> {
+        match self.stream.read(buffer)? {
+            // The gateway sent close_notify
+            0 => Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Connection closed by the gateway",
+            )),
+            size => Ok(size),
+        }
+    }
+
+    fn close(&self) -> Result<(), MqttSnError> {
+        // close_notify needs the stream mutably, it is sent on drop
+        Ok(())
+    }
+
+    // Every packet must fit in one datagram
+    fn get_max_packet_length(&self) -> usize {
+        DTLS_MTU as usize - DTLS_MAX_RECORD_OVERHEAD
+    }
+
+    fn get_last_sender(&self) -> Option<PeerAddress> {
+        self.stream.get_ref().socket.peer_addr().ok().map(PeerAddress::Socket)
+    }
+}
+
+impl Drop for DTLSSensorNetwork {
+    fn drop(&mut self) {
+        let _ = self.stream.shutdown();
     }
 }
 
@@ -176,16 +1045,15 @@ impl SerialPortSensorNetwork {
         data_bits: DataBits,
         flow_control: FlowControl,
         timeout: Duration,
-    ) -> SerialPortSensorNetwork {
+    ) -> Result<SerialPortSensorNetwork, MqttSnError> {
         let port = serialport::new(port_name.as_str(), baud_rate)
             .data_bits(data_bits)
             .parity(parity)
             .flow_control(flow_control)
             //.timeout(timeout)
-            .open()
-            .expect("Could not open serial port");
+            .open()?;
 
-        SerialPortSensorNetwork {
+        Ok(SerialPortSensorNetwork {
             port_name,
             baud_rate,
             parity,
@@ -193,7 +1061,7 @@ impl SerialPortSensorNetwork {
             flow_control,
             timeout,
             port,
-        }
+        })
     }
 }
 
@@ -202,8 +1070,9 @@ impl SensorNetwork for SerialPortSensorNetwork {
         self.timeout.as_millis() as u64
     }
 
-    fn initialize(&self) {
+    fn initialize(&self) -> Result<(), MqttSnError> {
         // Nothing to do here
+        Ok(())
     }
 
     fn get_description(&self) -> String {
@@ -215,7 +1084,7 @@ impl SensorNetwork for SerialPortSensorNetwork {
         match self.port.write_all(data) {
             Ok(()) => {
                 info!("Sent {} bytes", data.len());
-                Ok(data.len() as usize)
+                Ok(data.len())
             }
             Err(e) => {
                 error!("Error sending data: {}", e);
@@ -225,68 +1094,399 @@ impl SensorNetwork for SerialPortSensorNetwork {
     }
 
     fn receive(&mut self) -> Result<Vec<u8>, std::io::Error> {
-        let mut serial_buf: Vec<u8> = vec![0; 255];
-        let mut message_size = 0;
-        let mut pos: usize = 0;
-        let mut bytes_read: Result<usize, std::io::Error>;
-        loop {
-            // Read message size, only the first byte
-            while message_size == 0 {
-                bytes_read = self.port.read(&mut serial_buf);
-                match bytes_read {
-                    Ok(0) => {
-                        debug!("Error: No bytes read");
-                        continue;
-                    }
-                    Ok(_) => {
-                        ();
-                    }
-                    Err(_) => {
-                        debug!("Timeout reached");
-                        break;
-                    }
+        let mut serial_buf: Vec<u8> = vec![0; MQTT_SN_MAX_PACKET_LENGTH];
+        let size = self.receive_into(&mut serial_buf)?;
+        serial_buf.truncate(size);
+        Ok(serial_buf)
+    }
+
+    fn receive_into(&mut self, serial_buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        // Read message size, only the first byte
+        // A read timeout is passed on as it is
+        let safe_bytes_read = self.port.read(serial_buf)?;
+        // Nothing arrived, report it like the other networks do
+        if safe_bytes_read == 0 {
+            debug!("Error: No bytes read");
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "No bytes read from the serial port",
+            ));
+        }
+        debug!("Bytes read: {}", safe_bytes_read);
+        let message_size = serial_buf[0] as usize;
+        let mut pos = safe_bytes_read;
+        if message_size == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Received packet has no length",
+            ));
+        }
+        // Read the rest of the message
+        let _ = self.port.set_timeout(self.timeout);
+        while pos < message_size {
+            let safe_bytes_read = match self.port.read(&mut serial_buf[pos..]) {
+                Ok(0) => {
+                    debug!("Error: No more bytes read");
+                    break;
                 }
-                let safe_bytes_read = bytes_read.unwrap();
-                debug!("Bytes read: {}", safe_bytes_read);
-                if safe_bytes_read > 0 {
-                    message_size = serial_buf[0] as usize;
-                    pos = safe_bytes_read;
+                Ok(bytes_read) => bytes_read,
+                Err(_) => {
+                    debug!("Timeout reached");
+                    break;
                 }
-            }
-            // Read the rest of the message
-            let _ = self.port.set_timeout(self.timeout);
-            while pos < message_size {
-                bytes_read = self.port.read(&mut serial_buf[pos..]);
-                match bytes_read {
-                    Ok(0) => {
-                        debug!("Error: No more bytes read");
-                        break;
-                    }
-                    Ok(_) => {
-                        ();
-                    }
-                    Err(_) => {
-                        debug!("Timeout reached");
-                        break;
-                    }
+            };
+            debug!("Bytes read: {}", safe_bytes_read);
+            pos += safe_bytes_read;
+        }
+        // If the message is complete, return it
+        if pos >= message_size {
+            Ok(message_size)
+        } else {
+            Err(std::io::Error::other(
+                "Error reading message",
+            ))
+        }
+    }
+
+    fn close(&self) -> Result<(), MqttSnError> {
+        // Nothing to do here
+        Ok(())
+    }
+
+    // Whatever is read comes from the device on the port
+    fn get_last_sender(&self) -> Option<PeerAddress> {
+        Some(PeerAddress::SerialPort(self.port_name.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::{BigNum, MsbOption};
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+    use openssl::x509::{X509NameBuilder, X509};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+
+    const PSK_IDENTITY: &str = "sensor";
+    const PSK_KEY: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+    // Gateway stand-in: completes the handshake with the first client and
+    // echoes every record back
+    fn spawn_echo_gateway(context: SslContext) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (_, client) = socket.peek_from(&mut [0u8; 1]).unwrap();
+            socket.connect(client).unwrap();
+            let mut ssl = Ssl::new(&context).unwrap();
+            ssl.set_mtu(DTLS_MTU).unwrap();
+            let mut stream = match ssl.accept(DatagramChannel { socket }) {
+                Ok(stream) => stream,
+                Err(_) => return,
+            };
+            let mut record = [0u8; DTLS_MAX_RECORD_LENGTH];
+            while let Ok(size) = stream.read(&mut record) {
+                if size == 0 || stream.write_all(&record[..size]).is_err() {
+                    break;
                 }
-                let safe_bytes_read = bytes_read.unwrap();
-                debug!("Bytes read: {}", safe_bytes_read);
-                pos += safe_bytes_read;
             }
-            // If the message is complete, return it
-            if pos >= message_size {
-                return Ok(serial_buf[0..message_size].to_vec());
-            } else {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Error reading message",
-                ));
+        });
+        address
+    }
+
+    fn psk_gateway_context() -> SslContext {
+        let mut context = SslContext::builder(SslMethod::dtls_server()).unwrap();
+        context.set_options(SslOptions::NO_QUERY_MTU);
+        context.set_cipher_list("PSK").unwrap();
+        context.set_psk_server_callback(|_, identity, key_buffer| {
+            if identity != Some(PSK_IDENTITY.as_bytes()) {
+                return Ok(0);
+            }
+            key_buffer[..PSK_KEY.len()].copy_from_slice(&PSK_KEY);
+            Ok(PSK_KEY.len())
+        });
+        context.build()
+    }
+
+    fn key() -> PKey<Private> {
+        PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()
+    }
+
+    // Certificate for common_name, self-signed if no issuer is given
+    fn certificate(common_name: &str, key: &PKey<Private>, issuer: Option<(&X509, &PKey<Private>)>) -> X509 {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", common_name).unwrap();
+        let name = name.build();
+        let mut serial = BigNum::new().unwrap();
+        serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(&serial.to_asn1_integer().unwrap()).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        let signing_key = match issuer {
+            Some((issuer_certificate, issuer_key)) => {
+                builder.set_issuer_name(issuer_certificate.subject_name()).unwrap();
+                let alternative_name = SubjectAlternativeName::new()
+                    .ip("127.0.0.1")
+                    .build(&builder.x509v3_context(Some(issuer_certificate), None))
+                    .unwrap();
+                builder.append_extension(alternative_name).unwrap();
+                issuer_key
+            }
+            None => {
+                builder.set_issuer_name(&name).unwrap();
+                builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+                key
             }
+        };
+        builder.sign(signing_key, MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    fn write_pem(name: &str, pem: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mqtt-sn-tools-rs-{}-{}.pem", std::process::id(), name));
+        std::fs::write(&path, pem).unwrap();
+        path
+    }
+
+    fn echo(security: &DtlsSecurity, gateway: SocketAddr) -> Result<Vec<u8>, MqttSnError> {
+        let mut network = DTLSSensorNetwork::new("127.0.0.1:0", &gateway.to_string(), Duration::from_secs(5), security)?;
+        network.initialize()?;
+        let pingreq = [2, 0x16];
+        network.send(&pingreq)?;
+        let mut buffer = [0u8; 16];
+        let (size, sender) = network.receive_from(&mut buffer)?;
+        assert_eq!(sender, Some(PeerAddress::Socket(gateway)));
+        Ok(buffer[..size].to_vec())
+    }
+
+    #[test]
+    fn psk_key_from_hex() {
+        assert_eq!(parse_psk_key("0aFF").unwrap(), vec![0x0A, 0xFF]);
+        assert!(parse_psk_key("abc").is_err());
+        assert!(parse_psk_key("zz").is_err());
+        assert!(parse_psk_key("").is_err());
+    }
+
+    #[test]
+    fn dtls_psk_echo() {
+        let gateway = spawn_echo_gateway(psk_gateway_context());
+        let security = DtlsSecurity {
+            psk_identity: String::from(PSK_IDENTITY),
+            psk_key: PSK_KEY.to_vec(),
+            ..DtlsSecurity::default()
+        };
+        assert_eq!(echo(&security, gateway).unwrap(), vec![2, 0x16]);
+    }
+
+    #[test]
+    fn dtls_max_packet_echo() {
+        let gateway = spawn_echo_gateway(psk_gateway_context());
+        let security = DtlsSecurity {
+            psk_identity: String::from(PSK_IDENTITY),
+            psk_key: PSK_KEY.to_vec(),
+            ..DtlsSecurity::default()
+        };
+        let mut network = DTLSSensorNetwork::new("127.0.0.1:0", &gateway.to_string(), Duration::from_secs(5), &security).unwrap();
+        network.initialize().unwrap();
+
+        let packet = vec![0xAB; network.get_max_packet_length()];
+        network.send(&packet).unwrap();
+        assert_eq!(network.receive().unwrap(), packet);
+    }
+
+    #[test]
+    fn dtls_unknown_psk_identity_fails() {
+        let gateway = spawn_echo_gateway(psk_gateway_context());
+        let security = DtlsSecurity {
+            psk_identity: String::from("intruder"),
+            psk_key: PSK_KEY.to_vec(),
+            ..DtlsSecurity::default()
+        };
+        assert!(echo(&security, gateway).is_err());
+    }
+
+    #[test]
+    fn dtls_certificate_echo() {
+        let ca_key = key();
+        let ca = certificate("Test CA", &ca_key, None);
+        let gateway_key = key();
+        let gateway_certificate = certificate("gateway", &gateway_key, Some((&ca, &ca_key)));
+        let client_key = key();
+        let client_certificate = certificate("client", &client_key, Some((&ca, &ca_key)));
+
+        let ca_file = write_pem("ca", &ca.to_pem().unwrap());
+        let cert_file = write_pem("client-cert", &client_certificate.to_pem().unwrap());
+        let key_file = write_pem("client-key", &client_key.private_key_to_pem_pkcs8().unwrap());
+
+        // The gateway asks for a client certificate from the same CA
+        let gateway_context = || {
+            let mut context = SslContext::builder(SslMethod::dtls_server()).unwrap();
+            context.set_options(SslOptions::NO_QUERY_MTU);
+            context.set_certificate(&gateway_certificate).unwrap();
+            context.set_private_key(&gateway_key).unwrap();
+            context.set_ca_file(&ca_file).unwrap();
+            context.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+            context.build()
+        };
+
+        let security = DtlsSecurity {
+            ca_file: ca_file.to_string_lossy().into_owned(),
+            cert_file: cert_file.to_string_lossy().into_owned(),
+            key_file: key_file.to_string_lossy().into_owned(),
+            server_name: String::from("127.0.0.1"),
+            ..DtlsSecurity::default()
+        };
+        assert_eq!(echo(&security, spawn_echo_gateway(gateway_context())).unwrap(), vec![2, 0x16]);
+
+        // The certificate is not issued to that name
+        let security = DtlsSecurity {
+            server_name: String::from("gateway.invalid"),
+            ..security
+        };
+        assert!(echo(&security, spawn_echo_gateway(gateway_context())).is_err());
+
+        for file in [ca_file, cert_file, key_file] {
+            let _ = std::fs::remove_file(file);
         }
     }
 
-    fn close(&self) {
-        // Nothing to do here
+    // A TCP network connected to a gateway stand-in, returned as its side
+    // of the stream
+    fn tcp_pair() -> (TCPSensorNetwork, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let network = TCPSensorNetwork::new(&address, Duration::from_millis(500)).unwrap();
+        network.initialize().unwrap();
+        let (gateway, _) = listener.accept().unwrap();
+        gateway.set_nodelay(true).unwrap();
+        (network, gateway)
+    }
+
+    #[test]
+    fn tcp_packet_split_across_reads() {
+        let (mut network, mut gateway) = tcp_pair();
+        gateway.write_all(&[4, 0x0F]).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        gateway.write_all(&[0x00, 0x01]).unwrap();
+
+        assert_eq!(network.receive().unwrap(), vec![4, 0x0F, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn tcp_two_packets_in_one_read() {
+        let (mut network, mut gateway) = tcp_pair();
+        gateway.write_all(&[2, 0x17, 4, 0x0F, 0x00, 0x01]).unwrap();
+
+        assert_eq!(network.receive().unwrap(), vec![2, 0x17]);
+        assert_eq!(network.receive().unwrap(), vec![4, 0x0F, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn tcp_packet_too_long_for_buffer() {
+        let (mut network, mut gateway) = tcp_pair();
+        gateway.write_all(&[4, 0x0F, 0x00, 0x01, 2, 0x17]).unwrap();
+
+        let mut buffer = [0u8; 3];
+        let error = network.receive_into(&mut buffer).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        // The following packet is still read whole
+        assert_eq!(network.receive_into(&mut buffer).unwrap(), 2);
+        assert_eq!(&buffer[..2], &[2, 0x17]);
+    }
+
+    #[test]
+    fn tcp_invalid_length_closes_stream() {
+        let (mut network, mut gateway) = tcp_pair();
+        gateway.write_all(&[0, 0x17, 2, 0x17]).unwrap();
+
+        let error = network.receive().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(network.pending.is_empty());
+        // The gateway sees the connection closed
+        gateway.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        assert_eq!(gateway.read(&mut [0u8; 4]).unwrap(), 0);
+    }
+
+    #[test]
+    fn resolve_bracketed_ipv6() {
+        let address = resolve_address("[::1]", 1883, AddressFamily::Any).unwrap();
+        assert_eq!(address, "[::1]:1883".parse().unwrap());
+        let address = resolve_address("::1", 1883, AddressFamily::IPv6).unwrap();
+        assert_eq!(address, "[::1]:1883".parse().unwrap());
+    }
+
+    #[test]
+    fn resolve_address_family() {
+        let address = resolve_address("127.0.0.1", 1883, AddressFamily::IPv4).unwrap();
+        assert_eq!(address, "127.0.0.1:1883".parse().unwrap());
+
+        match resolve_address("127.0.0.1", 1883, AddressFamily::IPv6) {
+            Err(MqttSnError::InvalidArgument(reason)) => assert_eq!(reason, "No IPv6 address found for 127.0.0.1"),
+            result => panic!("Expected no IPv6 address, got {:?}", result),
+        }
+        match resolve_address("[::1]", 1883, AddressFamily::IPv4) {
+            Err(MqttSnError::InvalidArgument(reason)) => assert_eq!(reason, "No IPv4 address found for ::1"),
+            result => panic!("Expected no IPv4 address, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn udp_multicast_loopback() {
+        // The group port is the one the network listens on
+        let port = UdpSocket::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+        let group = format!("239.255.77.77:{}", port);
+        let options = MulticastOptions {
+            interface: Some(MulticastInterface::Address(Ipv4Addr::LOCALHOST)),
+            ..MulticastOptions::default()
+        };
+        let mut network =
+            UDPSensorNetwork::new_multicast(&format!("0.0.0.0:{}", port), &group, Duration::from_secs(5), options).unwrap();
+        network.initialize().unwrap();
+
+        // Another node on this host sends to the group
+        let node = UdpSocket::bind("127.0.0.1:0").unwrap();
+        SockRef::from(&node).set_multicast_if_v4(&Ipv4Addr::LOCALHOST).unwrap();
+        node.send_to(&[2, 0x16], &group).unwrap();
+
+        let mut buffer = [0u8; 16];
+        let (size, sender) = network.receive_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], &[2, 0x16]);
+        assert_eq!(sender, Some(PeerAddress::Socket(node.local_addr().unwrap())));
+        assert_eq!(network.get_last_sender(), sender);
+        network.close().unwrap();
+    }
+
+    #[test]
+    fn udp_broadcast_receives_from_any_peer() {
+        let gateway = UdpSocket::bind("127.0.0.1:0").unwrap();
+        gateway.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut network =
+            UDPSensorNetwork::new_broadcast("127.0.0.1:0", &gateway.local_addr().unwrap().to_string(), Duration::from_secs(5)).unwrap();
+        network.initialize().unwrap();
+
+        let searchgw = [3, 0x01, 0];
+        network.send(&searchgw).unwrap();
+        let mut buffer = [0u8; 16];
+        let (size, client) = gateway.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], &searchgw);
+
+        // The socket is not connected, so a peer other than the
+        // destination is heard too
+        let other_gateway = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let gwinfo = [3, 0x02, 7];
+        other_gateway.send_to(&gwinfo, client).unwrap();
+        let (size, sender) = network.receive_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], &gwinfo);
+        assert_eq!(sender, Some(PeerAddress::Socket(other_gateway.local_addr().unwrap())));
     }
 }
@@ -1,6 +1,25 @@
 #![allow(unused_variables)]
 #![allow(dead_code)]
-use std::fmt::Debug;
+use core::fmt::Debug;
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use crate::mqttsn::buf::{
+    BufMut,
+    ClientIdBuf,
+    FieldBuf,
+    GwAddressBuf,
+    InnerPacketBuf,
+    LengthCounter,
+    PayloadBuf,
+    TopicBuf,
+    WirelessNodeIdBuf,
+};
+use crate::mqttsn::error::{DecodeError, DecodeErrorKind, EncodeError};
+use crate::mqttsn::flags::{Flags, TopicIdType};
+#[cfg(feature = "std")]
+use crate::mqttsn::error::MqttSnError;
 
 // This module contains structs and enums for the different MQTT-SN packet types.
 // Import constants
@@ -44,538 +63,1381 @@ pub fn mqtt_sn_packet_type_to_str(packet_type: u8) -> &'static str {
 
 
 
+// Length field helpers
+// Packets longer than MQTT_SN_MAX_PACKET_LENGTH use a three byte length
+// field: 0x01 followed by the total length of the packet as a u16.
+
+// Returns the total length of a packet whose length with a single byte
+// length field would be `length`
+pub fn mqtt_sn_packet_length(length: usize) -> u16 {
+    if length > MQTT_SN_MAX_PACKET_LENGTH {
+        (length + 2) as u16
+    } else {
+        length as u16
+    }
+}
+
+pub fn mqtt_sn_put_length(buf: &mut impl BufMut, length: u16) {
+    if length as usize > MQTT_SN_MAX_PACKET_LENGTH {
+        buf.put_u8(MQTT_SN_LONG_LENGTH_PREFIX);
+        buf.put_u16(length);
+    } else {
+        buf.put_u8(length as u8);
+    }
+}
+
+// Returns the length of the packet at the start of `bytes` and the size of
+// its length field, or None if the length field is incomplete
+pub fn mqtt_sn_decode_length(bytes: &[u8]) -> Option<(u16, usize)> {
+    match bytes.first() {
+        Some(&MQTT_SN_LONG_LENGTH_PREFIX) if bytes.len() >= 3 => {
+            Some((u16::from_be_bytes([bytes[1], bytes[2]]), 3))
+        }
+        Some(&MQTT_SN_LONG_LENGTH_PREFIX) | None => None,
+        Some(&length) => Some((length as u16, 1)),
+    }
+}
+
+// Checks the header of a packet of type msg_type and returns its length
+// and the packet cut at that length, without the two extra bytes of a
+// long length field. That way the fields are found at the same index
+// with both length forms. The declared length must fit in the buffer
+// and hold the min_length bytes of the fixed part of the message type.
+pub fn mqtt_sn_decode_header(bytes: &[u8], msg_type: u8, min_length: usize) -> Result<(u16, &[u8]), DecodeError> {
+    let (length, header_length) = mqtt_sn_decode_length(bytes)
+        .ok_or(DecodeError::new(msg_type, 0, DecodeErrorKind::InvalidLength))?;
+    let declared = length as usize;
+    if declared > bytes.len() {
+        return Err(DecodeError::new(msg_type, bytes.len(), DecodeErrorKind::Truncated {
+            declared,
+            available: bytes.len(),
+        }));
+    }
+    if declared > header_length && bytes[header_length] != msg_type {
+        return Err(DecodeError::new(msg_type, header_length, DecodeErrorKind::UnexpectedType(bytes[header_length])));
+    }
+    let required = min_length + header_length - 1;
+    if declared < required {
+        return Err(DecodeError::new(msg_type, declared, DecodeErrorKind::TooShort {
+            length: declared,
+            min_length: required,
+        }));
+    }
+    Ok((length, &bytes[header_length - 1..declared]))
+}
+
+// Copies the field starting at `start` in a packet cut by
+// mqtt_sn_decode_header, up to the end of the packet
+fn mqtt_sn_read_field<F: FieldBuf>(length: u16, bytes: &[u8], start: usize) -> Result<F, DecodeError> {
+    // Count the offset from the start of the received packet, including
+    // the extra bytes of a long length field
+    let offset = length as usize - bytes.len() + start;
+    mqtt_sn_read_field_at(bytes[1], &bytes[start..], offset)
+}
+
+fn mqtt_sn_read_field_at<F: FieldBuf>(msg_type: u8, field: &[u8], offset: usize) -> Result<F, DecodeError> {
+    F::from_field(field).ok_or(DecodeError::new(msg_type, offset, DecodeErrorKind::FieldTooLong {
+        length: field.len(),
+        capacity: F::capacity(),
+    }))
+}
+
+// Reads the flags byte, at index 2 of every packet carrying one
+fn mqtt_sn_read_flags(length: u16, bytes: &[u8]) -> Result<Flags, DecodeError> {
+    let offset = length as usize - bytes.len() + 2;
+    Flags::from_bits(bytes[2]).ok_or(DecodeError::new(bytes[1], offset, DecodeErrorKind::InvalidFlags(bytes[2])))
+}
+
 // Define a generic Packet trait
 pub trait Packet: Debug {
-    fn length(&self) -> u8 { 0 }
+    fn length(&self) -> u16 { 0 }
     fn msg_type(&self) -> u8 { 0 }
-    fn as_bytes(&self) -> Vec<u8> { Vec::new() }
-    fn from_bytes(bytes: &Vec<u8>) -> Self where Self: Sized{
-        unimplemented!()
+    fn encode(&self, buf: &mut impl BufMut);
+    // Number of bytes encode writes, which follows the length field and
+    // the contents rather than length()
+    fn encoded_length(&self) -> usize {
+        let mut counter = LengthCounter::default();
+        self.encode(&mut counter);
+        counter.written
+    }
+    // Encodes into the start of buf without allocating, returns the
+    // number of bytes written
+    fn encode_to_slice(&self, buf: &mut [u8]) -> Result<usize, EncodeError> {
+        mqtt_sn_encode_to_slice(buf, self.msg_type(), self.encoded_length(), |slice| self.encode(slice))
+    }
+    #[cfg(feature = "alloc")]
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.encoded_length());
+        self.encode(&mut bytes);
+        bytes
+    }
+    #[cfg(feature = "std")]
+    fn from_bytes(bytes: &Vec<u8>) -> Result<Self, MqttSnError> where Self: Sized + for<'a> TryFrom<&'a [u8], Error = DecodeError> {
+        Ok(Self::try_from(bytes.as_slice())?)
     }
-    fn as_connect(&self) -> Option<&ConnectPacket> { None }
-    fn as_register(&self) -> Option<&RegisterPacket> { None }
-    fn as_publish(&self) -> Option<&PublishPacket> { None }
-    fn as_subscribe(&self) -> Option<&SubscribePacket> { None }
-    fn as_regack(&self) -> Option<&RegackPacket> { None }
-    fn as_suback(&self) -> Option<&SubackPacket> { None }
-    fn as_puback(&self) -> Option<&PubackPacket> { None }
-    fn as_connack(&self) -> Option<&ConnackPacket> { None }
-    fn as_disconnect(&self) -> Option<&DisconnectPacket> { None }
-    fn as_frwdencap(&self) -> Option<&FWDEncapPacket> { None }
 }
 
 
 
-// Connect
+// Advertise
 #[derive(Debug)]
 #[derive(Clone)]
-pub struct ConnectPacket {
-    pub length: u8,
+pub struct AdvertisePacket {
+    pub length: u16,
     pub msg_type: u8,
-    pub flags: u8,
-    pub protocol_id: u8,
+    pub gw_id: u8,
     pub duration: u16,
-    pub client_id: Vec<u8>,
 }
 
-impl Packet for ConnectPacket {
-    fn length(&self) -> u8 {
-        0x06 + self.client_id.len() as u8
-    }
+impl TryFrom<&[u8]> for AdvertisePacket {
+    type Error = DecodeError;
 
-    fn msg_type(&self) -> u8 {
-        MQTT_SN_CONNECT
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_ADVERTISE, 5)?;
+        Ok(AdvertisePacket {
+            length,
+            msg_type: bytes[1],
+            gw_id: bytes[2],
+            duration: u16::from_be_bytes([bytes[3], bytes[4]]),
+        })
     }
+}
 
-    fn from_bytes(bytes: &Vec<u8>) -> Self where Self: Sized {
-        ConnectPacket {
-            length: bytes[0],
-            msg_type: bytes[1],
-            flags: bytes[2],
-            protocol_id: bytes[3],
-            duration: u16::from_be_bytes([bytes[4], bytes[5]]),
-            client_id: bytes[6..].to_vec(),
-        }
+impl Packet for AdvertisePacket {
+    fn length(&self) -> u16 {
+        5
     }
 
-    fn as_bytes(&self) -> Vec<u8> {
-        // Create an array of bytes and push the struct fields into it
-        let mut bytes = Vec::new(); 
-        bytes.push(self.length);
-        bytes.push(self.msg_type);
-        bytes.push(self.flags);
-        bytes.push(self.protocol_id);
-        bytes.push((self.duration >> 8) as u8);
-        bytes.push(self.duration as u8);
-        bytes.extend_from_slice(self.client_id.as_slice());
-        bytes
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_ADVERTISE
     }
 
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_u8(self.gw_id);
+        buf.put_u8((self.duration >> 8) as u8);
+        buf.put_u8(self.duration as u8);
+    }
 }
 
-// Connack
+// Searchgw
 #[derive(Debug)]
 #[derive(Clone)]
-pub struct ConnackPacket {
-    pub length: u8,
+pub struct SearchgwPacket {
+    pub length: u16,
     pub msg_type: u8,
-    pub return_code: u8,
+    pub radius: u8,
 }
 
-impl Packet for ConnackPacket {
-    fn length(&self) -> u8 {
-        3
-    }
+impl TryFrom<&[u8]> for SearchgwPacket {
+    type Error = DecodeError;
 
-    fn msg_type(&self) -> u8 {
-        MQTT_SN_CONNACK
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_SEARCHGW, 3)?;
+        Ok(SearchgwPacket {
+            length,
+            msg_type: bytes[1],
+            radius: bytes[2],
+        })
     }
+}
 
-    fn from_bytes(bytes: &Vec<u8>) -> Self where Self: Sized {
-        ConnackPacket {
-            length: bytes[0],
-            msg_type: bytes[1],
-            return_code: bytes[2],
-        }
+impl Packet for SearchgwPacket {
+    fn length(&self) -> u16 {
+        3
     }
 
-    fn as_bytes(&self) -> Vec<u8> {
-        // Create an array of bytes and push the struct fields into it
-        let mut bytes = Vec::new(); 
-        bytes.push(self.length);
-        bytes.push(self.msg_type);
-        bytes.push(self.return_code);
-        bytes
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_SEARCHGW
     }
 
-    fn as_connack(&self) -> Option<&ConnackPacket> {
-        Some(self)
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_u8(self.radius);
     }
 }
 
-// Register
+// Gwinfo
+// The gateway address is only present when the GWINFO is sent by a client
 #[derive(Debug)]
 #[derive(Clone)]
-pub struct RegisterPacket {
-    pub length: u8,
+pub struct GwinfoPacket {
+    pub length: u16,
     pub msg_type: u8,
-    pub topic_id: u16,
-    pub message_id: u16,
-    pub topic_name: Vec<u8>,
+    pub gw_id: u8,
+    pub gw_add: GwAddressBuf,
 }
 
-impl Packet for RegisterPacket {
-    fn length(&self) -> u8 {
-        0x06 + self.topic_name.len() as u8
-    }
+impl TryFrom<&[u8]> for GwinfoPacket {
+    type Error = DecodeError;
 
-    fn msg_type(&self) -> u8 {
-        MQTT_SN_REGISTER
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_GWINFO, 3)?;
+        Ok(GwinfoPacket {
+            length,
+            msg_type: bytes[1],
+            gw_id: bytes[2],
+            gw_add: mqtt_sn_read_field(length, bytes, 3)?,
+        })
     }
+}
 
-    fn from_bytes(bytes: &Vec<u8>) -> Self where Self: Sized {
-        RegisterPacket {
-            length: bytes[0],
-            msg_type: bytes[1],
-            topic_id: u16::from_be_bytes([bytes[2], bytes[3]]),
-            message_id: u16::from_be_bytes([bytes[4], bytes[5]]),
-            topic_name: bytes[6..].to_vec(),
-        }
+impl Packet for GwinfoPacket {
+    fn length(&self) -> u16 {
+        mqtt_sn_packet_length(0x03 + self.gw_add.len())
     }
 
-    fn as_bytes(&self) -> Vec<u8> {
-        // Create an array of bytes and push the struct fields into it
-        let mut bytes = Vec::new(); 
-        bytes.push(self.length);
-        bytes.push(self.msg_type);
-        bytes.push((self.topic_id >> 8) as u8);
-        bytes.push(self.topic_id as u8);
-        bytes.push((self.message_id >> 8) as u8);
-        bytes.push(self.message_id as u8);
-        bytes.extend_from_slice(self.topic_name.as_slice());
-        bytes
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_GWINFO
+    }
 
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_u8(self.gw_id);
+        buf.put_slice(self.gw_add.as_slice());
     }
 }
 
-// Regack
+// Connect
 #[derive(Debug)]
 #[derive(Clone)]
-pub struct RegackPacket {
-    pub length: u8,
+pub struct ConnectPacket {
+    pub length: u16,
     pub msg_type: u8,
-    pub topic_id: u16,
-    pub message_id: u16,
-    pub return_code: u8,
+    pub flags: Flags,
+    pub protocol_id: u8,
+    pub duration: u16,
+    pub client_id: ClientIdBuf,
 }
 
-impl Packet for RegackPacket {
-    fn length(&self) -> u8 {
-        7
-    }
+impl TryFrom<&[u8]> for ConnectPacket {
+    type Error = DecodeError;
 
-    fn msg_type(&self) -> u8 {
-        MQTT_SN_REGACK
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_CONNECT, 6)?;
+        Ok(ConnectPacket {
+            length,
+            msg_type: bytes[1],
+            flags: mqtt_sn_read_flags(length, bytes)?,
+            protocol_id: bytes[3],
+            duration: u16::from_be_bytes([bytes[4], bytes[5]]),
+            client_id: mqtt_sn_read_field(length, bytes, 6)?,
+        })
     }
+}
 
-    fn from_bytes(bytes: &Vec<u8>) -> RegackPacket {
-        RegackPacket {
-            length: bytes[0],
-            msg_type: bytes[1],
-            topic_id: u16::from_be_bytes([bytes[2], bytes[3]]),
-            message_id: u16::from_be_bytes([bytes[4], bytes[5]]),
-            return_code: bytes[6],
-        }
+impl Packet for ConnectPacket {
+    fn length(&self) -> u16 {
+        mqtt_sn_packet_length(0x06 + self.client_id.len())
     }
 
-    fn as_bytes(&self) -> Vec<u8> {
-        // Create an array of bytes and push the struct fields into it
-        let mut bytes = Vec::new(); 
-        bytes.push(self.length);
-        bytes.push(self.msg_type);
-        bytes.push((self.topic_id >> 8) as u8);
-        bytes.push(self.topic_id as u8);
-        bytes.push((self.message_id >> 8) as u8);
-        bytes.push(self.message_id as u8);
-        bytes.push(self.return_code);
-        bytes
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_CONNECT
     }
 
-    fn as_regack(&self) -> Option<&RegackPacket> {
-        Some(self)
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_u8(self.flags.bits());
+        buf.put_u8(self.protocol_id);
+        buf.put_u8((self.duration >> 8) as u8);
+        buf.put_u8(self.duration as u8);
+        buf.put_slice(self.client_id.as_slice());
     }
+
 }
 
-// Publish
+// Connack
 #[derive(Debug)]
 #[derive(Clone)]
-pub struct PublishPacket {
-    pub length: u8,
+pub struct ConnackPacket {
+    pub length: u16,
     pub msg_type: u8,
-    pub flags: u8,
-    pub topic_id: u16,
-    pub message_id: u16,
-    pub data: Vec<u8>,
+    pub return_code: u8,
 }
 
-impl Packet for PublishPacket {
-    fn length(&self) -> u8 {
-        0x07 + self.data.len() as u8
-    }
+impl TryFrom<&[u8]> for ConnackPacket {
+    type Error = DecodeError;
 
-    fn msg_type(&self) -> u8 {
-        MQTT_SN_PUBLISH
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_CONNACK, 3)?;
+        Ok(ConnackPacket {
+            length,
+            msg_type: bytes[1],
+            return_code: bytes[2],
+        })
     }
+}
 
-    fn from_bytes(bytes: &Vec<u8>) -> Self where Self: Sized {
-        let length = bytes[0] as usize;
-        PublishPacket {
-            length: bytes[0],
-            msg_type: bytes[1],
-            flags: bytes[2],
-            topic_id: u16::from_be_bytes([bytes[3], bytes[4]]),
-            message_id: u16::from_be_bytes([bytes[5], bytes[6]]),
-            data: bytes[7..length].to_vec(),
-        }
+impl Packet for ConnackPacket {
+    fn length(&self) -> u16 {
+        3
     }
 
-    fn as_bytes(&self) -> Vec<u8> {
-        // Create an array of bytes and push the struct fields into it
-        let mut bytes = Vec::new(); 
-        bytes.push(self.length);
-        bytes.push(self.msg_type);
-        bytes.push(self.flags);
-        bytes.push((self.topic_id >> 8) as u8);
-        bytes.push(self.topic_id as u8);
-        bytes.push((self.message_id >> 8) as u8);
-        bytes.push(self.message_id as u8);
-        bytes.extend_from_slice(&self.data);
-        bytes
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_CONNACK
     }
 
-    fn as_publish(&self) -> Option<&PublishPacket> {
-        Some(self)
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_u8(self.return_code);
     }
 }
 
-// Puback
+// Willtopicreq
 #[derive(Debug)]
 #[derive(Clone)]
-pub struct PubackPacket {
-    pub length: u8,
+pub struct WilltopicreqPacket {
+    pub length: u16,
     pub msg_type: u8,
-    pub topic_id: u16,
-    pub message_id: u16,
-    pub return_code: u8,
 }
 
-impl Packet for PubackPacket {
-    fn length(&self) -> u8 {
-        7
-    }
+impl TryFrom<&[u8]> for WilltopicreqPacket {
+    type Error = DecodeError;
 
-    fn msg_type(&self) -> u8 {
-        MQTT_SN_PUBACK
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_WILLTOPICREQ, 2)?;
+        Ok(WilltopicreqPacket {
+            length,
+            msg_type: bytes[1],
+        })
     }
+}
 
-    fn from_bytes(bytes: &Vec<u8>) -> PubackPacket {
-        PubackPacket {
-            length: bytes[0],
-            msg_type: bytes[1],
-            topic_id: u16::from_be_bytes([bytes[2], bytes[3]]),
-            message_id: u16::from_be_bytes([bytes[4], bytes[5]]),
-            return_code: bytes[6],
-        }
+impl Packet for WilltopicreqPacket {
+    fn length(&self) -> u16 {
+        0x02
     }
 
-    fn as_bytes(&self) -> Vec<u8> {
-        // Create an array of bytes and push the struct fields into it
-        let mut bytes = Vec::new(); 
-        bytes.push(self.length);
-        bytes.push(self.msg_type);
-        bytes.push((self.topic_id >> 8) as u8);
-        bytes.push(self.topic_id as u8);
-        bytes.push((self.message_id >> 8) as u8);
-        bytes.push(self.message_id as u8);
-        bytes.push(self.return_code);
-        bytes
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_WILLTOPICREQ
     }
 
-    fn as_puback(&self) -> Option<&PubackPacket> {
-        Some(self)
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
     }
 }
 
+// Willtopic
 #[derive(Debug)]
-pub struct PingreqPacket {
-    pub length: u8,
+#[derive(Clone)]
+pub struct WilltopicPacket {
+    pub length: u16,
     pub msg_type: u8,
+    pub flags: Flags,
+    pub will_topic: TopicBuf,
 }
 
-impl Packet for PingreqPacket {
-    fn length(&self) -> u8 {
-        0x02
+impl TryFrom<&[u8]> for WilltopicPacket {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_WILLTOPIC, 3)?;
+        Ok(WilltopicPacket {
+            length,
+            msg_type: bytes[1],
+            flags: mqtt_sn_read_flags(length, bytes)?,
+            will_topic: mqtt_sn_read_field(length, bytes, 3)?,
+        })
     }
+}
 
-    fn msg_type(&self) -> u8 {
-        MQTT_SN_PINGREQ
+impl Packet for WilltopicPacket {
+    fn length(&self) -> u16 {
+        mqtt_sn_packet_length(0x03 + self.will_topic.len())
     }
 
-    fn from_bytes(bytes: &Vec<u8>) -> Self where Self: Sized {
-        PingreqPacket {
-            length: bytes[0],
-            msg_type: bytes[1],
-        }
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_WILLTOPIC
     }
 
-    fn as_bytes(&self) -> Vec<u8> {
-        // Create an array of bytes and push the struct fields into it
-        let mut bytes = Vec::new(); 
-        bytes.push(self.length);
-        bytes.push(self.msg_type);
-        bytes
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_u8(self.flags.bits());
+        buf.put_slice(self.will_topic.as_slice());
     }
 }
 
-
+// Willmsgreq
 #[derive(Debug)]
-pub struct PingrespPacket {
-    pub length: u8,
+#[derive(Clone)]
+pub struct WillmsgreqPacket {
+    pub length: u16,
     pub msg_type: u8,
 }
 
-impl Packet for PingrespPacket {
-    fn length(&self) -> u8 {
-        0x02
-    }
-
-    fn msg_type(&self) -> u8 {
-        MQTT_SN_PINGRESP
-    }
+impl TryFrom<&[u8]> for WillmsgreqPacket {
+    type Error = DecodeError;
 
-    fn from_bytes(bytes: &Vec<u8>) -> Self where Self: Sized {
-        PingrespPacket {
-            length: bytes[0],
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_WILLMSGREQ, 2)?;
+        Ok(WillmsgreqPacket {
+            length,
             msg_type: bytes[1],
-        }
+        })
     }
+}
 
-    fn as_bytes(&self) -> Vec<u8> {
-        // Create an array of bytes and push the struct fields into it
-        let mut bytes = Vec::new(); 
-        bytes.push(self.length);
-        bytes.push(self.msg_type);
-        bytes
+impl Packet for WillmsgreqPacket {
+    fn length(&self) -> u16 {
+        0x02
     }
-}
 
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_WILLMSGREQ
+    }
 
-// Topics enum
-// This could be an array of bytes or a topic id
-#[derive(Debug, Clone)]
-pub enum Topic {
-    TopicId(u16),
-    TopicName(Vec<u8>),
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+    }
 }
 
-// Subscribe
+// Willmsg
 #[derive(Debug)]
 #[derive(Clone)]
-pub struct SubscribePacket {
-    pub length: u8,
+pub struct WillmsgPacket {
+    pub length: u16,
     pub msg_type: u8,
-    pub flags: u8,
-    pub message_id: u16,
-    pub topic: Topic,
+    pub will_msg: PayloadBuf,
 }
 
-impl Packet for SubscribePacket {
-    fn length(&self) -> u8 {
-        0x05 + match self.topic {
-            Topic::TopicId(_) => 2,
-            Topic::TopicName(ref name) => name.len() as u8,
-        }
-    }
+impl TryFrom<&[u8]> for WillmsgPacket {
+    type Error = DecodeError;
 
-    fn msg_type(&self) -> u8 {
-        MQTT_SN_SUBSCRIBE
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_WILLMSG, 2)?;
+        Ok(WillmsgPacket {
+            length,
+            msg_type: bytes[1],
+            will_msg: mqtt_sn_read_field(length, bytes, 2)?,
+        })
     }
+}
 
-    fn from_bytes(bytes: &Vec<u8>) -> Self where Self: Sized {
-        let topic = if (bytes[2] & MQTT_SN_FLAG_QOS_MASK)  == MQTT_SN_TOPIC_TYPE_PREDEFINED {
-            Topic::TopicId(u16::from_be_bytes([bytes[5], bytes[6]]))
-        } else {
-            Topic::TopicName(bytes[5..].to_vec())
-        };
+impl Packet for WillmsgPacket {
+    fn length(&self) -> u16 {
+        mqtt_sn_packet_length(0x02 + self.will_msg.len())
+    }
 
-        SubscribePacket {
-            length: bytes[0],
-            msg_type: bytes[1],
-            flags: bytes[2],
-            message_id: u16::from_be_bytes([bytes[5], bytes[6]]),
-            topic: topic,
-        }
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_WILLMSG
     }
 
-    fn as_bytes(&self) -> Vec<u8> {
-        // Create an array of bytes and push the struct fields into it
-        let mut bytes = Vec::new(); 
-        bytes.push(self.length);
-        bytes.push(self.msg_type);
-        bytes.push(self.flags);
-        bytes.push((self.message_id >> 8) as u8);
-        bytes.push(self.message_id as u8);
-        match self.topic {
-            Topic::TopicId(id) => {
-                bytes.push((id >> 8) as u8);
-                bytes.push(id as u8);
-            },
-            Topic::TopicName(ref name) => {
-                bytes.extend_from_slice(name.as_slice());
-            },
-        }
-        bytes
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_slice(self.will_msg.as_slice());
     }
 }
 
-// Suback
+// Register
 #[derive(Debug)]
 #[derive(Clone)]
-pub struct SubackPacket {
-    pub length: u8,
+pub struct RegisterPacket {
+    pub length: u16,
     pub msg_type: u8,
-    pub flags: u8,
     pub topic_id: u16,
     pub message_id: u16,
-    pub return_code: u8,
+    pub topic_name: TopicBuf,
 }
 
-impl Packet for SubackPacket {
-    fn length(&self) -> u8 {
-        8
-    }
+impl TryFrom<&[u8]> for RegisterPacket {
+    type Error = DecodeError;
 
-    fn msg_type(&self) -> u8 {
-        MQTT_SN_SUBACK
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_REGISTER, 6)?;
+        Ok(RegisterPacket {
+            length,
+            msg_type: bytes[1],
+            topic_id: u16::from_be_bytes([bytes[2], bytes[3]]),
+            message_id: u16::from_be_bytes([bytes[4], bytes[5]]),
+            topic_name: mqtt_sn_read_field(length, bytes, 6)?,
+        })
     }
+}
 
-    fn from_bytes(bytes: &Vec<u8>) -> Self where Self: Sized {
-        SubackPacket {
-            length: bytes[0],
-            msg_type: bytes[1],
-            flags: bytes[2],
-            topic_id: u16::from_be_bytes([bytes[3], bytes[4]]),
-            message_id: u16::from_be_bytes([bytes[5], bytes[6]]),
-            return_code: bytes[7],
-        }
+impl Packet for RegisterPacket {
+    fn length(&self) -> u16 {
+        mqtt_sn_packet_length(0x06 + self.topic_name.len())
     }
 
-    fn as_bytes(&self) -> Vec<u8> {
-        // Create an array of bytes and push the struct fields into it
-        let mut bytes = Vec::new(); 
-        bytes.push(self.length);
-        bytes.push(self.msg_type);
-        bytes.push(self.flags);
-        bytes.push((self.topic_id >> 8) as u8);
-        bytes.push(self.topic_id as u8);
-        bytes.push((self.message_id >> 8) as u8);
-        bytes.push(self.message_id as u8);
-        bytes.push(self.return_code);
-        bytes
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_REGISTER
     }
 
-    fn as_suback(&self) -> Option<&SubackPacket> {
-        Some(self)
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_u8((self.topic_id >> 8) as u8);
+        buf.put_u8(self.topic_id as u8);
+        buf.put_u8((self.message_id >> 8) as u8);
+        buf.put_u8(self.message_id as u8);
+        buf.put_slice(self.topic_name.as_slice());
     }
 }
 
-// Disconnect
+// Regack
 #[derive(Debug)]
 #[derive(Clone)]
-pub struct DisconnectPacket {
-    pub length: u8,
+pub struct RegackPacket {
+    pub length: u16,
     pub msg_type: u8,
-    pub duration: u16,
+    pub topic_id: u16,
+    pub message_id: u16,
+    pub return_code: u8,
 }
 
-impl Packet for DisconnectPacket {
-    fn length(&self) -> u8 {
-        if self.duration == 0 {
-            0x02
-        } else {
-            0x04
-        }
+impl TryFrom<&[u8]> for RegackPacket {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_REGACK, 7)?;
+        Ok(RegackPacket {
+            length,
+            msg_type: bytes[1],
+            topic_id: u16::from_be_bytes([bytes[2], bytes[3]]),
+            message_id: u16::from_be_bytes([bytes[4], bytes[5]]),
+            return_code: bytes[6],
+        })
+    }
+}
+
+impl Packet for RegackPacket {
+    fn length(&self) -> u16 {
+        7
+    }
+
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_REGACK
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_u8((self.topic_id >> 8) as u8);
+        buf.put_u8(self.topic_id as u8);
+        buf.put_u8((self.message_id >> 8) as u8);
+        buf.put_u8(self.message_id as u8);
+        buf.put_u8(self.return_code);
+    }
+}
+
+// Publish
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct PublishPacket {
+    pub length: u16,
+    pub msg_type: u8,
+    pub flags: Flags,
+    pub topic_id: u16,
+    pub message_id: u16,
+    pub data: PayloadBuf,
+}
+
+impl TryFrom<&[u8]> for PublishPacket {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_PUBLISH, 7)?;
+        Ok(PublishPacket {
+            length,
+            msg_type: bytes[1],
+            flags: mqtt_sn_read_flags(length, bytes)?,
+            topic_id: u16::from_be_bytes([bytes[3], bytes[4]]),
+            message_id: u16::from_be_bytes([bytes[5], bytes[6]]),
+            data: mqtt_sn_read_field(length, bytes, 7)?,
+        })
+    }
+}
+
+impl Packet for PublishPacket {
+    fn length(&self) -> u16 {
+        mqtt_sn_packet_length(0x07 + self.data.len())
+    }
+
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_PUBLISH
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_u8(self.flags.bits());
+        buf.put_u8((self.topic_id >> 8) as u8);
+        buf.put_u8(self.topic_id as u8);
+        buf.put_u8((self.message_id >> 8) as u8);
+        buf.put_u8(self.message_id as u8);
+        buf.put_slice(&self.data);
+    }
+}
+
+// Puback
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct PubackPacket {
+    pub length: u16,
+    pub msg_type: u8,
+    pub topic_id: u16,
+    pub message_id: u16,
+    pub return_code: u8,
+}
+
+impl TryFrom<&[u8]> for PubackPacket {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_PUBACK, 7)?;
+        Ok(PubackPacket {
+            length,
+            msg_type: bytes[1],
+            topic_id: u16::from_be_bytes([bytes[2], bytes[3]]),
+            message_id: u16::from_be_bytes([bytes[4], bytes[5]]),
+            return_code: bytes[6],
+        })
+    }
+}
+
+impl Packet for PubackPacket {
+    fn length(&self) -> u16 {
+        7
+    }
+
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_PUBACK
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_u8((self.topic_id >> 8) as u8);
+        buf.put_u8(self.topic_id as u8);
+        buf.put_u8((self.message_id >> 8) as u8);
+        buf.put_u8(self.message_id as u8);
+        buf.put_u8(self.return_code);
+    }
+}
+
+// Pubrec
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct PubrecPacket {
+    pub length: u16,
+    pub msg_type: u8,
+    pub message_id: u16,
+}
+
+impl TryFrom<&[u8]> for PubrecPacket {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_PUBREC, 4)?;
+        Ok(PubrecPacket {
+            length,
+            msg_type: bytes[1],
+            message_id: u16::from_be_bytes([bytes[2], bytes[3]]),
+        })
+    }
+}
+
+impl Packet for PubrecPacket {
+    fn length(&self) -> u16 {
+        4
+    }
+
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_PUBREC
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_u8((self.message_id >> 8) as u8);
+        buf.put_u8(self.message_id as u8);
+    }
+}
+
+// Pubrel
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct PubrelPacket {
+    pub length: u16,
+    pub msg_type: u8,
+    pub message_id: u16,
+}
+
+impl TryFrom<&[u8]> for PubrelPacket {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_PUBREL, 4)?;
+        Ok(PubrelPacket {
+            length,
+            msg_type: bytes[1],
+            message_id: u16::from_be_bytes([bytes[2], bytes[3]]),
+        })
+    }
+}
+
+impl Packet for PubrelPacket {
+    fn length(&self) -> u16 {
+        4
+    }
+
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_PUBREL
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_u8((self.message_id >> 8) as u8);
+        buf.put_u8(self.message_id as u8);
+    }
+}
+
+// Pubcomp
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct PubcompPacket {
+    pub length: u16,
+    pub msg_type: u8,
+    pub message_id: u16,
+}
+
+impl TryFrom<&[u8]> for PubcompPacket {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_PUBCOMP, 4)?;
+        Ok(PubcompPacket {
+            length,
+            msg_type: bytes[1],
+            message_id: u16::from_be_bytes([bytes[2], bytes[3]]),
+        })
+    }
+}
+
+impl Packet for PubcompPacket {
+    fn length(&self) -> u16 {
+        4
+    }
+
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_PUBCOMP
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_u8((self.message_id >> 8) as u8);
+        buf.put_u8(self.message_id as u8);
+    }
+}
+
+// Pingreq
+// The client ID is only sent by sleeping clients, when waking up
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct PingreqPacket {
+    pub length: u16,
+    pub msg_type: u8,
+    pub client_id: ClientIdBuf,
+}
+
+impl TryFrom<&[u8]> for PingreqPacket {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_PINGREQ, 2)?;
+        Ok(PingreqPacket {
+            length,
+            msg_type: bytes[1],
+            client_id: mqtt_sn_read_field(length, bytes, 2)?,
+        })
+    }
+}
+
+impl Packet for PingreqPacket {
+    fn length(&self) -> u16 {
+        mqtt_sn_packet_length(0x02 + self.client_id.len())
+    }
+
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_PINGREQ
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_slice(self.client_id.as_slice());
+    }
+}
+
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct PingrespPacket {
+    pub length: u16,
+    pub msg_type: u8,
+}
+
+impl TryFrom<&[u8]> for PingrespPacket {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_PINGRESP, 2)?;
+        Ok(PingrespPacket {
+            length,
+            msg_type: bytes[1],
+        })
+    }
+}
+
+impl Packet for PingrespPacket {
+    fn length(&self) -> u16 {
+        0x02
+    }
+
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_PINGRESP
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+    }
+}
+
+
+// Topics enum
+// This could be an array of bytes or a topic id
+#[derive(Debug, Clone, PartialEq, Eq)]
+// Without alloc the topic name is kept inline
+#[cfg_attr(not(feature = "alloc"), allow(clippy::large_enum_variant))]
+pub enum Topic {
+    TopicId(u16),
+    TopicName(TopicBuf),
+}
+
+// Return codes
+// Sent by the gateway in CONNACK, REGACK, PUBACK, SUBACK, WILLTOPICRESP
+// and WILLMSGRESP packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReturnCode {
+    Accepted,
+    RejectedCongestion,
+    RejectedInvalidTopicId,
+    RejectedNotSupported,
+    Reserved(u8),
+}
+
+impl From<u8> for ReturnCode {
+    fn from(return_code: u8) -> Self {
+        match return_code {
+            MQTT_SN_ACCEPTED => ReturnCode::Accepted,
+            MQTT_SN_REJECTED_CONGESTION => ReturnCode::RejectedCongestion,
+            MQTT_SN_REJECTED_INVALID_TOPIC_ID => ReturnCode::RejectedInvalidTopicId,
+            MQTT_SN_REJECTED_NOT_SUPPORTED => ReturnCode::RejectedNotSupported,
+            _ => ReturnCode::Reserved(return_code),
+        }
+    }
+}
+
+impl From<ReturnCode> for u8 {
+    fn from(return_code: ReturnCode) -> Self {
+        match return_code {
+            ReturnCode::Accepted => MQTT_SN_ACCEPTED,
+            ReturnCode::RejectedCongestion => MQTT_SN_REJECTED_CONGESTION,
+            ReturnCode::RejectedInvalidTopicId => MQTT_SN_REJECTED_INVALID_TOPIC_ID,
+            ReturnCode::RejectedNotSupported => MQTT_SN_REJECTED_NOT_SUPPORTED,
+            ReturnCode::Reserved(return_code) => return_code,
+        }
+    }
+}
+
+impl core::fmt::Display for ReturnCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            ReturnCode::Accepted => write!(f, "accepted"),
+            ReturnCode::RejectedCongestion => write!(f, "rejected: congestion"),
+            ReturnCode::RejectedInvalidTopicId => write!(f, "rejected: invalid topic ID"),
+            ReturnCode::RejectedNotSupported => write!(f, "rejected: not supported"),
+            ReturnCode::Reserved(return_code) => write!(f, "reserved return code {}", return_code),
+        }
+    }
+}
+
+// Subscribe
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct SubscribePacket {
+    pub length: u16,
+    pub msg_type: u8,
+    pub flags: Flags,
+    pub message_id: u16,
+    pub topic: Topic,
+}
+
+impl TryFrom<&[u8]> for SubscribePacket {
+    type Error = DecodeError;
+
+    fn try_from(packet: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(packet, MQTT_SN_SUBSCRIBE, 5)?;
+        let flags = mqtt_sn_read_flags(length, bytes)?;
+        let topic = if flags.topic_id_type() == TopicIdType::Predefined {
+            // A pre-defined topic ID takes two bytes
+            mqtt_sn_decode_header(packet, MQTT_SN_SUBSCRIBE, 7)?;
+            Topic::TopicId(u16::from_be_bytes([bytes[5], bytes[6]]))
+        } else {
+            Topic::TopicName(mqtt_sn_read_field(length, bytes, 5)?)
+        };
+
+        Ok(SubscribePacket {
+            length,
+            msg_type: bytes[1],
+            flags,
+            message_id: u16::from_be_bytes([bytes[3], bytes[4]]),
+            topic,
+        })
+    }
+}
+
+impl Packet for SubscribePacket {
+    fn length(&self) -> u16 {
+        mqtt_sn_packet_length(0x05 + match self.topic {
+            Topic::TopicId(_) => 2,
+            Topic::TopicName(ref name) => name.len(),
+        })
+    }
+
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_SUBSCRIBE
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_u8(self.flags.bits());
+        buf.put_u8((self.message_id >> 8) as u8);
+        buf.put_u8(self.message_id as u8);
+        match self.topic {
+            Topic::TopicId(id) => {
+                buf.put_u8((id >> 8) as u8);
+                buf.put_u8(id as u8);
+            },
+            Topic::TopicName(ref name) => {
+                buf.put_slice(name.as_slice());
+            },
+        }
+    }
+}
+
+// Suback
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct SubackPacket {
+    pub length: u16,
+    pub msg_type: u8,
+    pub flags: Flags,
+    pub topic_id: u16,
+    pub message_id: u16,
+    pub return_code: u8,
+}
+
+impl TryFrom<&[u8]> for SubackPacket {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_SUBACK, 8)?;
+        Ok(SubackPacket {
+            length,
+            msg_type: bytes[1],
+            flags: mqtt_sn_read_flags(length, bytes)?,
+            topic_id: u16::from_be_bytes([bytes[3], bytes[4]]),
+            message_id: u16::from_be_bytes([bytes[5], bytes[6]]),
+            return_code: bytes[7],
+        })
+    }
+}
+
+impl Packet for SubackPacket {
+    fn length(&self) -> u16 {
+        8
+    }
+
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_SUBACK
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_u8(self.flags.bits());
+        buf.put_u8((self.topic_id >> 8) as u8);
+        buf.put_u8(self.topic_id as u8);
+        buf.put_u8((self.message_id >> 8) as u8);
+        buf.put_u8(self.message_id as u8);
+        buf.put_u8(self.return_code);
+    }
+}
+
+// Unsubscribe
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct UnsubscribePacket {
+    pub length: u16,
+    pub msg_type: u8,
+    pub flags: Flags,
+    pub message_id: u16,
+    pub topic: Topic,
+}
+
+impl TryFrom<&[u8]> for UnsubscribePacket {
+    type Error = DecodeError;
+
+    fn try_from(packet: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(packet, MQTT_SN_UNSUBSCRIBE, 5)?;
+        let flags = mqtt_sn_read_flags(length, bytes)?;
+        let topic = if flags.topic_id_type() == TopicIdType::Predefined {
+            // A pre-defined topic ID takes two bytes
+            mqtt_sn_decode_header(packet, MQTT_SN_UNSUBSCRIBE, 7)?;
+            Topic::TopicId(u16::from_be_bytes([bytes[5], bytes[6]]))
+        } else {
+            Topic::TopicName(mqtt_sn_read_field(length, bytes, 5)?)
+        };
+
+        Ok(UnsubscribePacket {
+            length,
+            msg_type: bytes[1],
+            flags,
+            message_id: u16::from_be_bytes([bytes[3], bytes[4]]),
+            topic,
+        })
+    }
+}
+
+impl Packet for UnsubscribePacket {
+    fn length(&self) -> u16 {
+        mqtt_sn_packet_length(0x05 + match self.topic {
+            Topic::TopicId(_) => 2,
+            Topic::TopicName(ref name) => name.len(),
+        })
+    }
+
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_UNSUBSCRIBE
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_u8(self.flags.bits());
+        buf.put_u8((self.message_id >> 8) as u8);
+        buf.put_u8(self.message_id as u8);
+        match self.topic {
+            Topic::TopicId(id) => {
+                buf.put_u8((id >> 8) as u8);
+                buf.put_u8(id as u8);
+            },
+            Topic::TopicName(ref name) => {
+                buf.put_slice(name.as_slice());
+            },
+        }
+    }
+}
+
+// Unsuback
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct UnsubackPacket {
+    pub length: u16,
+    pub msg_type: u8,
+    pub message_id: u16,
+}
+
+impl TryFrom<&[u8]> for UnsubackPacket {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_UNSUBACK, 4)?;
+        Ok(UnsubackPacket {
+            length,
+            msg_type: bytes[1],
+            message_id: u16::from_be_bytes([bytes[2], bytes[3]]),
+        })
+    }
+}
+
+impl Packet for UnsubackPacket {
+    fn length(&self) -> u16 {
+        4
+    }
+
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_UNSUBACK
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_u8((self.message_id >> 8) as u8);
+        buf.put_u8(self.message_id as u8);
+    }
+}
+
+// Disconnect
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct DisconnectPacket {
+    pub length: u16,
+    pub msg_type: u8,
+    pub duration: u16,
+}
+
+impl TryFrom<&[u8]> for DisconnectPacket {
+    type Error = DecodeError;
+
+    fn try_from(packet: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(packet, MQTT_SN_DISCONNECT, 2)?;
+        // Only a client going to sleep sends the duration
+        let duration = if bytes.len() >= 4 {
+            u16::from_be_bytes([bytes[2], bytes[3]])
+        } else {
+            0
+        };
+
+        Ok(DisconnectPacket {
+            length,
+            msg_type: bytes[1],
+            duration,
+        })
+    }
+}
+
+impl Packet for DisconnectPacket {
+    fn length(&self) -> u16 {
+        if self.duration == 0 {
+            0x02
+        } else {
+            0x04
+        }
     }
 
     fn msg_type(&self) -> u8 {
         MQTT_SN_DISCONNECT
     }
 
-    fn from_bytes(bytes: &Vec<u8>) -> Self where Self: Sized {
-        let length = bytes[0] as usize;
-        let mut duration: u16 = 0;
-        if length == 0x04 {
-                duration = u16::from_be_bytes([bytes[2], bytes[3]]);
-        } 
-        return DisconnectPacket {
-            length: bytes[0],
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        // The duration is only sent when going to sleep
+        if self.length >= 0x04 {
+            buf.put_u8((self.duration >> 8) as u8);
+            buf.put_u8(self.duration as u8);
+        }
+    }
+}
+
+// Willtopicupd
+// An empty will topic deletes the will. In that case the packet
+// carries neither flags nor topic, and it is exactly 2 bytes long.
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct WilltopicupdPacket {
+    pub length: u16,
+    pub msg_type: u8,
+    pub flags: Flags,
+    pub will_topic: TopicBuf,
+}
+
+impl TryFrom<&[u8]> for WilltopicupdPacket {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_WILLTOPICUPD, 2)?;
+        let mut flags = Flags::new();
+        let mut will_topic = TopicBuf::new();
+        if bytes.len() > 2 {
+            flags = mqtt_sn_read_flags(length, bytes)?;
+            will_topic = mqtt_sn_read_field(length, bytes, 3)?;
+        }
+        Ok(WilltopicupdPacket {
+            length,
             msg_type: bytes[1],
-            duration: duration,
+            flags,
+            will_topic,
+        })
+    }
+}
+
+impl Packet for WilltopicupdPacket {
+    fn length(&self) -> u16 {
+        if self.will_topic.is_empty() {
+            0x02
+        } else {
+            mqtt_sn_packet_length(0x03 + self.will_topic.len())
         }
     }
 
-    fn as_bytes(&self) -> Vec<u8> {
-        // Create an array of bytes and push the struct fields into it
-        let mut bytes = Vec::new(); 
-        bytes.push(self.length);
-        bytes.push(self.msg_type);
-        bytes.push((self.duration >> 8) as u8);
-        bytes.push(self.duration as u8);
-        bytes
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_WILLTOPICUPD
     }
 
-    fn as_disconnect(&self) -> Option<&DisconnectPacket> {
-        Some(self)
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        if !self.will_topic.is_empty() {
+            buf.put_u8(self.flags.bits());
+            buf.put_slice(self.will_topic.as_slice());
+        }
+    }
+}
+
+// Willtopicresp
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct WilltopicrespPacket {
+    pub length: u16,
+    pub msg_type: u8,
+    pub return_code: u8,
+}
+
+impl TryFrom<&[u8]> for WilltopicrespPacket {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_WILLTOPICRESP, 3)?;
+        Ok(WilltopicrespPacket {
+            length,
+            msg_type: bytes[1],
+            return_code: bytes[2],
+        })
+    }
+}
+
+impl Packet for WilltopicrespPacket {
+    fn length(&self) -> u16 {
+        3
+    }
+
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_WILLTOPICRESP
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_u8(self.return_code);
+    }
+}
+
+// Willmsgupd
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct WillmsgupdPacket {
+    pub length: u16,
+    pub msg_type: u8,
+    pub will_msg: PayloadBuf,
+}
+
+impl TryFrom<&[u8]> for WillmsgupdPacket {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_WILLMSGUPD, 2)?;
+        Ok(WillmsgupdPacket {
+            length,
+            msg_type: bytes[1],
+            will_msg: mqtt_sn_read_field(length, bytes, 2)?,
+        })
+    }
+}
+
+impl Packet for WillmsgupdPacket {
+    fn length(&self) -> u16 {
+        mqtt_sn_packet_length(0x02 + self.will_msg.len())
+    }
+
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_WILLMSGUPD
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_slice(self.will_msg.as_slice());
+    }
+}
+
+// Willmsgresp
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct WillmsgrespPacket {
+    pub length: u16,
+    pub msg_type: u8,
+    pub return_code: u8,
+}
+
+impl TryFrom<&[u8]> for WillmsgrespPacket {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (length, bytes) = mqtt_sn_decode_header(bytes, MQTT_SN_WILLMSGRESP, 3)?;
+        Ok(WillmsgrespPacket {
+            length,
+            msg_type: bytes[1],
+            return_code: bytes[2],
+        })
+    }
+}
+
+impl Packet for WillmsgrespPacket {
+    fn length(&self) -> u16 {
+        3
+    }
+
+    fn msg_type(&self) -> u8 {
+        MQTT_SN_WILLMSGRESP
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_u8(self.return_code);
     }
 }
 
@@ -583,15 +1445,47 @@ impl Packet for DisconnectPacket {
 #[derive(Debug)]
 #[derive(Clone)]
 pub struct FWDEncapPacket {
-    pub length: u8,
+    pub length: u16,
     pub msg_type: u8,
     pub ctrl: u8,
-    pub wireless_node_id: Vec<u8>,
-    pub inner_packet: Vec<u8>,
+    pub wireless_node_id: WirelessNodeIdBuf,
+    pub inner_packet: InnerPacketBuf,
+}
+
+impl TryFrom<&[u8]> for FWDEncapPacket {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        // The header always uses the short length form, the inner
+        // packet may not
+        if bytes.first() == Some(&MQTT_SN_LONG_LENGTH_PREFIX) {
+            return Err(DecodeError::new(MQTT_SN_FRWDENCAP, 0, DecodeErrorKind::InvalidLength));
+        }
+        mqtt_sn_decode_header(bytes, MQTT_SN_FRWDENCAP, 3)?;
+        let last_index = bytes[0] as usize;
+        let inner_packet_length = match mqtt_sn_decode_length(&bytes[last_index..]) {
+            Some((length, header_length)) if length as usize > header_length => length as usize,
+            _ => return Err(DecodeError::new(MQTT_SN_FRWDENCAP, last_index, DecodeErrorKind::InvalidLength)),
+        };
+        let inner_packet_end = last_index + inner_packet_length;
+        if inner_packet_end > bytes.len() {
+            return Err(DecodeError::new(MQTT_SN_FRWDENCAP, bytes.len(), DecodeErrorKind::Truncated {
+                declared: inner_packet_end,
+                available: bytes.len(),
+            }));
+        }
+        Ok(FWDEncapPacket {
+            length: bytes[0] as u16,
+            msg_type: bytes[1],
+            ctrl: bytes[2],
+            wireless_node_id: mqtt_sn_read_field_at(MQTT_SN_FRWDENCAP, &bytes[3..last_index], 3)?,
+            inner_packet: mqtt_sn_read_field_at(MQTT_SN_FRWDENCAP, &bytes[last_index..inner_packet_end], last_index)?,
+        })
+    }
 }
 
 impl Packet for FWDEncapPacket {
-    fn length(&self) -> u8 {
+    fn length(&self) -> u16 {
         self.length
     }
 
@@ -599,31 +1493,440 @@ impl Packet for FWDEncapPacket {
         MQTT_SN_FRWDENCAP
     }
 
-    fn from_bytes(bytes: &Vec<u8>) -> Self where Self: Sized {
-        let last_index = bytes[0] as usize;
-        let inner_packet_length = bytes[last_index] as usize;
-        let inner_packet_end = last_index + inner_packet_length;
-        FWDEncapPacket {
-            length: bytes[0],
-            msg_type: bytes[1],
-            ctrl: bytes[2],
-            wireless_node_id: bytes[3..last_index].to_vec(),
-            inner_packet: bytes[last_index..inner_packet_end].to_vec(),
+    // The length field only covers the encapsulation header
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Push the struct fields into the buffer
+        mqtt_sn_put_length(buf, self.length);
+        buf.put_u8(self.msg_type);
+        buf.put_u8(self.ctrl);
+        buf.put_slice(self.wireless_node_id.as_slice());
+        buf.put_slice(self.inner_packet.as_slice());
+    }
+}
+
+// Checks buf has room for the packet before encoding into it, as BufMut
+// panics when writing past the end of a slice
+fn mqtt_sn_encode_to_slice(
+    buf: &mut [u8],
+    msg_type: u8,
+    encoded_length: usize,
+    encode: impl FnOnce(&mut &mut [u8]),
+) -> Result<usize, EncodeError> {
+    let available = buf.len();
+    if available < encoded_length {
+        return Err(EncodeError { msg_type, needed: encoded_length, available });
+    }
+    // The slice shrinks as it gets written
+    let mut slice = buf;
+    encode(&mut slice);
+    Ok(available - slice.len())
+}
+
+// Reads the message type, right after the length field
+pub fn mqtt_sn_read_msg_type(buffer: &[u8]) -> Result<u8, DecodeError> {
+    let (length, header_length) = mqtt_sn_decode_length(buffer)
+        .ok_or(DecodeError { msg_type: None, offset: 0, kind: DecodeErrorKind::InvalidLength })?;
+    if buffer.len() <= header_length {
+        return Err(DecodeError {
+            msg_type: None,
+            offset: buffer.len(),
+            kind: DecodeErrorKind::Truncated { declared: length as usize, available: buffer.len() },
+        });
+    }
+    if length as usize <= header_length {
+        return Err(DecodeError {
+            msg_type: None,
+            offset: header_length,
+            kind: DecodeErrorKind::TooShort { length: length as usize, min_length: header_length + 1 },
+        });
+    }
+    Ok(buffer[header_length])
+}
+
+// Any MQTT-SN v1.2 packet, one variant per message type
+#[derive(Debug)]
+#[derive(Clone)]
+// Without alloc the fields of each packet are kept inline
+#[cfg_attr(not(feature = "alloc"), allow(clippy::large_enum_variant))]
+pub enum MqttSnPacket {
+    Advertise(AdvertisePacket),
+    Searchgw(SearchgwPacket),
+    Gwinfo(GwinfoPacket),
+    Connect(ConnectPacket),
+    Connack(ConnackPacket),
+    Willtopicreq(WilltopicreqPacket),
+    Willtopic(WilltopicPacket),
+    Willmsgreq(WillmsgreqPacket),
+    Willmsg(WillmsgPacket),
+    Register(RegisterPacket),
+    Regack(RegackPacket),
+    Publish(PublishPacket),
+    Puback(PubackPacket),
+    Pubcomp(PubcompPacket),
+    Pubrec(PubrecPacket),
+    Pubrel(PubrelPacket),
+    Subscribe(SubscribePacket),
+    Suback(SubackPacket),
+    Unsubscribe(UnsubscribePacket),
+    Unsuback(UnsubackPacket),
+    Pingreq(PingreqPacket),
+    Pingresp(PingrespPacket),
+    Disconnect(DisconnectPacket),
+    Willtopicupd(WilltopicupdPacket),
+    Willtopicresp(WilltopicrespPacket),
+    Willmsgupd(WillmsgupdPacket),
+    Willmsgresp(WillmsgrespPacket),
+    Frwdencap(FWDEncapPacket),
+}
+
+impl MqttSnPacket {
+    pub fn decode(bytes: &[u8]) -> Result<MqttSnPacket, DecodeError> {
+        let msg_type = mqtt_sn_read_msg_type(bytes)?;
+        let packet = match msg_type {
+            MQTT_SN_ADVERTISE => MqttSnPacket::Advertise(AdvertisePacket::try_from(bytes)?),
+            MQTT_SN_SEARCHGW => MqttSnPacket::Searchgw(SearchgwPacket::try_from(bytes)?),
+            MQTT_SN_GWINFO => MqttSnPacket::Gwinfo(GwinfoPacket::try_from(bytes)?),
+            MQTT_SN_CONNECT => MqttSnPacket::Connect(ConnectPacket::try_from(bytes)?),
+            MQTT_SN_CONNACK => MqttSnPacket::Connack(ConnackPacket::try_from(bytes)?),
+            MQTT_SN_WILLTOPICREQ => MqttSnPacket::Willtopicreq(WilltopicreqPacket::try_from(bytes)?),
+            MQTT_SN_WILLTOPIC => MqttSnPacket::Willtopic(WilltopicPacket::try_from(bytes)?),
+            MQTT_SN_WILLMSGREQ => MqttSnPacket::Willmsgreq(WillmsgreqPacket::try_from(bytes)?),
+            MQTT_SN_WILLMSG => MqttSnPacket::Willmsg(WillmsgPacket::try_from(bytes)?),
+            MQTT_SN_REGISTER => MqttSnPacket::Register(RegisterPacket::try_from(bytes)?),
+            MQTT_SN_REGACK => MqttSnPacket::Regack(RegackPacket::try_from(bytes)?),
+            MQTT_SN_PUBLISH => MqttSnPacket::Publish(PublishPacket::try_from(bytes)?),
+            MQTT_SN_PUBACK => MqttSnPacket::Puback(PubackPacket::try_from(bytes)?),
+            MQTT_SN_PUBCOMP => MqttSnPacket::Pubcomp(PubcompPacket::try_from(bytes)?),
+            MQTT_SN_PUBREC => MqttSnPacket::Pubrec(PubrecPacket::try_from(bytes)?),
+            MQTT_SN_PUBREL => MqttSnPacket::Pubrel(PubrelPacket::try_from(bytes)?),
+            MQTT_SN_SUBSCRIBE => MqttSnPacket::Subscribe(SubscribePacket::try_from(bytes)?),
+            MQTT_SN_SUBACK => MqttSnPacket::Suback(SubackPacket::try_from(bytes)?),
+            MQTT_SN_UNSUBSCRIBE => MqttSnPacket::Unsubscribe(UnsubscribePacket::try_from(bytes)?),
+            MQTT_SN_UNSUBACK => MqttSnPacket::Unsuback(UnsubackPacket::try_from(bytes)?),
+            MQTT_SN_PINGREQ => MqttSnPacket::Pingreq(PingreqPacket::try_from(bytes)?),
+            MQTT_SN_PINGRESP => MqttSnPacket::Pingresp(PingrespPacket::try_from(bytes)?),
+            MQTT_SN_DISCONNECT => MqttSnPacket::Disconnect(DisconnectPacket::try_from(bytes)?),
+            MQTT_SN_WILLTOPICUPD => MqttSnPacket::Willtopicupd(WilltopicupdPacket::try_from(bytes)?),
+            MQTT_SN_WILLTOPICRESP => MqttSnPacket::Willtopicresp(WilltopicrespPacket::try_from(bytes)?),
+            MQTT_SN_WILLMSGUPD => MqttSnPacket::Willmsgupd(WillmsgupdPacket::try_from(bytes)?),
+            MQTT_SN_WILLMSGRESP => MqttSnPacket::Willmsgresp(WillmsgrespPacket::try_from(bytes)?),
+            MQTT_SN_FRWDENCAP => MqttSnPacket::Frwdencap(FWDEncapPacket::try_from(bytes)?),
+            _ => {
+                let offset = mqtt_sn_decode_length(bytes).map(|(_, header_length)| header_length).unwrap_or(1);
+                return Err(DecodeError { msg_type: None, offset, kind: DecodeErrorKind::UnknownType(msg_type) });
+            }
+        };
+        Ok(packet)
+    }
+
+    pub fn encode(&self, buf: &mut impl BufMut) {
+        match self {
+            MqttSnPacket::Advertise(packet) => packet.encode(buf),
+            MqttSnPacket::Searchgw(packet) => packet.encode(buf),
+            MqttSnPacket::Gwinfo(packet) => packet.encode(buf),
+            MqttSnPacket::Connect(packet) => packet.encode(buf),
+            MqttSnPacket::Connack(packet) => packet.encode(buf),
+            MqttSnPacket::Willtopicreq(packet) => packet.encode(buf),
+            MqttSnPacket::Willtopic(packet) => packet.encode(buf),
+            MqttSnPacket::Willmsgreq(packet) => packet.encode(buf),
+            MqttSnPacket::Willmsg(packet) => packet.encode(buf),
+            MqttSnPacket::Register(packet) => packet.encode(buf),
+            MqttSnPacket::Regack(packet) => packet.encode(buf),
+            MqttSnPacket::Publish(packet) => packet.encode(buf),
+            MqttSnPacket::Puback(packet) => packet.encode(buf),
+            MqttSnPacket::Pubcomp(packet) => packet.encode(buf),
+            MqttSnPacket::Pubrec(packet) => packet.encode(buf),
+            MqttSnPacket::Pubrel(packet) => packet.encode(buf),
+            MqttSnPacket::Subscribe(packet) => packet.encode(buf),
+            MqttSnPacket::Suback(packet) => packet.encode(buf),
+            MqttSnPacket::Unsubscribe(packet) => packet.encode(buf),
+            MqttSnPacket::Unsuback(packet) => packet.encode(buf),
+            MqttSnPacket::Pingreq(packet) => packet.encode(buf),
+            MqttSnPacket::Pingresp(packet) => packet.encode(buf),
+            MqttSnPacket::Disconnect(packet) => packet.encode(buf),
+            MqttSnPacket::Willtopicupd(packet) => packet.encode(buf),
+            MqttSnPacket::Willtopicresp(packet) => packet.encode(buf),
+            MqttSnPacket::Willmsgupd(packet) => packet.encode(buf),
+            MqttSnPacket::Willmsgresp(packet) => packet.encode(buf),
+            MqttSnPacket::Frwdencap(packet) => packet.encode(buf),
         }
     }
 
-    fn as_bytes(&self) -> Vec<u8> {
-        // Create an array of bytes and push the struct fields into it
-        let mut bytes = Vec::new(); 
-        bytes.push(self.length);
-        bytes.push(self.msg_type);
-        bytes.push(self.ctrl);
-        bytes.extend_from_slice(self.wireless_node_id.as_slice());
-        bytes.extend_from_slice(self.inner_packet.as_slice());
+    pub fn encoded_length(&self) -> usize {
+        let mut counter = LengthCounter::default();
+        self.encode(&mut counter);
+        counter.written
+    }
+
+    pub fn encode_to_slice(&self, buf: &mut [u8]) -> Result<usize, EncodeError> {
+        mqtt_sn_encode_to_slice(buf, self.msg_type(), self.encoded_length(), |slice| self.encode(slice))
+    }
+
+    #[cfg(feature = "alloc")]
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.encoded_length());
+        self.encode(&mut bytes);
         bytes
     }
 
-    fn as_frwdencap(&self) -> Option<&FWDEncapPacket> {
-        Some(self)
+    pub fn length(&self) -> u16 {
+        match self {
+            MqttSnPacket::Advertise(packet) => packet.length,
+            MqttSnPacket::Searchgw(packet) => packet.length,
+            MqttSnPacket::Gwinfo(packet) => packet.length,
+            MqttSnPacket::Connect(packet) => packet.length,
+            MqttSnPacket::Connack(packet) => packet.length,
+            MqttSnPacket::Willtopicreq(packet) => packet.length,
+            MqttSnPacket::Willtopic(packet) => packet.length,
+            MqttSnPacket::Willmsgreq(packet) => packet.length,
+            MqttSnPacket::Willmsg(packet) => packet.length,
+            MqttSnPacket::Register(packet) => packet.length,
+            MqttSnPacket::Regack(packet) => packet.length,
+            MqttSnPacket::Publish(packet) => packet.length,
+            MqttSnPacket::Puback(packet) => packet.length,
+            MqttSnPacket::Pubcomp(packet) => packet.length,
+            MqttSnPacket::Pubrec(packet) => packet.length,
+            MqttSnPacket::Pubrel(packet) => packet.length,
+            MqttSnPacket::Subscribe(packet) => packet.length,
+            MqttSnPacket::Suback(packet) => packet.length,
+            MqttSnPacket::Unsubscribe(packet) => packet.length,
+            MqttSnPacket::Unsuback(packet) => packet.length,
+            MqttSnPacket::Pingreq(packet) => packet.length,
+            MqttSnPacket::Pingresp(packet) => packet.length,
+            MqttSnPacket::Disconnect(packet) => packet.length,
+            MqttSnPacket::Willtopicupd(packet) => packet.length,
+            MqttSnPacket::Willtopicresp(packet) => packet.length,
+            MqttSnPacket::Willmsgupd(packet) => packet.length,
+            MqttSnPacket::Willmsgresp(packet) => packet.length,
+            MqttSnPacket::Frwdencap(packet) => packet.length,
+        }
+    }
+
+    pub fn msg_type(&self) -> u8 {
+        match self {
+            MqttSnPacket::Advertise(_) => MQTT_SN_ADVERTISE,
+            MqttSnPacket::Searchgw(_) => MQTT_SN_SEARCHGW,
+            MqttSnPacket::Gwinfo(_) => MQTT_SN_GWINFO,
+            MqttSnPacket::Connect(_) => MQTT_SN_CONNECT,
+            MqttSnPacket::Connack(_) => MQTT_SN_CONNACK,
+            MqttSnPacket::Willtopicreq(_) => MQTT_SN_WILLTOPICREQ,
+            MqttSnPacket::Willtopic(_) => MQTT_SN_WILLTOPIC,
+            MqttSnPacket::Willmsgreq(_) => MQTT_SN_WILLMSGREQ,
+            MqttSnPacket::Willmsg(_) => MQTT_SN_WILLMSG,
+            MqttSnPacket::Register(_) => MQTT_SN_REGISTER,
+            MqttSnPacket::Regack(_) => MQTT_SN_REGACK,
+            MqttSnPacket::Publish(_) => MQTT_SN_PUBLISH,
+            MqttSnPacket::Puback(_) => MQTT_SN_PUBACK,
+            MqttSnPacket::Pubcomp(_) => MQTT_SN_PUBCOMP,
+            MqttSnPacket::Pubrec(_) => MQTT_SN_PUBREC,
+            MqttSnPacket::Pubrel(_) => MQTT_SN_PUBREL,
+            MqttSnPacket::Subscribe(_) => MQTT_SN_SUBSCRIBE,
+            MqttSnPacket::Suback(_) => MQTT_SN_SUBACK,
+            MqttSnPacket::Unsubscribe(_) => MQTT_SN_UNSUBSCRIBE,
+            MqttSnPacket::Unsuback(_) => MQTT_SN_UNSUBACK,
+            MqttSnPacket::Pingreq(_) => MQTT_SN_PINGREQ,
+            MqttSnPacket::Pingresp(_) => MQTT_SN_PINGRESP,
+            MqttSnPacket::Disconnect(_) => MQTT_SN_DISCONNECT,
+            MqttSnPacket::Willtopicupd(_) => MQTT_SN_WILLTOPICUPD,
+            MqttSnPacket::Willtopicresp(_) => MQTT_SN_WILLTOPICRESP,
+            MqttSnPacket::Willmsgupd(_) => MQTT_SN_WILLMSGUPD,
+            MqttSnPacket::Willmsgresp(_) => MQTT_SN_WILLMSGRESP,
+            MqttSnPacket::Frwdencap(_) => MQTT_SN_FRWDENCAP,
+        }
+    }
+
+    // Return code of the acknowledgements that carry one
+    pub fn return_code(&self) -> Option<ReturnCode> {
+        let return_code = match self {
+            MqttSnPacket::Connack(packet) => packet.return_code,
+            MqttSnPacket::Regack(packet) => packet.return_code,
+            MqttSnPacket::Puback(packet) => packet.return_code,
+            MqttSnPacket::Suback(packet) => packet.return_code,
+            MqttSnPacket::Willtopicresp(packet) => packet.return_code,
+            MqttSnPacket::Willmsgresp(packet) => packet.return_code,
+            _ => return None,
+        };
+        Some(ReturnCode::from(return_code))
+    }
+
+    // Message ID of the packets that carry one
+    pub fn message_id(&self) -> Option<u16> {
+        let message_id = match self {
+            MqttSnPacket::Register(packet) => packet.message_id,
+            MqttSnPacket::Regack(packet) => packet.message_id,
+            MqttSnPacket::Publish(packet) => packet.message_id,
+            MqttSnPacket::Puback(packet) => packet.message_id,
+            MqttSnPacket::Pubrec(packet) => packet.message_id,
+            MqttSnPacket::Pubrel(packet) => packet.message_id,
+            MqttSnPacket::Pubcomp(packet) => packet.message_id,
+            MqttSnPacket::Subscribe(packet) => packet.message_id,
+            MqttSnPacket::Suback(packet) => packet.message_id,
+            MqttSnPacket::Unsubscribe(packet) => packet.message_id,
+            MqttSnPacket::Unsuback(packet) => packet.message_id,
+            _ => return None,
+        };
+        Some(message_id)
+    }
+}
+
+
+// Run with --no-default-features to check the codec builds and works
+// without std, and with --features alloc for the Vec backed fields
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqttsn::flags::QoS;
+
+    fn field<F: FieldBuf>(bytes: &[u8]) -> F {
+        F::from_field(bytes).unwrap()
+    }
+
+    #[test]
+    fn publish_roundtrip() {
+        let packet = MqttSnPacket::Publish(PublishPacket {
+            length: 9,
+            msg_type: MQTT_SN_PUBLISH,
+            flags: Flags::new().with_qos(QoS::AtLeastOnce),
+            topic_id: 0x0102,
+            message_id: 7,
+            data: field(b"hi"),
+        });
+        let mut buffer = [0u8; MQTT_SN_MAX_PACKET_LENGTH];
+        let length = packet.encode_to_slice(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], &[9, MQTT_SN_PUBLISH, MQTT_SN_FLAG_QOS_1, 1, 2, 0, 7, b'h', b'i']);
+
+        match MqttSnPacket::decode(&buffer[..length]).unwrap() {
+            MqttSnPacket::Publish(publish) => {
+                assert_eq!(publish.topic_id, 0x0102);
+                assert_eq!(publish.message_id, 7);
+                assert_eq!(publish.data.as_slice(), b"hi");
+            }
+            packet => panic!("decoded {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn encode_to_short_slice() {
+        let packet = MqttSnPacket::Disconnect(DisconnectPacket { length: 4, msg_type: MQTT_SN_DISCONNECT, duration: 60 });
+        let mut buffer = [0u8; 3];
+        let error = packet.encode_to_slice(&mut buffer).unwrap_err();
+        assert_eq!(error, EncodeError { msg_type: MQTT_SN_DISCONNECT, needed: 4, available: 3 });
+    }
+
+    #[test]
+    fn encode_with_mismatched_length() {
+        // The length field asks for the duration, even though it is 0
+        let disconnect = DisconnectPacket { length: 4, msg_type: MQTT_SN_DISCONNECT, duration: 0 };
+        let mut buffer = [0u8; 3];
+        let error = disconnect.encode_to_slice(&mut buffer).unwrap_err();
+        assert_eq!(error, EncodeError { msg_type: MQTT_SN_DISCONNECT, needed: 4, available: 3 });
+
+        // The payload is longer than the length field says
+        let publish = MqttSnPacket::Publish(PublishPacket {
+            length: 7,
+            msg_type: MQTT_SN_PUBLISH,
+            flags: Flags::new(),
+            topic_id: 1,
+            message_id: 0,
+            data: field(b"hello"),
+        });
+        let mut buffer = [0u8; 8];
+        let error = publish.encode_to_slice(&mut buffer).unwrap_err();
+        assert_eq!(error, EncodeError { msg_type: MQTT_SN_PUBLISH, needed: 12, available: 8 });
+        assert_eq!(publish.encode_to_slice(&mut [0u8; 16]), Ok(12));
+    }
+
+    #[test]
+    fn frwdencap_roundtrip() {
+        let packet = MqttSnPacket::Frwdencap(FWDEncapPacket {
+            length: 5,
+            msg_type: MQTT_SN_FRWDENCAP,
+            ctrl: 0,
+            wireless_node_id: field(&[0xAB, 0xCD]),
+            inner_packet: field(&[2, MQTT_SN_PINGRESP]),
+        });
+        let mut buffer = [0u8; 16];
+        let length = packet.encode_to_slice(&mut buffer).unwrap();
+        assert_eq!(length, 7);
+
+        match MqttSnPacket::decode(&buffer[..length]).unwrap() {
+            MqttSnPacket::Frwdencap(frwdencap) => {
+                assert_eq!(frwdencap.wireless_node_id.as_slice(), &[0xAB, 0xCD]);
+                assert_eq!(frwdencap.inner_packet.as_slice(), &[2, MQTT_SN_PINGRESP]);
+            }
+            packet => panic!("decoded {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn will_update_roundtrip() {
+        let packet = MqttSnPacket::Willtopicupd(WilltopicupdPacket {
+            length: 6,
+            msg_type: MQTT_SN_WILLTOPICUPD,
+            flags: Flags::new().with_qos(QoS::AtLeastOnce).with_retain(true),
+            will_topic: field(b"a/b"),
+        });
+        let mut buffer = [0u8; 16];
+        let length = packet.encode_to_slice(&mut buffer).unwrap();
+        assert_eq!(
+            &buffer[..length],
+            &[6, MQTT_SN_WILLTOPICUPD, MQTT_SN_FLAG_QOS_1 | MQTT_SN_FLAG_RETAIN, b'a', b'/', b'b']
+        );
+
+        match MqttSnPacket::decode(&buffer[..length]).unwrap() {
+            MqttSnPacket::Willtopicupd(willtopicupd) => {
+                assert_eq!(willtopicupd.flags.qos(), QoS::AtLeastOnce);
+                assert!(willtopicupd.flags.retain());
+                assert_eq!(willtopicupd.will_topic.as_slice(), b"a/b");
+            }
+            packet => panic!("decoded {:?}", packet),
+        }
+
+        let packet = MqttSnPacket::Willmsgupd(WillmsgupdPacket {
+            length: 5,
+            msg_type: MQTT_SN_WILLMSGUPD,
+            will_msg: field(b"bye"),
+        });
+        let length = packet.encode_to_slice(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], &[5, MQTT_SN_WILLMSGUPD, b'b', b'y', b'e']);
+
+        match MqttSnPacket::decode(&buffer[..length]).unwrap() {
+            MqttSnPacket::Willmsgupd(willmsgupd) => assert_eq!(willmsgupd.will_msg.as_slice(), b"bye"),
+            packet => panic!("decoded {:?}", packet),
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn truncated_packet() {
+        let error = MqttSnPacket::decode(&[9, MQTT_SN_PUBLISH, 0, 0, 1]).unwrap_err();
+        assert_eq!(error.kind, DecodeErrorKind::Truncated { declared: 9, available: 5 });
+    }
+
+    // Topic ID type 0b11 is reserved
+    #[test]
+    fn reserved_topic_id_type() {
+        let error = MqttSnPacket::decode(&[7, MQTT_SN_PUBLISH, 0x23, 0, 1, 0, 7]).unwrap_err();
+        assert_eq!(error.kind, DecodeErrorKind::InvalidFlags(0x23));
+        assert_eq!(error.offset, 2);
+    }
+
+    // A PUBLISH using the long length form, too long for the fixed
+    // capacity payload
+    #[test]
+    fn long_publish() {
+        let mut buffer = [0u8; 300];
+        buffer[..9].copy_from_slice(&[MQTT_SN_LONG_LENGTH_PREFIX, 1, 44, MQTT_SN_PUBLISH, 0, 0, 1, 0, 0]);
+        let decoded = MqttSnPacket::decode(&buffer);
+
+        #[cfg(feature = "alloc")]
+        match decoded.unwrap() {
+            MqttSnPacket::Publish(publish) => assert_eq!(publish.data.len(), 291),
+            packet => panic!("decoded {:?}", packet),
+        }
+        #[cfg(not(feature = "alloc"))]
+        assert_eq!(
+            decoded.unwrap_err().kind,
+            DecodeErrorKind::FieldTooLong { length: 291, capacity: MQTT_SN_MAX_PAYLOAD_LENGTH }
+        );
+    }
+}
@@ -1,764 +1,285 @@
 // This module defines the logic for sending and receiving MQTT-SN packets.
+//
+// The functions below work on a session kept for the calling thread, see
+// Session and Client to run several sessions in one process.
 
-use log::{debug, error, info, warn, LevelFilter};
+use std::cell::RefCell;
 use std::str;
-use chrono::prelude::*;
 
 use crate::mqttsn::constants::{
     //Message types
-    // MQTT_SN_ADVERTISE,
-    MQTT_SN_CONNACK,
-    MQTT_SN_CONNECT,
-    MQTT_SN_DISCONNECT,
-    MQTT_SN_FLAG_CLEAN,
-    MQTT_SN_FLAG_QOS_0,
-    MQTT_SN_FLAG_QOS_1,
-    MQTT_SN_FLAG_QOS_2,
-    MQTT_SN_FLAG_QOS_N1,
-    MQTT_SN_FLAG_RETAIN,
     MQTT_SN_FRWDENCAP,
-    MQTT_SN_MAX_CLIENT_ID_LENGTH,
-    MQTT_SN_MAX_PACKET_LENGTH,
-    MQTT_SN_MAX_TOPIC_LENGTH,
-    MQTT_SN_MAX_WIRELESS_NODE_ID_LENGTH,
-    MQTT_SN_PINGREQ,
-    MQTT_SN_PINGRESP,
-    MQTT_SN_PROTOCOL_ID,
-    MQTT_SN_PUBACK,
-    MQTT_SN_PUBLISH,
-    MQTT_SN_REGACK,
-    MQTT_SN_REGISTER,
-    MQTT_SN_SUBACK,
-    MQTT_SN_SUBSCRIBE,
-    MQTT_SN_TOPIC_TYPE_NORMAL,
-    MQTT_SN_TOPIC_TYPE_PREDEFINED,
-    MQTT_SN_TOPIC_TYPE_SHORT,
 };
 
 use crate::mqttsn::packet_types::{
     //Packet types
-    mqtt_sn_packet_type_to_str,
+    mqtt_sn_read_msg_type,
+    FWDEncapPacket,
     ConnackPacket,
-    ConnectPacket,
-    DisconnectPacket,
-    Packet,
-    PingreqPacket,
-    PingrespPacket,
-    PubackPacket,
+    MqttSnPacket,
     PublishPacket,
+    PubrelPacket,
     RegackPacket,
     RegisterPacket,
-    SubackPacket,
-    SubscribePacket,
     Topic,
+    WillmsgrespPacket,
+    WilltopicrespPacket,
 };
 
-use crate::mqttsn::settings::{
-    get_last_message_id, get_next_message_id, get_qos_flag, get_topic_id, reset_message_id,
-    set_topic_id, set_wireless_node_id, Settings,
-};
+use crate::mqttsn::error::MqttSnError;
 
-use crate::mqttsn::network_abstractions::SensorNetwork;
+use crate::mqttsn::settings::Settings;
+use crate::mqttsn::flags::TopicIdType;
 
-// Generic send and receive functions
+use crate::mqttsn::network_abstractions::{PeerAddress, SensorNetwork};
 
-pub fn mqtt_sn_send_packet(sensor_net: &mut dyn SensorNetwork, packet: &dyn Packet) -> Result<(), String>{
-    // Use the length to get the bytes
-    let packet_bytes = packet.as_bytes();
-    let packet_length = packet_bytes[0];
-    let safe_buffer: &[u8] = &packet_bytes[0..packet_length as usize];
-    // Print the packet, each byte as hex
-    let mut hex_buffer = String::new();
-    for byte in safe_buffer {
-        hex_buffer.push_str(&format!("{:02X} ", byte));
-    }
-    // Strip final space
-    hex_buffer.pop();
-    debug!("Sending packet: {:?}", hex_buffer);
-
-    let result = sensor_net.send(safe_buffer);
-    match result {
-        Ok(_) => Ok(()),
-        Err(e) => Err(format!("Failed to send packet: {}", e)),
-    }
-}
+use crate::mqttsn::session::Session;
 
-pub fn mqtt_sn_print_publish_packet(packet: &PublishPacket, settings: &Settings) {
-    if settings.verbose_time {
-        let time = Local::now();
-        print!("{} - ", time);
-    }
-    
-    if settings.verbose {
-        print!("{}: ", packet.topic_id);
-    }
-    println!("{}", str::from_utf8(&packet.data).unwrap());
+thread_local! {
+    // Session used by the free functions of this module
+    static SESSION: RefCell<Session> = RefCell::new(Session::new());
 }
 
-pub fn mqtt_sn_validate_packet(buffer: &[u8], settings: &Settings) -> Option<Box<dyn Packet>> {
-    // Check valid packet length
-    let length = buffer[0] as usize;
-    let inner_length = buffer[buffer[0] as usize] as usize;
-    let total_length = length + inner_length;
-    let packet_type = buffer[1];
+fn with_session<T>(f: impl FnOnce(&mut Session) -> T) -> T {
+    SESSION.with(|session| f(&mut session.borrow_mut()))
+}
 
-    if length == 0 {
-        error!("Invalid packet length: {}", length);
-        return None;
-    }
+// Packet parsing
 
-    if length == 1 {
-        error!("Valid, but unsupported packet length format.");
-        return None;
-    }
+pub fn mqtt_sn_validate_packet(buffer: &[u8], settings: &Settings) -> Result<MqttSnPacket, MqttSnError> {
+    let packet_type = mqtt_sn_read_msg_type(buffer)?;
 
     // Forwarder encapsulation enabled
     // If FWDEncap is enabled, every packet should be a FWDEncap packet
     if settings.forwarder_encapsulation && packet_type != MQTT_SN_FRWDENCAP {
-        error!("Forwarder encapsulation is enabled, but received a non-FWDEncap packet");
-        return None;
-    }
-
-    // Check length if FWDEncap is enabled
-    if packet_type == MQTT_SN_FRWDENCAP && length < 4 {
-        error!("Invalid packet length for FWDEncap: {}", length);
-        return None;
+        return Err(MqttSnError::ProtocolViolation(String::from(
+            "Forwarder encapsulation is enabled, but received a non-FWDEncap packet",
+        )));
     }
 
-    if packet_type == MQTT_SN_FRWDENCAP {
-        if buffer.len() < total_length {
-            error!(
-                "Received only {} bytes, but expected {} bytes",
-                buffer.len(),
-                total_length
-            );
-            return None;
-        }
-    }
-    // Return the packet
-    mqtt_sn_rebuild_packet(&buffer.to_vec())
-}
-
-pub fn mqtt_sn_rebuild_packet(buffer: &Vec<u8>) -> Option<Box<dyn Packet>> {
-    // Read the message type
-    let msg_type = buffer[1];
-
-    // Create a packet based on the message type
-    let packet: Box<dyn Packet> = match msg_type {
-        // All supported message types in order
-        //MQTT_SN_ADVERTISE => Box::new(AdvertisePacket::from_bytes(buffer)),
-        //MQTT_SN_SEARCHGW => Box::new(SearchgwPacket::from_bytes(buffer)),
-        //MQTT_SN_GWINFO => Box::new(GwinfoPacket::from_bytes(buffer)),
-        MQTT_SN_CONNECT => Box::new(ConnectPacket::from_bytes(buffer)),
-        MQTT_SN_CONNACK => Box::new(ConnackPacket::from_bytes(buffer)),
-        //MQTT_SN_WILLTOPICREQ => Box::new(WilltopicreqPacket::from_bytes(buffer)),
-        //MQTT_SN_WILLTOPIC => Box::new(WilltopicPacket::from_bytes(buffer)),
-        //MQTT_SN_WILLMSGREQ => Box::new(WillmsgreqPacket::from_bytes(buffer)),
-        //MQTT_SN_WILLMSG => Box::new(WillmsgPacket::from_bytes(buffer)),
-        MQTT_SN_REGISTER => Box::new(RegisterPacket::from_bytes(buffer)),
-        MQTT_SN_REGACK => Box::new(RegackPacket::from_bytes(buffer)),
-        MQTT_SN_PUBLISH => Box::new(PublishPacket::from_bytes(buffer)),
-        MQTT_SN_PUBACK => Box::new(PubackPacket::from_bytes(buffer)),
-        //MQTT_SN_PUBCOMP => Box::new(PubcompPacket::from_bytes(buffer)),
-        //MQTT_SN_PUBREC => Box::new(PubrecPacket::from_bytes(buffer)),
-        //MQTT_SN_PUBREL => Box::new(PubrelPacket::from_bytes(buffer)),
-        MQTT_SN_SUBSCRIBE => Box::new(SubscribePacket::from_bytes(buffer)),
-        MQTT_SN_SUBACK => Box::new(SubackPacket::from_bytes(buffer)),
-        //MQTT_SN_UNSUBSCRIBE => Box::new(UnsubscribePacket::from_bytes(buffer)),
-        //MQTT_SN_UNSUBACK => Box::new(UnsubackPacket::from_bytes(buffer)),
-        MQTT_SN_PINGREQ => Box::new(PingreqPacket::from_bytes(buffer)),
-        MQTT_SN_PINGRESP => Box::new(PingrespPacket::from_bytes(buffer)),
-        MQTT_SN_DISCONNECT => Box::new(DisconnectPacket::from_bytes(buffer)),
-        //MQTT_SN_WILLTOPICUPD => Box::new(WilltopicupdPacket::from_bytes(buffer)),
-        //MQTT_SN_WILLTOPICRESP => Box::new(WilltopicrespPacket::from_bytes(buffer)),
-        //MQTT_SN_WILLMSGUPD => Box::new(WillmsgupdPacket::from_bytes(buffer)),
-        //MQTT_SN_WILLMSGRESP => Box::new(WillmsgrespPacket::from_bytes(buffer)),
-        //MQTT_SN_FRWDENCAP => Box::new(FrwdencapPacket::from_bytes(buffer)),
-        _ => return None,
-    };
+    // The decoders check the declared lengths against the buffer,
+    // including the one of an encapsulated packet
+    mqtt_sn_rebuild_packet(buffer)
+}
 
-    Some(packet)
+pub fn mqtt_sn_rebuild_packet(buffer: &[u8]) -> Result<MqttSnPacket, MqttSnError> {
+    Ok(MqttSnPacket::decode(buffer)?)
 }
 
-pub fn mqtt_sn_wait_for(
-    sensor_net: &mut dyn SensorNetwork,
-    packet_type: u8,
-    settings: &Settings,
-) -> Option<Box<dyn Packet>> {
-    // Save current time to calculate next keep alive
-    let start = std::time::Instant::now();
-    let mut last_transmission = start.clone();
+// A gateway found while searching, along with the address it was
+// heard from (if the network is able to tell).
+#[derive(Debug, Clone)]
+pub struct GatewayInfo {
+    pub gw_id: u8,
+    pub address: Option<PeerAddress>,
+    pub advertised: bool,
+}
 
-    let mut connected = true;
-    if packet_type == MQTT_SN_CONNACK {
-        connected = false;
-    }
+// Session wrappers
 
-    debug!("Start waiting for {} packet", mqtt_sn_packet_type_to_str(packet_type));
-
-    loop {
-        // Send a PINGREQ packet if keep alive is enabled, but only if connected
-        if settings.keep_alive > 0 && connected
-            && last_transmission.elapsed().as_secs() >= settings.keep_alive as u64
-        {
-            // Send a PINGREQ packet
-            debug!("Sending PINGREQ packet");
-            mqtt_sn_send_pingreq(sensor_net);
-            last_transmission = std::time::Instant::now();
-        }
-        // Receive a packet
-        let packet = mqtt_sn_receive_packet(sensor_net);
-
-        match packet {
-            Some(packet) =>{
-                let safe_packet = packet;
-                if safe_packet.msg_type() == packet_type {
-                    debug!("Received expected packet: {:?}", safe_packet);
-                    return Some(safe_packet);
-                } else if safe_packet.msg_type() == MQTT_SN_DISCONNECT {
-                    let disconnect = safe_packet.as_disconnect().unwrap();
-                    error!("Received DISCONNECT packet from gateway: {:?}", disconnect);
-                    // Exit and return -1
-                    std::process::exit(-1);
-                } else {
-                    warn!(
-                        "Was expecting {} packet but received {}",
-                        mqtt_sn_packet_type_to_str(packet_type),
-                        mqtt_sn_packet_type_to_str(safe_packet.msg_type())
-                    );
-                }
-            }
-            None => {
-                warn!("Network timeout reached while waiting for packet");
-                warn!("Retrying...");
-            }
-        }
-
-        // Check if the timeout has been reached
-        if settings.timeout > 0 && start.elapsed().as_secs() >= settings.timeout {
-            warn!("Timeout reached while waiting for packet");
-            info!("Timeout: {}", settings.timeout);
-            break;
-        }
-    }
-    None
-}
-
-pub fn mqtt_receive_frwdencap_packet(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Option<Box<dyn Packet>> {
-    // Create a buffer to hold the data, with a maximun size given by:
-    // MQTT_SN_MAX_PACKET_LENGTH
-    // MQTT_SN_MAX_WIRELESS_NODE_ID_LENGTH
-    // + 4 (for the protocol overhead)
-    const MAX_SIZE: usize = MQTT_SN_MAX_PACKET_LENGTH + MQTT_SN_MAX_WIRELESS_NODE_ID_LENGTH + 4;
-    let mut buffer: Vec<u8>;
-
-    debug!("Waiting to receive packet...");
-
-    // Read the packet into the buffer safely
-    let read_result = sensor_net.receive();
-    match read_result {
-        Ok(data) => {
-            buffer = data;
-        }
-        Err(e) => {
-            let error_message = match e.kind() {
-                std::io::ErrorKind::TimedOut => "Timed out while waiting for packet",
-                std::io::ErrorKind::WouldBlock => "Network unavailable or busy",
-                _ => "Unknown error while waiting for packet.",
-            };
-            warn!("Failed to read from sensor_net: {}", error_message);
-            return None;
-        }
-    } 
-
-    let mut bytes_read = buffer[0] as usize;
-
-    if bytes_read > MAX_SIZE as usize {
-        warn!("Received packet is too long: {}", bytes_read);
-        info!("Truncating packet to {} bytes", MAX_SIZE);
-        bytes_read = MAX_SIZE as usize;
-        buffer.resize(bytes_read, 0);
-    }
+pub fn mqtt_sn_send_packet(sensor_net: &mut dyn SensorNetwork, packet: &MqttSnPacket) -> Result<(), MqttSnError> {
+    with_session(|session| session.send_packet(sensor_net, packet))
+}
 
-    debug!("Received {} bytes", bytes_read);
+pub fn mqtt_sn_enable_frwdencap(settings: &Settings) {
+    with_session(|session| session.enable_frwdencap(settings))
+}
 
-    if bytes_read == 0 {
-        panic!("Failed to read from sensor_net");
-    }
+pub fn mqtt_sn_disable_frwdencap() {
+    with_session(|session| session.disable_frwdencap())
+}
 
-    let log_level = log::max_level();
-    if log_level >= LevelFilter::Debug {
-        // Check if the packet is a forwarder encapsulation packet
-        if buffer[1] == MQTT_SN_FRWDENCAP {
-            debug!(
-                "Received {} bytes from sensor_net. Packet type {} -> {}",
-                bytes_read,
-                mqtt_sn_packet_type_to_str(buffer[2]),
-                mqtt_sn_packet_type_to_str(buffer[3]),
-            );
-        } else {
-            debug!(
-                "Received {} bytes from sensor_net. Packet type {}",
-                bytes_read,
-                mqtt_sn_packet_type_to_str(buffer[1]),
-            );
-        }
-    }
+pub fn mqtt_sn_create_frwdencap_packet(ctrl: u8, inner_packet: &[u8]) -> FWDEncapPacket {
+    with_session(|session| session.create_frwdencap_packet(ctrl, inner_packet))
+}
 
-    // Validate the packet
-    let generic_packet = mqtt_sn_validate_packet(&buffer, settings);
-    if generic_packet.is_none() {
-        error!("Failed to validate packet");
-        return None;
-    } else {
-        debug!("Packet validated");
-    }
+pub fn mqtt_sn_max_packet_length(sensor_net: &dyn SensorNetwork) -> usize {
+    with_session(|session| session.max_packet_length(sensor_net))
+}
 
-    // Add a null terminator to the buffer
-    buffer[bytes_read] = 0;
-
-    if buffer[1] == MQTT_SN_FRWDENCAP {
-        // Forwarder encapsulation packet;
-        // Rebuild the packet
-        let packet = generic_packet.unwrap();
-        let frwencap_packet = packet.as_frwdencap().unwrap();
-        set_wireless_node_id(frwencap_packet.wireless_node_id.clone());
-        let inner_packet_data = frwencap_packet.inner_packet.clone();
-        let inner_packet = mqtt_sn_rebuild_packet(&inner_packet_data);
-        // Return the inner packet
-        inner_packet
-    } else {
-        // Regular packet
-        mqtt_sn_rebuild_packet(&buffer.to_vec())
-    }
+pub fn mqtt_sn_max_topic_length(sensor_net: &dyn SensorNetwork) -> usize {
+    with_session(|session| session.max_topic_length(sensor_net))
 }
 
-pub fn mqtt_sn_receive_packet(sensor_net: &mut dyn SensorNetwork) -> Option<Box<dyn Packet>> {
-    // Create a buffer to hold the packet data
-    let mut buffer: Vec<u8>;
-    info!("Waiting to receive packet...");
-
-    // Read the packet into the buffer safely
-    let read_result = sensor_net.receive();
-
-    match read_result {
-        Ok(data) => {
-            buffer = data;
-        }
-        Err(e) => {
-            let error_message = match e.kind() {
-                std::io::ErrorKind::TimedOut => "Timed out while waiting for packet",
-                std::io::ErrorKind::WouldBlock => "Network unavailable or busy",
-                _ => "Unknown error while waiting for packet.",
-            };
-            warn!("Failed to read from sensor_net: {}", error_message);
-            return None;
-        }
-    }
-    if buffer.len() == 0 {
-        warn!("Failed to read from sensor_net: buffer is empty");
-        return None;
-    }
+pub fn mqtt_sn_max_payload_length(sensor_net: &dyn SensorNetwork) -> usize {
+    with_session(|session| session.max_payload_length(sensor_net))
+}
 
-    // Check the number of bytes read
-    let mut bytes_read = buffer[0] as usize;
+pub fn mqtt_sn_print_publish_packet(packet: &PublishPacket, settings: &Settings) {
+    with_session(|session| session.print_publish_packet(packet, settings))
+}
 
-    if bytes_read > MQTT_SN_MAX_PACKET_LENGTH as usize {
-        error!("Received packet is too long: {}", bytes_read);
-        warn!("Truncating packet to {} bytes", MQTT_SN_MAX_PACKET_LENGTH);
-        bytes_read = MQTT_SN_MAX_PACKET_LENGTH as usize;
-        buffer.resize(bytes_read, 0);
-    }
+pub fn mqtt_sn_wait_for(sensor_net: &mut dyn SensorNetwork, packet_type: u8, settings: &Settings) -> Result<MqttSnPacket, MqttSnError> {
+    with_session(|session| session.wait_for(sensor_net, packet_type, settings))
+}
 
-    debug!("Received {} bytes", bytes_read);
+pub fn mqtt_receive_frwdencap_packet(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<MqttSnPacket, MqttSnError> {
+    with_session(|session| session.receive_frwdencap_packet(sensor_net, settings))
+}
 
-    if bytes_read == 0 {
-        error!("Failed to read from sensor_net");
-        return None;
-    }
+pub fn mqtt_receive_frwdencap_packet_from(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(MqttSnPacket, Option<PeerAddress>), MqttSnError> {
+    with_session(|session| session.receive_frwdencap_packet_from(sensor_net, settings))
+}
 
-    // Rebuild the packet
-    let packet = mqtt_sn_rebuild_packet(&buffer.to_vec());
-    // Return the packet
-    packet
-}
-
-// Specific send and receive functions
-
-// Connection wrapper
-
-pub fn mqtt_sn_connect(sensor_net: &mut dyn SensorNetwork, settings: &Settings) {
-    loop {
-        // Send a CONNECT packet
-        loop {
-            match mqtt_sn_send_connect(sensor_net, settings, true) {
-                Ok(()) => {
-                    break;
-                }
-                Err(_) => {
-                    continue;
-                }
-            }
-        }
-        // Receive a CONNACK packet
-        match mqtt_sn_receive_connack(sensor_net, settings) {
-            Some(_) => {
-                break;
-            } 
-            None => {
-                continue;
-            }
-        }   
-    }
-    return;
+pub fn mqtt_sn_receive_packet(sensor_net: &mut dyn SensorNetwork) -> Result<MqttSnPacket, MqttSnError> {
+    with_session(|session| session.receive_packet(sensor_net))
 }
 
+pub fn mqtt_sn_discover_gateway(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<GatewayInfo, MqttSnError> {
+    with_session(|session| session.discover_gateway(sensor_net, settings))
+}
 
-pub fn mqtt_sn_send_connect(sensor_net: &mut dyn SensorNetwork, settings: &Settings, clean_session: bool) -> Result<(), String>{
-    // Check client id length
-    if settings.client_id.len() > MQTT_SN_MAX_CLIENT_ID_LENGTH {
-        panic!(
-            "Client ID is too long. Maximum length is {}",
-            MQTT_SN_MAX_CLIENT_ID_LENGTH
-        );
-    }
+pub fn mqtt_sn_send_searchgw(sensor_net: &mut dyn SensorNetwork, radius: u8) -> Result<(), MqttSnError> {
+    with_session(|session| session.send_searchgw(sensor_net, radius))
+}
 
-    let msg_type = MQTT_SN_CONNECT;
-    let flags = if clean_session { MQTT_SN_FLAG_CLEAN } else { 0 };
-    let protocol_id = MQTT_SN_PROTOCOL_ID;
-    let duration = settings.keep_alive.to_be();
-
-    // Copy the client ID into the packet
-    let client_id = settings.client_id.as_bytes().to_vec();
-
-    // Get the length of the packet
-    let length = 0x06 + settings.client_id.len() as u8;
-
-    // Assemble the packet
-    let packet = ConnectPacket {
-        length,
-        msg_type,
-        flags,
-        protocol_id,
-        duration,
-        client_id,
-    };
+pub fn mqtt_sn_connect(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+    with_session(|session| session.connect(sensor_net, settings))
+}
 
-    info!("Sending CONNECT {:?}", packet);
-    mqtt_sn_send_packet(sensor_net, &packet)
-}
-
-pub fn mqtt_sn_receive_connack(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Option<Box<ConnackPacket>>                           {
-    match mqtt_sn_wait_for(sensor_net, MQTT_SN_CONNACK, settings) {
-        // If some packet is received, it is a CONNACK packet for sure
-        Some(packet) => {
-            let connack = packet.as_connack().unwrap();
-            info!("Received CONNACK packet: {:?}", connack);
-            Some(Box::new(connack.clone()))
-        }
-        None => {
-            warn!("Failed to receive CONNACK packet");
-            None
-        }
-    }
+pub fn mqtt_sn_send_connect(sensor_net: &mut dyn SensorNetwork, settings: &Settings, clean_session: bool) -> Result<(), MqttSnError> {
+    with_session(|session| session.send_connect(sensor_net, settings, clean_session))
 }
 
-pub fn mqtt_sn_send_register(sensor_net: &mut dyn SensorNetwork, settings: &Settings) {
-    // Check topic name length
-    if settings.topic.len() > MQTT_SN_MAX_TOPIC_LENGTH {
-        panic!(
-            "Topic name is too long. Maximum length is {}",
-            MQTT_SN_MAX_TOPIC_LENGTH
-        );
-    }
+pub fn mqtt_sn_receive_connack(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<Box<ConnackPacket>, MqttSnError> {
+    with_session(|session| session.receive_connack(sensor_net, settings))
+}
 
-    let msg_type = MQTT_SN_REGISTER;
-    let topic_id = 0; // Not used
-    let message_id = get_next_message_id();
+pub fn mqtt_sn_send_will(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+    with_session(|session| session.send_will(sensor_net, settings))
+}
 
-    // Copy the topic name into the packet
-    let topic_name = settings.topic.as_bytes().to_vec();
+pub fn mqtt_sn_send_willtopic(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+    with_session(|session| session.send_willtopic(sensor_net, settings))
+}
 
-    // Get the packet length
-    let length = 0x06 + settings.topic.len() as u8;
+pub fn mqtt_sn_send_willmsg(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+    with_session(|session| session.send_willmsg(sensor_net, settings))
+}
 
-    // Assemble the packet
-    let packet = RegisterPacket {
-        length,
-        msg_type,
-        topic_id,
-        message_id,
-        topic_name,
-    };
+pub fn mqtt_sn_update_will_topic(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+    with_session(|session| session.update_will_topic(sensor_net, settings))
+}
 
-    info!("Sending REGISTER packet: {:?}", packet);
-    let _ = mqtt_sn_send_packet(sensor_net, &packet);
+pub fn mqtt_sn_update_will_message(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+    with_session(|session| session.update_will_message(sensor_net, settings))
 }
 
-pub fn mqtt_sn_receive_regack(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> RegackPacket {
-    let packet = mqtt_sn_wait_for(sensor_net, MQTT_SN_REGACK, settings);
+pub fn mqtt_sn_send_willtopicupd(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+    with_session(|session| session.send_willtopicupd(sensor_net, settings))
+}
 
-    if let Some(regack) = packet.unwrap().as_regack() {
-        debug!("Updated topic ID: {}", regack.topic_id);
-        let reordered = regack.topic_id.to_be();
-        set_topic_id(reordered);
-        regack.clone()
-    } else {
-        panic!("Received packet is not a REGACK packet");
-    }
+pub fn mqtt_sn_receive_willtopicresp(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<WilltopicrespPacket, MqttSnError> {
+    with_session(|session| session.receive_willtopicresp(sensor_net, settings))
 }
 
-pub fn mqtt_sn_send_subscribe_topic_name(sensor_net: &mut dyn SensorNetwork, settings: &Settings, topic: &str) {
-    // Check topic name length
-    if topic.len() > MQTT_SN_MAX_TOPIC_LENGTH {
-        panic!(
-            "Topic name is too long. Maximum length is {}",
-            MQTT_SN_MAX_TOPIC_LENGTH
-        );
-    }
+pub fn mqtt_sn_send_willmsgupd(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+    with_session(|session| session.send_willmsgupd(sensor_net, settings))
+}
 
-    let msg_type = MQTT_SN_SUBSCRIBE;
-    let message_id = get_next_message_id();
-    let mut flags = 0;
-    flags |= get_qos_flag(settings.qos);
-
-    // Copy the topic name into the packet
-    let topic_name: Topic = Topic::TopicName(topic.as_bytes().to_vec());
-
-    let length = 5 + topic.len() as u8;
-    // Get the packet length
-    if topic.len() == 2 {
-        // Short topic name
-        flags |= MQTT_SN_TOPIC_TYPE_SHORT;
-    } else {
-        // Normal topic name
-        flags |= MQTT_SN_TOPIC_TYPE_NORMAL;
-    }
+pub fn mqtt_sn_receive_willmsgresp(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<WillmsgrespPacket, MqttSnError> {
+    with_session(|session| session.receive_willmsgresp(sensor_net, settings))
+}
 
-    // Assemble the packet
-    let packet = SubscribePacket {
-        length,
-        msg_type,
-        flags,
-        message_id,
-        topic: topic_name,
-    };
+pub fn mqtt_sn_send_register(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+    with_session(|session| session.send_register(sensor_net, &settings.topic))
+}
 
-    info!("Sending SUBSCRIBE packet: {:?}", packet);
-    let _ = mqtt_sn_send_packet(sensor_net, &packet);
+pub fn mqtt_sn_receive_regack(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<RegackPacket, MqttSnError> {
+    with_session(|session| session.receive_regack(sensor_net, settings))
 }
 
-pub fn mqtt_sn_send_subscribe_topic_id(sensor_net: &mut dyn SensorNetwork, settings: &Settings, topic_id: u16) {
-    let msg_type = MQTT_SN_SUBSCRIBE;
-    let message_id = get_next_message_id();
-    let mut flags = 0;
-    flags |= MQTT_SN_TOPIC_TYPE_PREDEFINED;
-    flags |= get_qos_flag(settings.qos);
+pub fn mqtt_sn_receive_register(sensor_net: &mut dyn SensorNetwork, register: &RegisterPacket) -> Result<(), MqttSnError> {
+    with_session(|session| session.receive_register(sensor_net, register))
+}
 
-    // Copy the topic ID into the packet
-    let topic: Topic = Topic::TopicId(topic_id);
+pub fn mqtt_sn_send_regack(sensor_net: &mut dyn SensorNetwork, topic_id: u16, message_id: u16, return_code: u8) -> Result<(), MqttSnError> {
+    with_session(|session| session.send_regack(sensor_net, topic_id, message_id, return_code))
+}
 
-    // Get the packet length
-    let length = 0x05 + 2;
+pub fn mqtt_sn_send_subscribe_topic_name(sensor_net: &mut dyn SensorNetwork, settings: &Settings, topic: &str) -> Result<(), MqttSnError> {
+    with_session(|session| session.send_subscribe_topic_name(sensor_net, settings, topic))
+}
 
-    // Assemble the packet
-    let packet = SubscribePacket {
-        length,
-        msg_type,
-        flags,
-        message_id,
-        topic,
-    };
+pub fn mqtt_sn_send_subscribe_topic_id(sensor_net: &mut dyn SensorNetwork, settings: &Settings, topic_id: u16) -> Result<(), MqttSnError> {
+    with_session(|session| session.send_subscribe_topic_id(sensor_net, settings, topic_id))
+}
 
-    info!("Sending SUBSCRIBE packet: {:?}", packet);
-    let _ = mqtt_sn_send_packet(sensor_net, &packet);
-}
-
-pub fn mqtt_sn_receive_suback(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> u16 {
-    let packet = mqtt_sn_wait_for(sensor_net, MQTT_SN_SUBACK, settings);
-
-    if let Some(suback) = packet.unwrap().as_suback() {
-        debug!("Received SUBACK packet: {:?}", suback);
-        // Check the returned code
-        if suback.return_code != 0 {
-            error!("SUBACK failed with return code: {}", suback.return_code);
-            return 0;
-        } else {
-            // Check message ID
-            if suback.message_id != get_last_message_id() {
-                error!(
-                    "Received SUBACK with unexpected message ID: {}",
-                    suback.message_id
-                );
-                return 0;
-            } else {
-                info!("SUBACK successful");
-                info!("Topic ID: {}", suback.topic_id);
-                suback.topic_id
-            }
-        }
-    } else {
-        panic!("Received packet is not a SUBACK packet");
-    }
+pub fn mqtt_sn_receive_suback(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<u16, MqttSnError> {
+    with_session(|session| session.receive_suback(sensor_net, settings))
 }
 
-pub fn mqtt_sn_send_disconnect(sensor_net: &mut dyn SensorNetwork, settings: &Settings) {
-    let msg_type = MQTT_SN_DISCONNECT;
-    if settings.sleep_duration == 0 {
-        let length = 0x02;
-        let packet = DisconnectPacket {
-            length,
-            msg_type,
-            duration: 0,
-        };
-        info!("Sending DISCONNECT packet: {:?}", packet);
-        let _ = mqtt_sn_send_packet(sensor_net, &packet);
-    } else {
-        let length: u8 = 0x04;
-        let duration: u16 = settings.sleep_duration as u16;
-        let packet = DisconnectPacket {
-            length,
-            msg_type,
-            duration,
-        };
-        info!("Sending DISCONNECT packet: {:?}", packet);
-        let _ = mqtt_sn_send_packet(sensor_net, &packet);
-    }
+pub fn mqtt_sn_send_unsubscribe(sensor_net: &mut dyn SensorNetwork, topic: &Topic) -> Result<(), MqttSnError> {
+    with_session(|session| session.send_unsubscribe(sensor_net, topic))
 }
 
-pub fn mqtt_sn_receive_disconnect(sensor_net: &mut dyn SensorNetwork, settings: &Settings) {
-    mqtt_sn_wait_for(sensor_net, MQTT_SN_DISCONNECT, settings);
+pub fn mqtt_sn_receive_unsuback(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+    with_session(|session| session.receive_unsuback(sensor_net, settings))
 }
 
-pub fn mqtt_sn_send_publish(sensor_net: &mut dyn SensorNetwork, settings: &Settings, message: &str) {
-    // Check message length
-    const MAX_MESSAGE_LENGTH: usize = MQTT_SN_MAX_PACKET_LENGTH - 7;
-    if message.len() > MAX_MESSAGE_LENGTH {
-        panic!(
-            "Message is too long. Maximum length is {}",
-            MAX_MESSAGE_LENGTH
-        );
-    }
+pub fn mqtt_sn_unsubscribe_all(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+    with_session(|session| session.unsubscribe_all(sensor_net, settings))
+}
 
-    let msg_type = MQTT_SN_PUBLISH;
-    let mut flags = 0;
+pub fn mqtt_sn_send_disconnect(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+    with_session(|session| session.send_disconnect(sensor_net, settings))
+}
 
-    if settings.retain != false {
-        flags |= MQTT_SN_FLAG_RETAIN;
-    }
+pub fn mqtt_sn_receive_disconnect(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+    with_session(|session| session.receive_disconnect(sensor_net, settings))
+}
 
-    // Get QoS flags
-    let qos_flag: u8 = match settings.qos {
-        0 => MQTT_SN_FLAG_QOS_0,
-        1 => MQTT_SN_FLAG_QOS_1,
-        2 => MQTT_SN_FLAG_QOS_2,
-        _ => MQTT_SN_FLAG_QOS_N1,
+// Publishes to the topic in the settings, sending settings.message if
+// message is empty
+pub fn mqtt_sn_send_publish(sensor_net: &mut dyn SensorNetwork, settings: &Settings, message: &str) -> Result<(), MqttSnError> {
+    let message = if message.is_empty() { settings.message.as_str() } else { message };
+    let topic = match settings.topic_id_type {
+        TopicIdType::Predefined => Topic::TopicId(settings.topic_id),
+        _ => Topic::TopicName(settings.topic.as_bytes().to_vec()),
     };
+    with_session(|session| session.publish(sensor_net, settings, &topic, message))
+}
 
-    flags |= qos_flag;
-
-    // Topic type
-    let topic_id_type = settings.topic_id_type;
-    flags |= topic_id_type & 0x03;
+pub fn mqtt_sn_send_pubrel(sensor_net: &mut dyn SensorNetwork, message_id: u16) -> Result<(), MqttSnError> {
+    with_session(|session| session.send_pubrel(sensor_net, message_id))
+}
 
-    // Topic id
-    let topic_id = get_topic_id().to_be();
+pub fn mqtt_sn_receive_publish(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<PublishPacket, MqttSnError> {
+    with_session(|session| session.receive_publish(sensor_net, settings))
+}
 
-    // Message ID
-    let mut message_id: u16 = 0;
-    if settings.qos > 0 {
-        // Increment the message ID
-        message_id = get_next_message_id();
-    } else {
-        // Set the message ID to 0
-        reset_message_id();
-    }
-    let default_message = settings.message.clone();
-    let mut data = default_message.as_str();
-    // Get message
-    if message != "" {
-        data = message;
-    }
+pub fn mqtt_sn_send_puback(sensor_net: &mut dyn SensorNetwork, packet: &PublishPacket, return_code: u8) -> Result<(), MqttSnError> {
+    with_session(|session| session.send_puback(sensor_net, packet, return_code))
+}
 
-    // Get the packet length
-    let length = 0x07 + data.len() as u8;
-
-    // Assemble the packet
-    let packet = PublishPacket {
-        length,
-        msg_type,
-        flags,
-        topic_id,
-        message_id: message_id,
-        data: data.as_bytes().to_vec(),
-    };
+pub fn mqtt_sn_handle_qos2_publish(sensor_net: &mut dyn SensorNetwork, settings: &Settings, packet: &PublishPacket) -> Result<(), MqttSnError> {
+    with_session(|session| session.handle_qos2_publish(sensor_net, settings, packet))
+}
 
-    info!("Sending PUBLISH packet: {:?}", packet);
-    let _ = mqtt_sn_send_packet(sensor_net, &packet);
-
-    if settings.qos == 1 {
-        // Wait for PUBACK
-        let packet = mqtt_sn_wait_for(sensor_net, MQTT_SN_PUBACK, settings);
-        match packet {
-            Some(real_packet) => {
-                if real_packet.msg_type() == MQTT_SN_PUBACK {
-                    let puback = real_packet.as_puback().unwrap();
-                    info!("Received PUBACK packet: {:?}", puback);
-                }
-            }
-            None => {
-                warn!("Failed to receive PUBACK packet");
-            }
-        }
-    }
+pub fn mqtt_sn_send_pubrec(sensor_net: &mut dyn SensorNetwork, message_id: u16) -> Result<(), MqttSnError> {
+    with_session(|session| session.send_pubrec(sensor_net, message_id))
 }
 
-pub fn mqtt_sn_receive_publish(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Option<PublishPacket> {
-    let packet = mqtt_sn_wait_for(sensor_net, MQTT_SN_PUBLISH, settings);
-    match packet {
-        Some(publish) => {
-            if let Some(publish) = publish.as_publish() {
-                mqtt_sn_print_publish_packet(&publish, settings);
-                Some(publish.clone())
-            } else {
-                warn!("Received packet is not a PUBLISH packet");
-                None
-            }
-        }
-        None => {
-            warn!("Timeout reached. Retrying...");
-            None
-        }
-    }
+pub fn mqtt_sn_receive_pubrel(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<PubrelPacket, MqttSnError> {
+    with_session(|session| session.receive_pubrel(sensor_net, settings))
 }
 
-pub fn mqtt_sn_send_puback(
-    sensor_net: &mut dyn SensorNetwork,
-    packet: &PublishPacket,
-    return_code: u8,
-) {
-    let msg_type = MQTT_SN_PUBACK;
-    let message_id = packet.message_id;
-    let topic_id = packet.topic_id;
+pub fn mqtt_sn_send_pubcomp(sensor_net: &mut dyn SensorNetwork, message_id: u16) -> Result<(), MqttSnError> {
+    with_session(|session| session.send_pubcomp(sensor_net, message_id))
+}
 
-    let length = 0x07;
+pub fn mqtt_sn_go_to_sleep(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+    with_session(|session| session.go_to_sleep(sensor_net, settings))
+}
 
-    let packet = PubackPacket {
-        length,
-        msg_type,
-        topic_id,
-        message_id,
-        return_code,
-    };
+pub fn mqtt_sn_send_wakeup_pingreq(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+    with_session(|session| session.send_wakeup_pingreq(sensor_net, settings))
+}
 
-    info!("Sending PUBACK packet: {:?}", packet);
-    let _ = mqtt_sn_send_packet(sensor_net, &packet);
+pub fn mqtt_sn_wake_up(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<Vec<PublishPacket>, MqttSnError> {
+    with_session(|session| session.wake_up(sensor_net, settings))
 }
 
-fn mqtt_sn_send_pingreq(sensor_net: &mut dyn SensorNetwork) {
-    let msg_type = MQTT_SN_PINGREQ;
-    let length = 0x02;
-    let packet = PingreqPacket { length, msg_type };
-    info!("Sending PINGREQ packet: {:?}", packet);
-    let _ = mqtt_sn_send_packet(sensor_net, &packet);
+pub fn mqtt_sn_sleep_cycle(sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<Vec<PublishPacket>, MqttSnError> {
+    with_session(|session| session.sleep_cycle(sensor_net, settings))
 }
@@ -0,0 +1,2414 @@
+// This module defines the state of an MQTT-SN session, and the logic
+// sending and receiving packets on its behalf.
+
+use log::{debug, error, info, warn, LevelFilter};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::str;
+use std::time::{Duration, Instant};
+use chrono::prelude::*;
+
+use crate::mqttsn::constants::{
+    //Message types
+    MQTT_SN_CONNACK,
+    MQTT_SN_CONNECT,
+    MQTT_SN_DISCONNECT,
+    MQTT_SN_FLAG_DUP,
+    MQTT_SN_FRWDENCAP,
+    MQTT_SN_FRWDENCAP_RADIUS_MASK,
+    MQTT_SN_MAX_CLIENT_ID_LENGTH,
+    MQTT_SN_MAX_LONG_PACKET_LENGTH,
+    MQTT_SN_MAX_PACKET_LENGTH,
+    MQTT_SN_MAX_WIRELESS_NODE_ID_LENGTH,
+    MQTT_SN_PINGREQ,
+    MQTT_SN_PINGRESP,
+    MQTT_SN_PROTOCOL_ID,
+    MQTT_SN_PUBACK,
+    MQTT_SN_PUBCOMP,
+    MQTT_SN_PUBLISH,
+    MQTT_SN_PUBREC,
+    MQTT_SN_PUBREL,
+    MQTT_SN_REGACK,
+    MQTT_SN_REGISTER,
+    MQTT_SN_SEARCHGW,
+    MQTT_SN_SUBACK,
+    MQTT_SN_SUBSCRIBE,
+    MQTT_SN_UNSUBACK,
+    MQTT_SN_UNSUBSCRIBE,
+    MQTT_SN_WILLMSG,
+    MQTT_SN_WILLMSGREQ,
+    MQTT_SN_WILLMSGRESP,
+    MQTT_SN_WILLMSGUPD,
+    MQTT_SN_WILLTOPIC,
+    MQTT_SN_WILLTOPICREQ,
+    MQTT_SN_WILLTOPICRESP,
+    MQTT_SN_WILLTOPICUPD,
+    MQTT_SN_ACCEPTED,
+};
+
+use crate::mqttsn::packet_types::{
+    //Packet types
+    mqtt_sn_decode_length,
+    mqtt_sn_packet_length,
+    mqtt_sn_packet_type_to_str,
+    FWDEncapPacket,
+    ConnackPacket,
+    ConnectPacket,
+    DisconnectPacket,
+    MqttSnPacket,
+    Packet,
+    PingreqPacket,
+    PubackPacket,
+    PubcompPacket,
+    PublishPacket,
+    PubrecPacket,
+    PubrelPacket,
+    RegackPacket,
+    RegisterPacket,
+    ReturnCode,
+    SearchgwPacket,
+    SubscribePacket,
+    Topic,
+    UnsubscribePacket,
+    WillmsgPacket,
+    WillmsgrespPacket,
+    WillmsgupdPacket,
+    WilltopicPacket,
+    WilltopicrespPacket,
+    WilltopicupdPacket,
+};
+
+use crate::mqttsn::error::MqttSnError;
+
+use crate::mqttsn::settings::Settings;
+use crate::mqttsn::flags::{Flags, QoS, TopicIdType};
+
+use crate::mqttsn::network_abstractions::{PeerAddress, SensorNetwork};
+
+use crate::mqttsn::pubsub::{mqtt_sn_rebuild_packet, mqtt_sn_validate_packet, GatewayInfo};
+
+
+// Everything a client has to remember between packets. Each session talks
+// to one gateway, so a process can run several of them side by side.
+#[derive(Debug, Default)]
+pub struct Session {
+    // Last message ID given to a packet
+    message_id: u16,
+    // Topic IDs and names known to the gateway, registered by either side
+    topics: HashMap<u16, String>,
+    // Topic names sent in a REGISTER, by message ID, until the REGACK arrives
+    registering: HashMap<u16, String>,
+    wireless_node_id: Vec<u8>,
+    // Ctrl byte of the FRWDENCAP header, set when outgoing packets must be encapsulated
+    forwarder_ctrl: Option<u8>,
+    // Message IDs of QoS 2 PUBLISH packets already delivered, waiting for PUBREL
+    qos2_received: HashSet<u16>,
+    // Packets waiting for an acknowledgement, resent by wait_for
+    retransmissions: Vec<Retransmission>,
+    connected: bool,
+}
+
+// A sent packet waiting for one of the `replies` message types, carrying
+// the same message ID if the packet has one
+#[derive(Debug, Clone)]
+pub struct Retransmission {
+    pub packet: Vec<u8>,
+    pub message_id: Option<u16>,
+    pub replies: Vec<u8>,
+    pub retries: u8,
+    pub last_sent: Instant,
+    // Set when a congested gateway asked for the packet again after T_WAIT
+    pub resend_at: Option<Instant>,
+}
+
+// Session state
+impl Session {
+    pub fn new() -> Session {
+        Session::default()
+    }
+
+    pub fn next_message_id(&mut self) -> u16 {
+        // 0 is reserved for QoS 0 and -1 publishes
+        self.message_id = self.message_id.checked_add(1).unwrap_or(1);
+        self.message_id
+    }
+
+    pub fn last_message_id(&self) -> u16 {
+        self.message_id
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    // ID the gateway gave to a topic name, if it was registered
+    pub fn topic_id(&self, topic_name: &str) -> Option<u16> {
+        self.topics
+            .iter()
+            .find(|(_, name)| name.as_str() == topic_name)
+            .map(|(topic_id, _)| *topic_id)
+    }
+
+    pub fn topic_name(&self, topic_id: u16) -> Option<&str> {
+        self.topics.get(&topic_id).map(|name| name.as_str())
+    }
+
+    // A topic name only keeps its latest ID
+    fn set_topic(&mut self, topic_id: u16, topic_name: String) {
+        self.topics.retain(|_, name| *name != topic_name);
+        self.topics.insert(topic_id, topic_name);
+    }
+
+    pub fn wireless_node_id(&self) -> &[u8] {
+        &self.wireless_node_id
+    }
+
+    fn set_wireless_node_id(&mut self, wireless_node_id: Vec<u8>) {
+        let mut wireless_node_id = wireless_node_id;
+        wireless_node_id.truncate(MQTT_SN_MAX_WIRELESS_NODE_ID_LENGTH);
+        self.wireless_node_id = wireless_node_id;
+    }
+
+    // Only one packet is pending per kind of reply
+    fn push_retransmission(&mut self, retransmission: Retransmission) {
+        self.retransmissions.retain(|r| r.replies != retransmission.replies);
+        self.retransmissions.push(retransmission);
+    }
+
+    // Removes the packet acknowledged by a message of type msg_type with the
+    // given message ID
+    fn acknowledge_retransmission(&mut self, msg_type: u8, message_id: Option<u16>) -> Option<Retransmission> {
+        let index = self
+            .retransmissions
+            .iter()
+            .position(|r| r.replies.contains(&msg_type) && r.message_id == message_id)?;
+        Some(self.retransmissions.remove(index))
+    }
+
+    // Removes and returns the packets not acknowledged within retry_timeout,
+    // or whose T_WAIT is over
+    fn take_expired_retransmissions(&mut self, retry_timeout: Duration) -> Vec<Retransmission> {
+        let now = Instant::now();
+        let (expired, waiting) = self
+            .retransmissions
+            .drain(..)
+            .partition(|r| match r.resend_at {
+                Some(resend_at) => now >= resend_at,
+                None => r.last_sent.elapsed() >= retry_timeout,
+            });
+        self.retransmissions = waiting;
+        expired
+    }
+
+    // Whether a packet is held back until a congested gateway takes requests again
+    fn congestion_pending(&self) -> bool {
+        self.retransmissions.iter().any(|r| r.resend_at.is_some())
+    }
+}
+
+// Answers matched to their request by message ID
+const MQTT_SN_ACKNOWLEDGEMENTS: [u8; 6] = [
+    MQTT_SN_REGACK,
+    MQTT_SN_SUBACK,
+    MQTT_SN_UNSUBACK,
+    MQTT_SN_PUBACK,
+    MQTT_SN_PUBREC,
+    MQTT_SN_PUBCOMP,
+];
+
+// Protocol logic
+impl Session {
+    // Generic send and receive functions
+
+    pub fn send_packet(&mut self, sensor_net: &mut dyn SensorNetwork, packet: &MqttSnPacket) -> Result<(), MqttSnError> {
+        // Short packets are encoded on the stack, saving an allocation per send
+        let mut buffer = [0u8; MQTT_SN_MAX_PACKET_LENGTH];
+        match packet.encode_to_slice(&mut buffer) {
+            Ok(length) => self.send_packet_bytes(sensor_net, &buffer[..length]),
+            Err(_) => self.send_packet_bytes(sensor_net, &packet.as_bytes()),
+        }
+    }
+
+    fn send_packet_bytes(&mut self, sensor_net: &mut dyn SensorNetwork, packet_bytes: &[u8]) -> Result<(), MqttSnError> {
+        // Use the length to get the bytes
+        let (packet_length, _) = mqtt_sn_decode_length(packet_bytes).unwrap_or((0, 0));
+        let packet_length = (packet_length as usize).min(packet_bytes.len());
+        let safe_buffer: &[u8] = &packet_bytes[0..packet_length];
+
+        if packet_length > self.max_packet_length(sensor_net) {
+            return Err(MqttSnError::InvalidArgument(format!(
+                "Packet is too long for the network: {} bytes",
+                packet_length
+            )));
+        }
+
+        // Wrap the packet when talking to the gateway through a forwarder
+        if let Some(ctrl) = self.forwarder_ctrl {
+            let frwdencap = self.create_frwdencap_packet(ctrl, safe_buffer);
+            debug!("Encapsulating packet: {:?}", frwdencap);
+            return mqtt_sn_send_bytes(sensor_net, &frwdencap.as_bytes());
+        }
+
+        mqtt_sn_send_bytes(sensor_net, safe_buffer)
+    }
+
+    // Retransmission
+    //
+    // Packets the gateway must answer are sent with send_acknowledged.
+    // If no answer arrives within settings.network_timeout milliseconds
+    // (Tretry), wait_for sends them again, up to settings.network_retries
+    // times (Nretry).
+
+    fn send_acknowledged(&mut self, sensor_net: &mut dyn SensorNetwork, packet: &MqttSnPacket, replies: &[u8]) -> Result<(), MqttSnError> {
+        let packet_bytes = packet.as_bytes();
+        match self.send_packet_bytes(sensor_net, &packet_bytes) {
+            Ok(()) => {}
+            // Even if sending failed, the retry will give it another go
+            Err(MqttSnError::Transport(e)) => warn!("Failed to send packet, retrying later: {}", e),
+            Err(e) => return Err(e),
+        }
+        self.push_retransmission(Retransmission {
+            packet: packet_bytes,
+            message_id: packet.message_id(),
+            replies: replies.to_vec(),
+            retries: 0,
+            last_sent: std::time::Instant::now(),
+            resend_at: None,
+        });
+        Ok(())
+    }
+
+    // Resends the packets whose answer is overdue. Fails once a packet has
+    // been resent settings.network_retries times without an answer.
+    fn retransmit(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+        let retry_timeout = std::time::Duration::from_millis(settings.network_timeout);
+        let mut result = Ok(());
+        for mut retransmission in self.take_expired_retransmissions(retry_timeout) {
+            if retransmission.resend_at.take().is_some() {
+                // T_WAIT is over, this is not a retry of a lost packet
+                info!(
+                    "Sending {} packet again after congestion",
+                    mqtt_sn_packet_type_to_str(mqtt_sn_packet_msg_type(&retransmission.packet))
+                );
+                let _ = self.send_packet_bytes(sensor_net, &retransmission.packet);
+                retransmission.last_sent = std::time::Instant::now();
+                self.push_retransmission(retransmission);
+                continue;
+            }
+            let msg_type = mqtt_sn_set_dup_flag(&mut retransmission.packet);
+            if retransmission.retries >= settings.network_retries {
+                error!(
+                    "No answer to {} packet after {} retries",
+                    mqtt_sn_packet_type_to_str(msg_type),
+                    retransmission.retries
+                );
+                result = Err(MqttSnError::Timeout);
+                continue;
+            }
+            retransmission.retries += 1;
+            warn!(
+                "Resending {} packet ({} of {})",
+                mqtt_sn_packet_type_to_str(msg_type),
+                retransmission.retries,
+                settings.network_retries
+            );
+            let _ = self.send_packet_bytes(sensor_net, &retransmission.packet);
+            retransmission.last_sent = std::time::Instant::now();
+            self.push_retransmission(retransmission);
+        }
+        result
+    }
+
+    // Schedules a rejected request to be sent again by retransmit after
+    // settings.t_wait seconds. It counts as one of its
+    // settings.network_retries retries.
+    fn resend_after_congestion(&mut self, settings: &Settings, mut retransmission: Retransmission) {
+        warn!(
+            "Gateway is congested, resending {} packet in {} seconds",
+            mqtt_sn_packet_type_to_str(mqtt_sn_packet_msg_type(&retransmission.packet)),
+            settings.t_wait
+        );
+        retransmission.retries += 1;
+        retransmission.resend_at = Some(Instant::now() + Duration::from_secs(settings.t_wait));
+        self.push_retransmission(retransmission);
+    }
+
+    // Forwarder encapsulation
+
+    // Enables forwarder encapsulation for every packet sent from now on,
+    // using settings.wireless_node_id and settings.forwarder_radius.
+    pub fn enable_frwdencap(&mut self, settings: &Settings) {
+        self.set_wireless_node_id(settings.wireless_node_id.to_be_bytes().to_vec());
+        self.forwarder_ctrl = Some(settings.forwarder_radius & MQTT_SN_FRWDENCAP_RADIUS_MASK);
+    }
+
+    pub fn disable_frwdencap(&mut self) {
+        self.forwarder_ctrl = None;
+    }
+
+    pub fn create_frwdencap_packet(&self, ctrl: u8, inner_packet: &[u8]) -> FWDEncapPacket {
+        let wireless_node_id = self.wireless_node_id.clone();
+        // The length only covers the encapsulation header, the inner packet
+        // carries its own length
+        FWDEncapPacket {
+            length: 3 + wireless_node_id.len() as u16,
+            msg_type: MQTT_SN_FRWDENCAP,
+            ctrl,
+            wireless_node_id,
+            inner_packet: inner_packet.to_vec(),
+        }
+    }
+
+    // Size limits
+
+    // Largest packet the network can carry, after the forwarder encapsulation
+    // header if enabled. Above MQTT_SN_MAX_PACKET_LENGTH the packet needs the
+    // three byte length field.
+    pub fn max_packet_length(&self, sensor_net: &dyn SensorNetwork) -> usize {
+        let mut max_length = sensor_net.get_max_packet_length().min(MQTT_SN_MAX_LONG_PACKET_LENGTH);
+        if self.forwarder_ctrl.is_some() {
+            max_length = max_length.saturating_sub(3 + self.wireless_node_id.len());
+        }
+        max_length
+    }
+
+    // Longest topic name that fits in a REGISTER packet
+    pub fn max_topic_length(&self, sensor_net: &dyn SensorNetwork) -> usize {
+        let max_length = self.max_packet_length(sensor_net);
+        if max_length > MQTT_SN_MAX_PACKET_LENGTH {
+            max_length - 8
+        } else {
+            max_length.saturating_sub(6)
+        }
+    }
+
+    // Longest payload that fits in a PUBLISH packet
+    pub fn max_payload_length(&self, sensor_net: &dyn SensorNetwork) -> usize {
+        let max_length = self.max_packet_length(sensor_net);
+        if max_length > MQTT_SN_MAX_PACKET_LENGTH {
+            max_length - 9
+        } else {
+            max_length.saturating_sub(7)
+        }
+    }
+
+    pub fn print_publish_packet(&self, packet: &PublishPacket, settings: &Settings) {
+        if settings.verbose_time {
+            let time = Local::now();
+            print!("{} - ", time);
+        }
+
+        if settings.verbose {
+            let topic_id = packet.topic_id;
+            match packet.flags.topic_id_type() {
+                TopicIdType::Short => {
+                    let topic_name = topic_id.to_be_bytes();
+                    print!("{}{}: ", topic_name[0] as char, topic_name[1] as char);
+                }
+                TopicIdType::Predefined => {
+                    print!("{:04x}: ", topic_id);
+                }
+                _ => {
+                    // Topic names registered by the gateway take precedence
+                    match self.topic_name(topic_id).or(settings.topic_map.get(&topic_id).map(|name| name.as_str())) {
+                        Some(topic_name) => print!("{}: ", topic_name),
+                        None => print!("{}: ", topic_id),
+                    }
+                }
+            }
+        }
+        println!("{}", String::from_utf8_lossy(&packet.data));
+    }
+
+    pub fn wait_for(
+        &mut self,
+        sensor_net: &mut dyn SensorNetwork,
+        packet_type: u8,
+        settings: &Settings,
+    ) -> Result<MqttSnPacket, MqttSnError> {
+        // Save current time to calculate next keep alive
+        let mut start = std::time::Instant::now();
+        let mut last_transmission = start;
+
+        let mut connected = true;
+        if packet_type == MQTT_SN_CONNACK
+            || packet_type == MQTT_SN_WILLTOPICREQ
+            || packet_type == MQTT_SN_WILLMSGREQ
+        {
+            connected = false;
+        }
+
+        debug!("Start waiting for {} packet", mqtt_sn_packet_type_to_str(packet_type));
+
+        loop {
+            // Send a PINGREQ packet if keep alive is enabled, but only if connected
+            if settings.keep_alive > 0 && connected
+                && last_transmission.elapsed().as_secs() >= settings.keep_alive as u64
+            {
+                // Send a PINGREQ packet
+                debug!("Sending PINGREQ packet");
+                if let Err(e) = self.send_pingreq(sensor_net) {
+                    warn!("{}", e);
+                }
+                last_transmission = std::time::Instant::now();
+            }
+            // The timeout only runs while the gateway takes requests
+            if self.congestion_pending() {
+                start = std::time::Instant::now();
+            }
+            // Resend what the gateway has not answered yet
+            self.retransmit(sensor_net, settings)?;
+            // Receive a packet
+            let packet = self.receive_frwdencap_packet(sensor_net, settings);
+
+            match packet {
+                Ok(packet) =>{
+                    let safe_packet = packet;
+                    let message_id = safe_packet.message_id();
+                    match self.acknowledge_retransmission(safe_packet.msg_type(), message_id) {
+                        Some(acknowledged) => {
+                            debug!("Acknowledged after {} retries", acknowledged.retries);
+                            // A congested gateway wants the request again after T_WAIT.
+                            // connect restarts the whole CONNECT handshake itself.
+                            if safe_packet.return_code() == Some(ReturnCode::RejectedCongestion)
+                                && safe_packet.msg_type() != MQTT_SN_CONNACK
+                                && acknowledged.retries < settings.network_retries
+                            {
+                                self.resend_after_congestion(settings, acknowledged);
+                                continue;
+                            }
+                        }
+                        // Late answers to earlier requests must not pass for the current one
+                        None if MQTT_SN_ACKNOWLEDGEMENTS.contains(&safe_packet.msg_type()) => {
+                            warn!(
+                                "Ignoring {} packet with unmatched message ID: {}",
+                                mqtt_sn_packet_type_to_str(safe_packet.msg_type()),
+                                message_id.unwrap_or(0)
+                            );
+                            continue;
+                        }
+                        None => {}
+                    }
+                    if safe_packet.msg_type() == packet_type {
+                        debug!("Received expected packet: {:?}", safe_packet);
+                        return Ok(safe_packet);
+                    }
+                    match safe_packet {
+                        MqttSnPacket::Disconnect(disconnect) => {
+                            info!("Received DISCONNECT packet from gateway: {:?}", disconnect);
+                            self.connected = false;
+                            return Err(MqttSnError::Disconnected);
+                        }
+                        MqttSnPacket::Register(register) => {
+                            // The gateway is telling us the topic ID of a topic name,
+                            // usually one matching a wildcard subscription
+                            if let Err(e) = self.receive_register(sensor_net, &register) {
+                                warn!("{}", e);
+                            }
+                        }
+                        MqttSnPacket::Pubrel(pubrel) => {
+                            // A late PUBREL for a QoS 2 message, complete the handshake anyway
+                            debug!("Received PUBREL packet outside of handshake: {:?}", pubrel);
+                            if let Err(e) = self.release_qos2_publish(sensor_net, pubrel.message_id) {
+                                warn!("{}", e);
+                            }
+                        }
+                        MqttSnPacket::Pingresp(_) => {
+                            // Answer to a keep alive PINGREQ, already acknowledged above
+                            debug!("Received PINGRESP packet");
+                        }
+                        MqttSnPacket::Publish(publish)
+                            if publish.flags.dup()
+                                && publish.flags.qos() == QoS::ExactlyOnce
+                                && self.qos2_received.contains(&publish.message_id) =>
+                        {
+                            // Our PUBREC got lost, the gateway is still waiting for it
+                            debug!("Received duplicate QoS 2 PUBLISH with message ID {}", publish.message_id);
+                            if let Err(e) = self.send_pubrec(sensor_net, publish.message_id) {
+                                warn!("{}", e);
+                            }
+                        }
+                        _ => {
+                            warn!(
+                                "Was expecting {} packet but received {}",
+                                mqtt_sn_packet_type_to_str(packet_type),
+                                mqtt_sn_packet_type_to_str(safe_packet.msg_type())
+                            );
+                        }
+                    }
+                }
+                // Read timeouts are routine, they pace the retransmissions
+                Err(MqttSnError::Timeout) => {
+                    debug!("Network timeout reached while waiting for packet");
+                }
+                // The gateway closed the connection, nothing more will arrive
+                Err(MqttSnError::Transport(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    warn!("{}", e);
+                    return Err(MqttSnError::Disconnected);
+                }
+                Err(e) => {
+                    warn!("Failed to receive packet: {}", e);
+                }
+            }
+
+            // Check if the timeout has been reached
+            if settings.timeout > 0 && start.elapsed().as_secs() >= settings.timeout {
+                warn!("Timeout reached while waiting for packet");
+                info!("Timeout: {}", settings.timeout);
+                return Err(MqttSnError::Timeout);
+            }
+        }
+    }
+
+    pub fn receive_frwdencap_packet(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<MqttSnPacket, MqttSnError> {
+        self.receive_frwdencap_packet_from(sensor_net, settings)
+            .map(|(packet, _)| packet)
+    }
+
+    // Also returns who sent the packet: the wireless node for encapsulated
+    // packets, the network peer otherwise.
+    pub fn receive_frwdencap_packet_from(
+        &mut self,
+        sensor_net: &mut dyn SensorNetwork,
+        settings: &Settings,
+    ) -> Result<(MqttSnPacket, Option<PeerAddress>), MqttSnError> {
+        // Create a buffer to hold the data, as long as the network can carry
+        // but never over the maximun size given by:
+        // MQTT_SN_MAX_LONG_PACKET_LENGTH
+        // MQTT_SN_MAX_WIRELESS_NODE_ID_LENGTH
+        // + 4 (for the protocol overhead)
+        const MAX_SIZE: usize = MQTT_SN_MAX_LONG_PACKET_LENGTH + MQTT_SN_MAX_WIRELESS_NODE_ID_LENGTH + 4;
+
+        debug!("Waiting to receive packet...");
+
+        // Read the packet into the buffer safely, longer ones are truncated
+        let mut buffer = vec![0u8; sensor_net.get_max_packet_length().min(MAX_SIZE)];
+        let (size, sender) = sensor_net.receive_from(&mut buffer)?;
+        let buffer = &buffer[..size];
+        if buffer.len() < 2 {
+            return Err(MqttSnError::ProtocolViolation(String::from("Received packet is too short")));
+        }
+
+        // The length field is one byte, or three for long packets
+        let (length, header_length) = mqtt_sn_decode_length(buffer).unwrap_or((0, 1));
+        let bytes_read = length as usize;
+
+        debug!("Received {} bytes", bytes_read);
+
+        if bytes_read == 0 {
+            return Err(MqttSnError::ProtocolViolation(String::from("Received packet has no length")));
+        }
+        let packet_type = buffer[header_length.min(buffer.len() - 1)];
+
+        let log_level = log::max_level();
+        if log_level >= LevelFilter::Debug {
+            // Check if the packet is a forwarder encapsulation packet
+            if packet_type == MQTT_SN_FRWDENCAP && buffer.len() > bytes_read + 1 {
+                // The inner packet may use the long length form too
+                let inner_header_length = mqtt_sn_decode_length(&buffer[bytes_read..])
+                    .map(|(_, inner_header_length)| inner_header_length)
+                    .unwrap_or(1);
+                let inner_packet_type = buffer.get(bytes_read + inner_header_length).copied().unwrap_or(0xFF);
+                debug!(
+                    "Received {} bytes from sensor_net. Packet type {} -> {}",
+                    buffer.len(),
+                    mqtt_sn_packet_type_to_str(packet_type),
+                    mqtt_sn_packet_type_to_str(inner_packet_type),
+                );
+            } else {
+                debug!(
+                    "Received {} bytes from sensor_net. Packet type {}",
+                    bytes_read.min(buffer.len()),
+                    mqtt_sn_packet_type_to_str(packet_type),
+                );
+            }
+        }
+
+        // Validate the packet
+        let generic_packet = mqtt_sn_validate_packet(buffer, settings)?;
+        debug!("Packet validated");
+
+        match generic_packet {
+            MqttSnPacket::Frwdencap(frwencap_packet) => {
+                // Forwarder encapsulation packet;
+                // Rebuild the packet. The node it came from is only reported,
+                // our own wireless node ID stays the configured one.
+                let sender = PeerAddress::WirelessNode(frwencap_packet.wireless_node_id);
+                // Return the inner packet
+                Ok((mqtt_sn_rebuild_packet(&frwencap_packet.inner_packet)?, Some(sender)))
+            }
+            // Regular packet
+            packet => Ok((packet, sender)),
+        }
+    }
+
+    pub fn receive_packet(&mut self, sensor_net: &mut dyn SensorNetwork) -> Result<MqttSnPacket, MqttSnError> {
+        info!("Waiting to receive packet...");
+
+        // Read the packet into the buffer safely
+        let mut buffer = vec![0u8; sensor_net.get_max_packet_length().min(MQTT_SN_MAX_LONG_PACKET_LENGTH)];
+        let size = sensor_net.receive_into(&mut buffer)?;
+        let mut buffer = &buffer[..size];
+        if buffer.is_empty() {
+            return Err(MqttSnError::ProtocolViolation(String::from("Received packet is empty")));
+        }
+
+        // Check the number of bytes read
+        let (length, _) = mqtt_sn_decode_length(buffer).unwrap_or((0, 1));
+        let mut bytes_read = length as usize;
+
+        let max_length = self.max_packet_length(sensor_net);
+        if bytes_read > max_length {
+            error!("Received packet is too long: {}", bytes_read);
+            warn!("Truncating packet to {} bytes", max_length);
+            bytes_read = max_length;
+            buffer = &buffer[..bytes_read.min(buffer.len())];
+        }
+
+        debug!("Received {} bytes", bytes_read);
+
+        if bytes_read == 0 {
+            return Err(MqttSnError::ProtocolViolation(String::from("Received packet has no length")));
+        }
+
+        // Rebuild the packet
+        mqtt_sn_rebuild_packet(buffer)
+    }
+
+    // Specific send and receive functions
+
+    // Gateway discovery
+
+    // Broadcasts a SEARCHGW and collects GWINFO and ADVERTISE replies until
+    // settings.discovery_timeout expires. The first gateway answering the
+    // SEARCHGW is picked, falling back to the first one heard advertising.
+    pub fn discover_gateway(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<GatewayInfo, MqttSnError> {
+        self.send_searchgw(sensor_net, settings.search_radius)?;
+
+        let start = std::time::Instant::now();
+        let mut gateways: Vec<GatewayInfo> = Vec::new();
+
+        while start.elapsed().as_secs() < settings.discovery_timeout {
+            let (packet, sender) = match self.receive_frwdencap_packet_from(sensor_net, settings) {
+                Ok(received) => received,
+                // Keep listening until the discovery time is over
+                Err(MqttSnError::Timeout) => continue,
+                Err(e @ (MqttSnError::Decode(_) | MqttSnError::ProtocolViolation(_))) => {
+                    warn!("Ignoring packet during gateway discovery: {}", e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let gateway = match packet {
+                MqttSnPacket::Gwinfo(gwinfo) => {
+                    info!("Received GWINFO packet: {:?}", gwinfo);
+                    // A gateway answers with its own address, a client
+                    // answering for it with an empty one
+                    let address = if gwinfo.gw_add.is_empty() {
+                        sender
+                    } else {
+                        Some(PeerAddress::from_gw_add(&gwinfo.gw_add))
+                    };
+                    GatewayInfo {
+                        gw_id: gwinfo.gw_id,
+                        address,
+                        advertised: false,
+                    }
+                }
+                MqttSnPacket::Advertise(advertise) => {
+                    info!("Received ADVERTISE packet: {:?}", advertise);
+                    GatewayInfo {
+                        gw_id: advertise.gw_id,
+                        address: sender,
+                        advertised: true,
+                    }
+                }
+                packet => {
+                    debug!(
+                        "Ignoring {} packet during gateway discovery",
+                        mqtt_sn_packet_type_to_str(packet.msg_type())
+                    );
+                    continue;
+                }
+            };
+
+            if gateways.iter().any(|known| known.gw_id == gateway.gw_id) {
+                continue;
+            }
+            info!("Found gateway {} at {:?}", gateway.gw_id, gateway.address);
+            gateways.push(gateway);
+        }
+
+        let chosen = gateways
+            .iter()
+            .find(|gateway| !gateway.advertised)
+            .or(gateways.first())
+            .cloned();
+
+        chosen.ok_or_else(|| {
+            warn!("No gateway found");
+            MqttSnError::Timeout
+        })
+    }
+
+    pub fn send_searchgw(&mut self, sensor_net: &mut dyn SensorNetwork, radius: u8) -> Result<(), MqttSnError> {
+        let msg_type = MQTT_SN_SEARCHGW;
+        let length = 0x03;
+
+        let packet = MqttSnPacket::Searchgw(SearchgwPacket {
+            length,
+            msg_type,
+            radius,
+        });
+
+        info!("Sending SEARCHGW packet: {:?}", packet);
+        self.send_packet(sensor_net, &packet)
+    }
+
+    // Connection wrapper
+
+    pub fn connect(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+        let mut attempts = 0;
+        self.connected = false;
+        // Whatever was waiting for an answer belongs to the previous connection
+        self.retransmissions.clear();
+        self.registering.clear();
+        // Send a CONNECT packet, resent by wait_for if unanswered
+        self.send_connect(sensor_net, settings, settings.clean_session)?;
+        loop {
+            // Answer the gateway's will prompts, if a will was requested
+            if !settings.will_topic.is_empty() {
+                self.send_will(sensor_net, settings)?;
+            }
+            // Receive a CONNACK packet
+            let connack = self.receive_connack(sensor_net, settings)?;
+            match ReturnCode::from(connack.return_code) {
+                ReturnCode::Accepted => {
+                    self.connected = true;
+                    return Ok(());
+                }
+                ReturnCode::RejectedCongestion if attempts < settings.network_retries => {
+                    // wait_for sends the CONNECT again once T_WAIT is over
+                    let packet = self.connect_packet(settings, settings.clean_session)?;
+                    self.resend_after_congestion(settings, Retransmission {
+                        packet: packet.as_bytes(),
+                        message_id: None,
+                        replies: vec![MQTT_SN_CONNACK, MQTT_SN_WILLTOPICREQ],
+                        retries: attempts,
+                        last_sent: Instant::now(),
+                        resend_at: None,
+                    });
+                    attempts += 1;
+                }
+                return_code => {
+                    return Err(MqttSnError::Rejected { msg_type: MQTT_SN_CONNECT, return_code });
+                }
+            }
+        }
+    }
+
+    pub fn send_connect(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings, clean_session: bool) -> Result<(), MqttSnError> {
+        let packet = self.connect_packet(settings, clean_session)?;
+        info!("Sending CONNECT {:?}", packet);
+        self.send_acknowledged(sensor_net, &packet, &[MQTT_SN_CONNACK, MQTT_SN_WILLTOPICREQ])
+    }
+
+    fn connect_packet(&self, settings: &Settings, clean_session: bool) -> Result<MqttSnPacket, MqttSnError> {
+        // Check client id length
+        if settings.client_id.len() > MQTT_SN_MAX_CLIENT_ID_LENGTH {
+            return Err(MqttSnError::InvalidArgument(format!(
+                "Client ID is too long. Maximum length is {}",
+                MQTT_SN_MAX_CLIENT_ID_LENGTH
+            )));
+        }
+
+        let msg_type = MQTT_SN_CONNECT;
+        let flags = Flags::new()
+            .with_clean_session(clean_session)
+            .with_will(!settings.will_topic.is_empty());
+        let protocol_id = MQTT_SN_PROTOCOL_ID;
+        let duration = settings.keep_alive.to_be();
+
+        // Copy the client ID into the packet
+        let client_id = settings.client_id.as_bytes().to_vec();
+
+        // Get the length of the packet
+        let length = mqtt_sn_packet_length(0x06 + settings.client_id.len());
+
+        // Assemble the packet
+        Ok(MqttSnPacket::Connect(ConnectPacket {
+            length,
+            msg_type,
+            flags,
+            protocol_id,
+            duration,
+            client_id,
+        }))
+    }
+
+    pub fn receive_connack(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<Box<ConnackPacket>, MqttSnError> {
+        let packet = self.wait_for(sensor_net, MQTT_SN_CONNACK, settings)?;
+        let connack = match packet {
+            MqttSnPacket::Connack(connack) => connack,
+            packet => return Err(mqtt_sn_unexpected_packet(MQTT_SN_CONNACK, &packet)),
+        };
+        info!("Received CONNACK packet: {:?}", connack);
+        Ok(Box::new(connack))
+    }
+
+    // Will wrapper
+    // Answers WILLTOPICREQ with WILLTOPIC and WILLMSGREQ with WILLMSG,
+    // in the order required by the CONNECT handshake.
+    pub fn send_will(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+        self.wait_for(sensor_net, MQTT_SN_WILLTOPICREQ, settings)?;
+        self.send_willtopic(sensor_net, settings)?;
+
+        self.wait_for(sensor_net, MQTT_SN_WILLMSGREQ, settings)?;
+        self.send_willmsg(sensor_net, settings)
+    }
+
+    pub fn send_willtopic(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+        // Check will topic length
+        if settings.will_topic.len() > self.max_topic_length(sensor_net) {
+            return Err(MqttSnError::InvalidArgument(format!(
+                "Will topic is too long. Maximum length is {}",
+                self.max_topic_length(sensor_net)
+            )));
+        }
+
+        let msg_type = MQTT_SN_WILLTOPIC;
+        let flags = Flags::new()
+            .with_qos(settings.will_qos)
+            .with_retain(settings.will_retain);
+
+        // Copy the will topic into the packet
+        let will_topic = settings.will_topic.as_bytes().to_vec();
+
+        // Get the packet length
+        let length = mqtt_sn_packet_length(0x03 + settings.will_topic.len());
+
+        // Assemble the packet
+        let packet = MqttSnPacket::Willtopic(WilltopicPacket {
+            length,
+            msg_type,
+            flags,
+            will_topic,
+        });
+
+        info!("Sending WILLTOPIC packet: {:?}", packet);
+        self.send_acknowledged(sensor_net, &packet, &[MQTT_SN_WILLMSGREQ])
+    }
+
+    pub fn send_willmsg(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+        // Check will message length
+        if settings.will_message.len() > self.max_payload_length(sensor_net) {
+            return Err(MqttSnError::InvalidArgument(format!(
+                "Will message is too long. Maximum length is {}",
+                self.max_payload_length(sensor_net)
+            )));
+        }
+
+        let msg_type = MQTT_SN_WILLMSG;
+
+        // Copy the will message into the packet
+        let will_msg = settings.will_message.as_bytes().to_vec();
+
+        // Get the packet length
+        let length = mqtt_sn_packet_length(0x02 + settings.will_message.len());
+
+        // Assemble the packet
+        let packet = MqttSnPacket::Willmsg(WillmsgPacket {
+            length,
+            msg_type,
+            will_msg,
+        });
+
+        info!("Sending WILLMSG packet: {:?}", packet);
+        self.send_acknowledged(sensor_net, &packet, &[MQTT_SN_CONNACK])
+    }
+
+    // Will update wrappers
+    // These can be used at any time while connected, to replace the will
+    // given in CONNECT. They take the new values from the settings.
+
+    pub fn update_will_topic(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+        self.send_willtopicupd(sensor_net, settings)?;
+        self.receive_willtopicresp(sensor_net, settings).map(|_| ())
+    }
+
+    pub fn update_will_message(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+        self.send_willmsgupd(sensor_net, settings)?;
+        self.receive_willmsgresp(sensor_net, settings).map(|_| ())
+    }
+
+    pub fn send_willtopicupd(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+        // Check will topic length
+        if settings.will_topic.len() > self.max_topic_length(sensor_net) {
+            return Err(MqttSnError::InvalidArgument(format!(
+                "Will topic is too long. Maximum length is {}",
+                self.max_topic_length(sensor_net)
+            )));
+        }
+
+        let msg_type = MQTT_SN_WILLTOPICUPD;
+        let flags = Flags::new()
+            .with_qos(settings.will_qos)
+            .with_retain(settings.will_retain);
+
+        // Copy the will topic into the packet, an empty one deletes the will
+        let will_topic = settings.will_topic.as_bytes().to_vec();
+
+        // Get the packet length
+        let length = if will_topic.is_empty() {
+            0x02
+        } else {
+            mqtt_sn_packet_length(0x03 + settings.will_topic.len())
+        };
+
+        // Assemble the packet
+        let packet = MqttSnPacket::Willtopicupd(WilltopicupdPacket {
+            length,
+            msg_type,
+            flags,
+            will_topic,
+        });
+
+        info!("Sending WILLTOPICUPD packet: {:?}", packet);
+        self.send_acknowledged(sensor_net, &packet, &[MQTT_SN_WILLTOPICRESP])
+    }
+
+    // Fails unless the gateway accepted the new will topic
+    pub fn receive_willtopicresp(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<WilltopicrespPacket, MqttSnError> {
+        let packet = self.wait_for(sensor_net, MQTT_SN_WILLTOPICRESP, settings)?;
+        let willtopicresp = match packet {
+            MqttSnPacket::Willtopicresp(willtopicresp) => willtopicresp,
+            packet => return Err(mqtt_sn_unexpected_packet(MQTT_SN_WILLTOPICRESP, &packet)),
+        };
+        info!("Received WILLTOPICRESP packet: {:?}", willtopicresp);
+        let return_code = ReturnCode::from(willtopicresp.return_code);
+        if return_code != ReturnCode::Accepted {
+            return Err(MqttSnError::Rejected { msg_type: MQTT_SN_WILLTOPICUPD, return_code });
+        }
+        Ok(willtopicresp.clone())
+    }
+
+    pub fn send_willmsgupd(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+        // Check will message length
+        if settings.will_message.len() > self.max_payload_length(sensor_net) {
+            return Err(MqttSnError::InvalidArgument(format!(
+                "Will message is too long. Maximum length is {}",
+                self.max_payload_length(sensor_net)
+            )));
+        }
+
+        let msg_type = MQTT_SN_WILLMSGUPD;
+
+        // Copy the will message into the packet
+        let will_msg = settings.will_message.as_bytes().to_vec();
+
+        // Get the packet length
+        let length = mqtt_sn_packet_length(0x02 + settings.will_message.len());
+
+        // Assemble the packet
+        let packet = MqttSnPacket::Willmsgupd(WillmsgupdPacket {
+            length,
+            msg_type,
+            will_msg,
+        });
+
+        info!("Sending WILLMSGUPD packet: {:?}", packet);
+        self.send_acknowledged(sensor_net, &packet, &[MQTT_SN_WILLMSGRESP])
+    }
+
+    // Fails unless the gateway accepted the new will message
+    pub fn receive_willmsgresp(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<WillmsgrespPacket, MqttSnError> {
+        let packet = self.wait_for(sensor_net, MQTT_SN_WILLMSGRESP, settings)?;
+        let willmsgresp = match packet {
+            MqttSnPacket::Willmsgresp(willmsgresp) => willmsgresp,
+            packet => return Err(mqtt_sn_unexpected_packet(MQTT_SN_WILLMSGRESP, &packet)),
+        };
+        info!("Received WILLMSGRESP packet: {:?}", willmsgresp);
+        let return_code = ReturnCode::from(willmsgresp.return_code);
+        if return_code != ReturnCode::Accepted {
+            return Err(MqttSnError::Rejected { msg_type: MQTT_SN_WILLMSGUPD, return_code });
+        }
+        Ok(willmsgresp.clone())
+    }
+
+    // Registers a topic name, returning the topic ID given by the gateway
+    pub fn register(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings, topic: &str) -> Result<u16, MqttSnError> {
+        self.send_register(sensor_net, topic)?;
+        self.receive_regack(sensor_net, settings).map(|regack| regack.topic_id)
+    }
+
+    pub fn send_register(&mut self, sensor_net: &mut dyn SensorNetwork, topic: &str) -> Result<(), MqttSnError> {
+        // Check topic name length
+        if topic.len() > self.max_topic_length(sensor_net) {
+            return Err(MqttSnError::InvalidArgument(format!(
+                "Topic name is too long. Maximum length is {}",
+                self.max_topic_length(sensor_net)
+            )));
+        }
+
+        let msg_type = MQTT_SN_REGISTER;
+        let topic_id = 0; // Not used
+        let message_id = self.next_message_id();
+
+        // Copy the topic name into the packet
+        let topic_name = topic.as_bytes().to_vec();
+
+        // Get the packet length
+        let length = mqtt_sn_packet_length(0x06 + topic.len());
+
+        // Assemble the packet
+        let packet = MqttSnPacket::Register(RegisterPacket {
+            length,
+            msg_type,
+            topic_id,
+            message_id,
+            topic_name,
+        });
+
+        info!("Sending REGISTER packet: {:?}", packet);
+        self.registering.insert(message_id, topic.to_string());
+        self.send_acknowledged(sensor_net, &packet, &[MQTT_SN_REGACK])
+    }
+
+    // Fails unless the gateway accepted the registration
+    pub fn receive_regack(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<RegackPacket, MqttSnError> {
+        let packet = self.wait_for(sensor_net, MQTT_SN_REGACK, settings)?;
+        let regack = match packet {
+            MqttSnPacket::Regack(regack) => regack,
+            packet => return Err(mqtt_sn_unexpected_packet(MQTT_SN_REGACK, &packet)),
+        };
+
+        let topic_name = self.registering.remove(&regack.message_id);
+        let return_code = ReturnCode::from(regack.return_code);
+        if return_code != ReturnCode::Accepted {
+            return Err(MqttSnError::Rejected { msg_type: MQTT_SN_REGISTER, return_code });
+        }
+        debug!("Updated topic ID: {}", regack.topic_id);
+        if let Some(topic_name) = topic_name {
+            self.set_topic(regack.topic_id, topic_name);
+        }
+        Ok(regack.clone())
+    }
+
+    pub fn receive_register(&mut self, sensor_net: &mut dyn SensorNetwork, register: &RegisterPacket) -> Result<(), MqttSnError> {
+        info!("Received REGISTER packet: {:?}", register);
+        let topic_name = String::from_utf8_lossy(&register.topic_name).to_string();
+        debug!("Registered topic ID {} as '{}'", register.topic_id, topic_name);
+        self.set_topic(register.topic_id, topic_name);
+        self.send_regack(sensor_net, register.topic_id, register.message_id, MQTT_SN_ACCEPTED)
+    }
+
+    pub fn send_regack(
+        &mut self,
+        sensor_net: &mut dyn SensorNetwork,
+        topic_id: u16,
+        message_id: u16,
+        return_code: u8,
+    ) -> Result<(), MqttSnError> {
+        let msg_type = MQTT_SN_REGACK;
+        let length = 0x07;
+
+        let packet = MqttSnPacket::Regack(RegackPacket {
+            length,
+            msg_type,
+            topic_id,
+            message_id,
+            return_code,
+        });
+
+        info!("Sending REGACK packet: {:?}", packet);
+        self.send_packet(sensor_net, &packet)
+    }
+
+    pub fn send_subscribe_topic_name(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings, topic: &str) -> Result<(), MqttSnError> {
+        // Check topic name length
+        if topic.len() > self.max_topic_length(sensor_net) {
+            return Err(MqttSnError::InvalidArgument(format!(
+                "Topic name is too long. Maximum length is {}",
+                self.max_topic_length(sensor_net)
+            )));
+        }
+
+        let msg_type = MQTT_SN_SUBSCRIBE;
+        let message_id = self.next_message_id();
+        let mut flags = Flags::new().with_qos(settings.qos);
+
+        // Copy the topic name into the packet
+        let topic_name: Topic = Topic::TopicName(topic.as_bytes().to_vec());
+
+        let length = mqtt_sn_packet_length(5 + topic.len());
+        // Get the packet length
+        if topic.len() == 2 {
+            // Short topic name
+            flags = flags.with_topic_id_type(TopicIdType::Short);
+        } else {
+            // Normal topic name
+            flags = flags.with_topic_id_type(TopicIdType::Normal);
+        }
+
+        // Assemble the packet
+        let packet = MqttSnPacket::Subscribe(SubscribePacket {
+            length,
+            msg_type,
+            flags,
+            message_id,
+            topic: topic_name,
+        });
+
+        info!("Sending SUBSCRIBE packet: {:?}", packet);
+        self.send_acknowledged(sensor_net, &packet, &[MQTT_SN_SUBACK])
+    }
+
+    pub fn send_subscribe_topic_id(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings, topic_id: u16) -> Result<(), MqttSnError> {
+        let msg_type = MQTT_SN_SUBSCRIBE;
+        let message_id = self.next_message_id();
+        let flags = Flags::new()
+            .with_topic_id_type(TopicIdType::Predefined)
+            .with_qos(settings.qos);
+
+        // Copy the topic ID into the packet
+        let topic: Topic = Topic::TopicId(topic_id);
+
+        // Get the packet length
+        let length = 0x05 + 2;
+
+        // Assemble the packet
+        let packet = MqttSnPacket::Subscribe(SubscribePacket {
+            length,
+            msg_type,
+            flags,
+            message_id,
+            topic,
+        });
+
+        info!("Sending SUBSCRIBE packet: {:?}", packet);
+        self.send_acknowledged(sensor_net, &packet, &[MQTT_SN_SUBACK])
+    }
+
+    // Returns the topic ID given by the gateway, 0 for wildcards and short
+    // topic names
+    pub fn subscribe(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings, topic: &Topic) -> Result<u16, MqttSnError> {
+        match topic {
+            Topic::TopicId(topic_id) => self.send_subscribe_topic_id(sensor_net, settings, *topic_id)?,
+            Topic::TopicName(name) => {
+                self.send_subscribe_topic_name(sensor_net, settings, &String::from_utf8_lossy(name))?
+            }
+        }
+        let topic_id = self.receive_suback(sensor_net, settings)?;
+        // Remember the ID of topic names without wildcards
+        if let Topic::TopicName(name) = topic {
+            if topic_id != 0 {
+                self.set_topic(topic_id, String::from_utf8_lossy(name).to_string());
+            }
+        }
+        Ok(topic_id)
+    }
+
+    // Returns the topic ID given by the gateway, failing unless it accepted
+    // the subscription
+    pub fn receive_suback(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<u16, MqttSnError> {
+        let packet = self.wait_for(sensor_net, MQTT_SN_SUBACK, settings)?;
+        let suback = match packet {
+            MqttSnPacket::Suback(suback) => suback,
+            packet => return Err(mqtt_sn_unexpected_packet(MQTT_SN_SUBACK, &packet)),
+        };
+        debug!("Received SUBACK packet: {:?}", suback);
+        // Check the returned code
+        let return_code = ReturnCode::from(suback.return_code);
+        if return_code != ReturnCode::Accepted {
+            return Err(MqttSnError::Rejected { msg_type: MQTT_SN_SUBSCRIBE, return_code });
+        }
+        info!("SUBACK successful");
+        info!("Topic ID: {}", suback.topic_id);
+        Ok(suback.topic_id)
+    }
+
+    // Topic names of two characters are sent as short topics,
+    // topic IDs are always pre-defined.
+    pub fn send_unsubscribe(&mut self, sensor_net: &mut dyn SensorNetwork, topic: &Topic) -> Result<(), MqttSnError> {
+        let msg_type = MQTT_SN_UNSUBSCRIBE;
+        let message_id = self.next_message_id();
+
+        let (topic_id_type, length) = match topic {
+            Topic::TopicId(_) => (TopicIdType::Predefined, 0x05 + 2),
+            Topic::TopicName(name) => {
+                // Check topic name length
+                if name.len() > self.max_topic_length(sensor_net) {
+                    return Err(MqttSnError::InvalidArgument(format!(
+                        "Topic name is too long. Maximum length is {}",
+                        self.max_topic_length(sensor_net)
+                    )));
+                }
+                if name.len() == 2 {
+                    (TopicIdType::Short, 0x05 + 2)
+                } else {
+                    (TopicIdType::Normal, mqtt_sn_packet_length(0x05 + name.len()))
+                }
+            }
+        };
+
+        // Assemble the packet
+        let packet = MqttSnPacket::Unsubscribe(UnsubscribePacket {
+            length,
+            msg_type,
+            flags: Flags::new().with_topic_id_type(topic_id_type),
+            message_id,
+            topic: topic.clone(),
+        });
+
+        info!("Sending UNSUBSCRIBE packet: {:?}", packet);
+        self.send_acknowledged(sensor_net, &packet, &[MQTT_SN_UNSUBACK])
+    }
+
+    pub fn receive_unsuback(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+        let packet = self.wait_for(sensor_net, MQTT_SN_UNSUBACK, settings)?;
+        debug!("Received UNSUBACK packet: {:?}", packet);
+        info!("UNSUBACK successful");
+        Ok(())
+    }
+
+    // Unsubscribe from every topic name and topic ID in the settings
+    pub fn unsubscribe_all(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+        for topic in settings.topic_list.iter() {
+            debug!("Unsubscribing from topic: {}", topic);
+            self.send_unsubscribe(sensor_net, &Topic::TopicName(topic.as_bytes().to_vec()))?;
+            self.receive_unsuback(sensor_net, settings)?;
+        }
+
+        for topic_id in settings.topic_id_list.iter() {
+            debug!("Unsubscribing from topic ID: {}", topic_id);
+            self.send_unsubscribe(sensor_net, &Topic::TopicId(*topic_id))?;
+            self.receive_unsuback(sensor_net, settings)?;
+        }
+        Ok(())
+    }
+
+    // Ends the session, waiting for the gateway to confirm
+    pub fn disconnect(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+        self.send_disconnect_duration(sensor_net, 0)?;
+        self.wait_for(sensor_net, MQTT_SN_DISCONNECT, settings).map(|_| ())
+    }
+
+    // Sends a DISCONNECT with settings.sleep_duration, going to sleep if set
+    pub fn send_disconnect(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+        self.send_disconnect_duration(sensor_net, settings.sleep_duration as u16)
+    }
+
+    fn send_disconnect_duration(&mut self, sensor_net: &mut dyn SensorNetwork, duration: u16) -> Result<(), MqttSnError> {
+        // Asleep or gone, either way no longer connected
+        self.connected = false;
+        let msg_type = MQTT_SN_DISCONNECT;
+        let length: u16 = if duration == 0 { 0x02 } else { 0x04 };
+        let packet = MqttSnPacket::Disconnect(DisconnectPacket {
+            length,
+            msg_type,
+            duration,
+        });
+        info!("Sending DISCONNECT packet: {:?}", packet);
+        self.send_packet(sensor_net, &packet)
+    }
+
+    pub fn receive_disconnect(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+        self.wait_for(sensor_net, MQTT_SN_DISCONNECT, settings).map(|_| ())
+    }
+
+    // Topic IDs are pre-defined, topic names of two characters are sent as
+    // short topics and longer ones are registered first, unless the gateway
+    // already knows them.
+    pub fn publish(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings, topic: &Topic, message: &str) -> Result<(), MqttSnError> {
+        self.publish_once(sensor_net, settings, topic, message, true)
+    }
+
+    // A PUBACK with an invalid topic ID makes us register the topic again,
+    // but only once per message
+    fn publish_once(
+        &mut self,
+        sensor_net: &mut dyn SensorNetwork,
+        settings: &Settings,
+        topic: &Topic,
+        message: &str,
+        reregister: bool,
+    ) -> Result<(), MqttSnError> {
+        // Check message length
+        let max_message_length = self.max_payload_length(sensor_net);
+        if message.len() > max_message_length {
+            return Err(MqttSnError::InvalidArgument(format!(
+                "Message is too long. Maximum length is {}",
+                max_message_length
+            )));
+        }
+
+        let msg_type = MQTT_SN_PUBLISH;
+        let flags = Flags::new()
+            .with_retain(settings.retain)
+            .with_qos(settings.qos);
+
+        // Topic type and id
+        let (topic_id_type, topic_id) = match topic {
+            Topic::TopicId(topic_id) => (TopicIdType::Predefined, *topic_id),
+            Topic::TopicName(name) if name.len() == 2 => {
+                (TopicIdType::Short, u16::from_be_bytes([name[0], name[1]]))
+            }
+            Topic::TopicName(name) => {
+                let name = String::from_utf8_lossy(name).to_string();
+                let topic_id = match self.topic_id(&name) {
+                    Some(topic_id) => topic_id,
+                    None => self.register(sensor_net, settings, &name)?,
+                };
+                (TopicIdType::Normal, topic_id)
+            }
+        };
+        let flags = flags.with_topic_id_type(topic_id_type);
+
+        // Message ID
+        let mut message_id: u16 = 0;
+        if settings.qos.is_acknowledged() {
+            // Increment the message ID
+            message_id = self.next_message_id();
+        } else {
+            // Set the message ID to 0
+            self.message_id = 0;
+        }
+        let data = message;
+
+        // Get the packet length
+        let length = mqtt_sn_packet_length(0x07 + data.len());
+
+        // Assemble the packet
+        let packet = MqttSnPacket::Publish(PublishPacket {
+            length,
+            msg_type,
+            flags,
+            topic_id,
+            message_id,
+            data: data.as_bytes().to_vec(),
+        });
+
+        info!("Sending PUBLISH packet: {:?}", packet);
+        // QoS 1 and 2 messages are resent until acknowledged
+        match settings.qos {
+            QoS::AtLeastOnce => self.send_acknowledged(sensor_net, &packet, &[MQTT_SN_PUBACK])?,
+            QoS::ExactlyOnce => self.send_acknowledged(sensor_net, &packet, &[MQTT_SN_PUBREC])?,
+            _ => self.send_packet(sensor_net, &packet)?,
+        }
+
+        if settings.qos == QoS::AtLeastOnce {
+            // Wait for PUBACK
+            let packet = self.wait_for(sensor_net, MQTT_SN_PUBACK, settings)?;
+            let puback = match packet {
+                MqttSnPacket::Puback(puback) => puback,
+                packet => return Err(mqtt_sn_unexpected_packet(MQTT_SN_PUBACK, &packet)),
+            };
+            info!("Received PUBACK packet: {:?}", puback);
+            match ReturnCode::from(puback.return_code) {
+                ReturnCode::Accepted => {}
+                ReturnCode::RejectedInvalidTopicId
+                    if reregister && topic_id_type == TopicIdType::Normal =>
+                {
+                    warn!("Gateway does not know topic ID {}, registering it again", puback.topic_id);
+                    // Publishing again registers the topic, now unknown
+                    self.topics.remove(&topic_id);
+                    return self.publish_once(sensor_net, settings, topic, message, false);
+                }
+                return_code => return Err(MqttSnError::Rejected { msg_type: MQTT_SN_PUBLISH, return_code }),
+            }
+        } else if settings.qos == QoS::ExactlyOnce {
+            // QoS 2 handshake: PUBLISH -> PUBREC -> PUBREL -> PUBCOMP
+            let pubrec = self.wait_for(sensor_net, MQTT_SN_PUBREC, settings)?;
+            info!("Received PUBREC packet: {:?}", pubrec);
+
+            self.send_pubrel(sensor_net, message_id)?;
+
+            let pubcomp = self.wait_for(sensor_net, MQTT_SN_PUBCOMP, settings)?;
+            info!("Received PUBCOMP packet: {:?}", pubcomp);
+        }
+        Ok(())
+    }
+
+    pub fn send_pubrel(&mut self, sensor_net: &mut dyn SensorNetwork, message_id: u16) -> Result<(), MqttSnError> {
+        let msg_type = MQTT_SN_PUBREL;
+        let length = 0x04;
+
+        let packet = MqttSnPacket::Pubrel(PubrelPacket {
+            length,
+            msg_type,
+            message_id,
+        });
+
+        info!("Sending PUBREL packet: {:?}", packet);
+        self.send_acknowledged(sensor_net, &packet, &[MQTT_SN_PUBCOMP])
+    }
+
+    pub fn receive_publish(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<PublishPacket, MqttSnError> {
+        let packet = self.wait_for(sensor_net, MQTT_SN_PUBLISH, settings)?;
+        let publish = match packet {
+            MqttSnPacket::Publish(publish) => publish,
+            packet => return Err(mqtt_sn_unexpected_packet(MQTT_SN_PUBLISH, &packet)),
+        };
+        self.deliver_publish(&publish, settings);
+        Ok(publish)
+    }
+
+    // Waits for the next PUBLISH and acknowledges it according to its QoS.
+    // Unlike receive_publish nothing is printed, and QoS 2 duplicates are
+    // only acknowledged, returning None.
+    pub fn poll(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<Option<PublishPacket>, MqttSnError> {
+        let packet = self.wait_for(sensor_net, MQTT_SN_PUBLISH, settings)?;
+        let publish = match packet {
+            MqttSnPacket::Publish(publish) => publish,
+            packet => return Err(mqtt_sn_unexpected_packet(MQTT_SN_PUBLISH, &packet)),
+        };
+        let qos = publish.flags.qos();
+        if qos == QoS::AtLeastOnce {
+            self.send_puback(sensor_net, &publish, MQTT_SN_ACCEPTED)?;
+        } else if qos == QoS::ExactlyOnce {
+            let duplicate = !self.qos2_received.insert(publish.message_id);
+            self.handle_qos2_publish(sensor_net, settings, &publish)?;
+            if duplicate {
+                info!(
+                    "Received duplicate QoS 2 PUBLISH with message ID {}",
+                    publish.message_id
+                );
+                return Ok(None);
+            }
+        }
+        Ok(Some(publish))
+    }
+
+    // Print a received PUBLISH, unless it is a QoS 2 duplicate
+    fn deliver_publish(&mut self, publish: &PublishPacket, settings: &Settings) {
+        let qos = publish.flags.qos();
+        if qos == QoS::ExactlyOnce && !self.qos2_received.insert(publish.message_id) {
+            // Already delivered, the gateway did not get our PUBREC
+            info!(
+                "Received duplicate QoS 2 PUBLISH with message ID {}",
+                publish.message_id
+            );
+        } else {
+            self.print_publish_packet(publish, settings);
+        }
+    }
+
+    pub fn send_puback(
+        &mut self,
+        sensor_net: &mut dyn SensorNetwork,
+        packet: &PublishPacket,
+        return_code: u8,
+    ) -> Result<(), MqttSnError> {
+        let msg_type = MQTT_SN_PUBACK;
+        let message_id = packet.message_id;
+        let topic_id = packet.topic_id;
+
+        let length = 0x07;
+
+        let packet = MqttSnPacket::Puback(PubackPacket {
+            length,
+            msg_type,
+            topic_id,
+            message_id,
+            return_code,
+        });
+
+        info!("Sending PUBACK packet: {:?}", packet);
+        self.send_packet(sensor_net, &packet)
+    }
+
+    // QoS 2 receive state machine: PUBLISH -> PUBREC -> PUBREL -> PUBCOMP
+    // The PUBLISH is delivered when first received (see receive_publish),
+    // so its message ID is kept until the PUBREL arrives to filter duplicates.
+    pub fn handle_qos2_publish(
+        &mut self,
+        sensor_net: &mut dyn SensorNetwork,
+        settings: &Settings,
+        packet: &PublishPacket,
+    ) -> Result<(), MqttSnError> {
+        self.send_pubrec(sensor_net, packet.message_id)?;
+
+        loop {
+            let pubrel = self.receive_pubrel(sensor_net, settings)?;
+            // A late PUBREL for an earlier message still gets its PUBCOMP
+            self.release_qos2_publish(sensor_net, pubrel.message_id)?;
+            if pubrel.message_id == packet.message_id {
+                return Ok(());
+            }
+            warn!(
+                "Received PUBREL with unexpected message ID: {}",
+                pubrel.message_id
+            );
+        }
+    }
+
+    // Ends the QoS 2 handshake of a received message
+    fn release_qos2_publish(&mut self, sensor_net: &mut dyn SensorNetwork, message_id: u16) -> Result<(), MqttSnError> {
+        self.qos2_received.remove(&message_id);
+        self.send_pubcomp(sensor_net, message_id)
+    }
+
+    pub fn send_pubrec(&mut self, sensor_net: &mut dyn SensorNetwork, message_id: u16) -> Result<(), MqttSnError> {
+        let msg_type = MQTT_SN_PUBREC;
+        let length = 0x04;
+
+        let packet = MqttSnPacket::Pubrec(PubrecPacket {
+            length,
+            msg_type,
+            message_id,
+        });
+
+        info!("Sending PUBREC packet: {:?}", packet);
+        self.send_acknowledged(sensor_net, &packet, &[MQTT_SN_PUBREL])
+    }
+
+    pub fn receive_pubrel(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<PubrelPacket, MqttSnError> {
+        let packet = self.wait_for(sensor_net, MQTT_SN_PUBREL, settings)?;
+        let pubrel = match packet {
+            MqttSnPacket::Pubrel(pubrel) => pubrel,
+            packet => return Err(mqtt_sn_unexpected_packet(MQTT_SN_PUBREL, &packet)),
+        };
+        info!("Received PUBREL packet: {:?}", pubrel);
+        Ok(pubrel.clone())
+    }
+
+    pub fn send_pubcomp(&mut self, sensor_net: &mut dyn SensorNetwork, message_id: u16) -> Result<(), MqttSnError> {
+        let msg_type = MQTT_SN_PUBCOMP;
+        let length = 0x04;
+
+        let packet = MqttSnPacket::Pubcomp(PubcompPacket {
+            length,
+            msg_type,
+            message_id,
+        });
+
+        info!("Sending PUBCOMP packet: {:?}", packet);
+        self.send_packet(sensor_net, &packet)
+    }
+
+    fn send_pingreq(&mut self, sensor_net: &mut dyn SensorNetwork) -> Result<(), MqttSnError> {
+        let msg_type = MQTT_SN_PINGREQ;
+        let length = 0x02;
+        let packet = MqttSnPacket::Pingreq(PingreqPacket { length, msg_type, client_id: Vec::new() });
+        info!("Sending PINGREQ packet: {:?}", packet);
+        self.send_acknowledged(sensor_net, &packet, &[MQTT_SN_PINGRESP])
+    }
+
+    // Sleeping clients
+    //
+    // A client goes to sleep by sending a DISCONNECT with a duration. While
+    // asleep, the gateway buffers the messages for it. The client wakes up by
+    // sending a PINGREQ with its client ID, receives the buffered messages,
+    // and goes back to sleep once the gateway answers with PINGRESP.
+
+    pub fn go_to_sleep(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+        if settings.sleep_duration == 0 {
+            return Err(MqttSnError::InvalidArgument(String::from(
+                "Cannot go to sleep without a sleep duration",
+            )));
+        }
+        self.send_disconnect(sensor_net, settings)?;
+        self.wait_for(sensor_net, MQTT_SN_DISCONNECT, settings)?;
+        info!("Asleep for {} seconds", settings.sleep_duration);
+        Ok(())
+    }
+
+    pub fn send_wakeup_pingreq(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<(), MqttSnError> {
+        // Check client id length
+        if settings.client_id.len() > MQTT_SN_MAX_CLIENT_ID_LENGTH {
+            return Err(MqttSnError::InvalidArgument(format!(
+                "Client ID is too long. Maximum length is {}",
+                MQTT_SN_MAX_CLIENT_ID_LENGTH
+            )));
+        }
+
+        let msg_type = MQTT_SN_PINGREQ;
+        let client_id = settings.client_id.as_bytes().to_vec();
+        let length = mqtt_sn_packet_length(0x02 + client_id.len());
+        let packet = MqttSnPacket::Pingreq(PingreqPacket { length, msg_type, client_id });
+        info!("Sending PINGREQ packet: {:?}", packet);
+        self.send_acknowledged(sensor_net, &packet, &[MQTT_SN_PINGRESP])
+    }
+
+    // Wake up and receive the messages buffered by the gateway, until it
+    // answers with PINGRESP. Every PUBLISH is printed and acknowledged.
+    pub fn wake_up(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<Vec<PublishPacket>, MqttSnError> {
+        let mut received: Vec<PublishPacket> = Vec::new();
+        self.send_wakeup_pingreq(sensor_net, settings)?;
+
+        let start = std::time::Instant::now();
+        loop {
+            // Resend the PINGREQ until the gateway answers, failing after Nretry
+            self.retransmit(sensor_net, settings)?;
+            match self.receive_frwdencap_packet(sensor_net, settings) {
+                Ok(packet) => {
+                    self.acknowledge_retransmission(packet.msg_type(), packet.message_id());
+                    // Messages already received are kept even if acknowledging fails
+                    let result = match packet {
+                        MqttSnPacket::Pingresp(_) => {
+                            debug!("Received PINGRESP, going back to sleep");
+                            return Ok(received);
+                        }
+                        MqttSnPacket::Disconnect(disconnect) => {
+                            info!("Received DISCONNECT packet from gateway: {:?}", disconnect);
+                            self.connected = false;
+                            return Err(MqttSnError::Disconnected);
+                        }
+                        MqttSnPacket::Publish(publish) => {
+                            self.deliver_publish(&publish, settings);
+                            let qos = publish.flags.qos();
+                            let result = if qos == QoS::AtLeastOnce {
+                                self.send_puback(sensor_net, &publish, MQTT_SN_ACCEPTED)
+                            } else if qos == QoS::ExactlyOnce {
+                                self.handle_qos2_publish(sensor_net, settings, &publish)
+                            } else {
+                                Ok(())
+                            };
+                            received.push(publish);
+                            result
+                        }
+                        MqttSnPacket::Register(register) => self.receive_register(sensor_net, &register),
+                        MqttSnPacket::Pubrel(pubrel) => {
+                            self.qos2_received.remove(&pubrel.message_id);
+                            self.send_pubcomp(sensor_net, pubrel.message_id)
+                        }
+                        packet => {
+                            warn!(
+                                "Received unexpected {} packet while awake",
+                                mqtt_sn_packet_type_to_str(packet.msg_type())
+                            );
+                            Ok(())
+                        }
+                    };
+                    match result {
+                        Err(MqttSnError::Disconnected) => return Err(MqttSnError::Disconnected),
+                        Err(e) => warn!("{}", e),
+                        Ok(()) => {}
+                    }
+                }
+                Err(MqttSnError::Timeout) => {
+                    debug!("Network timeout reached while waiting for PINGRESP");
+                }
+                // The gateway closed the connection, nothing more will arrive
+                Err(MqttSnError::Transport(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    warn!("{}", e);
+                    return Err(MqttSnError::Disconnected);
+                }
+                Err(e @ MqttSnError::Transport(_)) => return Err(e),
+                // A malformed packet does not end the wake up
+                Err(e) => {
+                    warn!("Failed to receive packet: {}", e);
+                }
+            }
+
+            // Check if the timeout has been reached
+            if settings.timeout > 0 && start.elapsed().as_secs() >= settings.timeout {
+                warn!("Timeout reached while waiting for PINGRESP");
+                return Err(MqttSnError::Timeout);
+            }
+        }
+    }
+
+    // One full sleep cycle: go to sleep, sleep for settings.sleep_duration,
+    // then wake up and collect the buffered messages.
+    pub fn sleep_cycle(&mut self, sensor_net: &mut dyn SensorNetwork, settings: &Settings) -> Result<Vec<PublishPacket>, MqttSnError> {
+        self.go_to_sleep(sensor_net, settings)?;
+        std::thread::sleep(std::time::Duration::from_secs(settings.sleep_duration));
+        self.wake_up(sensor_net, settings)
+    }
+}
+
+fn mqtt_sn_send_bytes(sensor_net: &mut dyn SensorNetwork, safe_buffer: &[u8]) -> Result<(), MqttSnError> {
+    // Print the packet, each byte as hex. Only built when it gets logged,
+    // as it allocates on every send.
+    if log::max_level() >= LevelFilter::Debug {
+        let mut hex_buffer = String::with_capacity(safe_buffer.len() * 3);
+        for byte in safe_buffer {
+            let _ = write!(hex_buffer, "{:02X} ", byte);
+        }
+        // Strip final space
+        hex_buffer.pop();
+        debug!("Sending packet: {:?}", hex_buffer);
+    }
+
+    sensor_net.send(safe_buffer).map_err(MqttSnError::Transport)?;
+    Ok(())
+}
+
+// wait_for only returns packets of the requested type, so this only
+// happens if the decoder and the message type disagree
+fn mqtt_sn_unexpected_packet(expected: u8, packet: &MqttSnPacket) -> MqttSnError {
+    MqttSnError::ProtocolViolation(format!(
+        "Was expecting {} packet but received {}",
+        mqtt_sn_packet_type_to_str(expected),
+        mqtt_sn_packet_type_to_str(packet.msg_type())
+    ))
+}
+
+// Message type of an encoded packet, 0xFF if it is too short
+fn mqtt_sn_packet_msg_type(packet: &[u8]) -> u8 {
+    let (_, header_length) = mqtt_sn_decode_length(packet).unwrap_or((0, 1));
+    packet.get(header_length).copied().unwrap_or(0xFF)
+}
+
+// Marks a PUBLISH or SUBSCRIBE as a duplicate. Returns the message type.
+fn mqtt_sn_set_dup_flag(packet: &mut [u8]) -> u8 {
+    let header_length = match mqtt_sn_decode_length(packet) {
+        Some((_, header_length)) if packet.len() > header_length + 1 => header_length,
+        _ => return 0xFF,
+    };
+    let msg_type = packet[header_length];
+    if msg_type == MQTT_SN_PUBLISH || msg_type == MQTT_SN_SUBSCRIBE {
+        packet[header_length + 1] |= MQTT_SN_FLAG_DUP;
+    }
+    msg_type
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    use crate::mqttsn::client::Client;
+    use crate::mqttsn::constants::{
+        MQTT_SN_ADVERTISE, MQTT_SN_GWINFO, MQTT_SN_REJECTED_CONGESTION, MQTT_SN_REJECTED_INVALID_TOPIC_ID,
+        MQTT_SN_REJECTED_NOT_SUPPORTED,
+    };
+    use crate::mqttsn::packet_types::{AdvertisePacket, GwinfoPacket, PingrespPacket, UnsubackPacket, WillmsgreqPacket, WilltopicreqPacket};
+    use crate::mqttsn::settings::default_settings;
+
+    // Plays the gateway from a script. Each receive takes the next scripted
+    // packet, None standing for a read timeout, and every sent packet is kept.
+    #[derive(Default)]
+    struct MockNetwork {
+        script: VecDeque<Option<Vec<u8>>>,
+        sent: Vec<Vec<u8>>,
+    }
+
+    impl MockNetwork {
+        fn new(script: Vec<Option<MqttSnPacket>>) -> MockNetwork {
+            MockNetwork {
+                script: script.into_iter().map(|packet| packet.map(|packet| packet.as_bytes())).collect(),
+                sent: Vec::new(),
+            }
+        }
+
+        fn sent_packets(&self) -> Vec<MqttSnPacket> {
+            self.sent.iter().map(|bytes| MqttSnPacket::decode(bytes).unwrap()).collect()
+        }
+    }
+
+    impl SensorNetwork for MockNetwork {
+        fn initialize(&self) -> Result<(), MqttSnError> {
+            Ok(())
+        }
+
+        fn get_description(&self) -> String {
+            String::from("Mock Sensor Network")
+        }
+
+        fn receive(&mut self) -> Result<Vec<u8>, std::io::Error> {
+            match self.script.pop_front() {
+                Some(Some(packet)) => Ok(packet),
+                _ => {
+                    // Lets Tretry go by as a real read timeout would
+                    std::thread::sleep(Duration::from_millis(5));
+                    Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "Script has no packet"))
+                }
+            }
+        }
+
+        fn send(&mut self, data: &[u8]) -> Result<usize, std::io::Error> {
+            self.sent.push(data.to_vec());
+            Ok(data.len())
+        }
+
+        fn get_timeout(&self) -> u64 {
+            0
+        }
+
+        fn close(&self) -> Result<(), MqttSnError> {
+            Ok(())
+        }
+    }
+
+    fn test_settings() -> Settings {
+        let mut settings = default_settings();
+        settings.keep_alive = 0;
+        settings.timeout = 1;
+        settings
+    }
+
+    fn publish(message_id: u16, qos: QoS, dup: bool) -> MqttSnPacket {
+        MqttSnPacket::Publish(PublishPacket {
+            length: 9,
+            msg_type: MQTT_SN_PUBLISH,
+            flags: Flags::new().with_qos(qos).with_dup(dup),
+            topic_id: 1,
+            message_id,
+            data: b"hi".to_vec(),
+        })
+    }
+
+    fn pubrel(message_id: u16) -> MqttSnPacket {
+        MqttSnPacket::Pubrel(PubrelPacket { length: 4, msg_type: MQTT_SN_PUBREL, message_id })
+    }
+
+    // Message types of the sent packets, along with their message IDs
+    fn sent_types(network: &MockNetwork) -> Vec<(u8, Option<u16>)> {
+        network
+            .sent_packets()
+            .iter()
+            .map(|packet| (packet.msg_type(), packet.message_id()))
+            .collect()
+    }
+
+    #[test]
+    fn pubrec_resent_for_duplicate_publish() {
+        let settings = test_settings();
+        let mut session = Session::new();
+        // The first PUBREC is lost, so the gateway sends the PUBLISH again
+        let mut network = MockNetwork::new(vec![Some(publish(5, QoS::ExactlyOnce, true)), Some(pubrel(5))]);
+
+        let first = match publish(5, QoS::ExactlyOnce, false) {
+            MqttSnPacket::Publish(publish) => publish,
+            _ => unreachable!(),
+        };
+        session.qos2_received.insert(5);
+        session.handle_qos2_publish(&mut network, &settings, &first).unwrap();
+
+        assert_eq!(
+            sent_types(&network),
+            vec![
+                (MQTT_SN_PUBREC, Some(5)),
+                (MQTT_SN_PUBREC, Some(5)),
+                (MQTT_SN_PUBCOMP, Some(5)),
+            ]
+        );
+        assert!(session.qos2_received.is_empty());
+    }
+
+    #[test]
+    fn wake_up_collects_buffered_messages() {
+        let settings = test_settings();
+        let mut session = Session::new();
+        let pingresp = MqttSnPacket::Pingresp(PingrespPacket { length: 2, msg_type: MQTT_SN_PINGRESP });
+        let mut network = MockNetwork::new(vec![None, Some(publish(0, QoS::AtMostOnce, false)), Some(pingresp)]);
+
+        let received = session.wake_up(&mut network, &settings).unwrap();
+
+        assert_eq!(received.len(), 1);
+        assert_eq!(sent_types(&network), vec![(MQTT_SN_PINGREQ, None)]);
+    }
+
+    #[test]
+    fn wake_up_times_out_without_pingresp() {
+        let mut settings = test_settings();
+        settings.timeout = 0;
+        settings.network_timeout = 20;
+        let mut session = Session::new();
+        let mut network = MockNetwork::new(vec![Some(publish(0, QoS::AtMostOnce, false))]);
+
+        assert!(matches!(session.wake_up(&mut network, &settings), Err(MqttSnError::Timeout)));
+    }
+
+    #[test]
+    fn wake_up_reports_disconnect() {
+        let settings = test_settings();
+        let mut session = Session::new();
+        let disconnect = MqttSnPacket::Disconnect(DisconnectPacket { length: 2, msg_type: MQTT_SN_DISCONNECT, duration: 0 });
+        let mut network = MockNetwork::new(vec![Some(disconnect)]);
+
+        assert!(matches!(session.wake_up(&mut network, &settings), Err(MqttSnError::Disconnected)));
+    }
+
+    fn connack(return_code: u8) -> MqttSnPacket {
+        MqttSnPacket::Connack(ConnackPacket { length: 3, msg_type: MQTT_SN_CONNACK, return_code })
+    }
+
+    #[test]
+    fn connect_again_after_congestion() {
+        let mut settings = test_settings();
+        settings.t_wait = 0;
+        let mut session = Session::new();
+        let mut network = MockNetwork::new(vec![
+            Some(connack(MQTT_SN_REJECTED_CONGESTION)),
+            None,
+            Some(connack(MQTT_SN_ACCEPTED)),
+        ]);
+
+        session.connect(&mut network, &settings).unwrap();
+
+        assert!(session.is_connected());
+        assert_eq!(sent_types(&network), vec![(MQTT_SN_CONNECT, None), (MQTT_SN_CONNECT, None)]);
+    }
+
+    #[test]
+    fn pingresp_while_waiting() {
+        let settings = test_settings();
+        let mut session = Session::new();
+        let pingresp = MqttSnPacket::Pingresp(PingrespPacket { length: 2, msg_type: MQTT_SN_PINGRESP });
+        let mut network = MockNetwork::new(vec![Some(pingresp), Some(pubrel(3))]);
+
+        session.send_pingreq(&mut network).unwrap();
+        let pubrel = session.receive_pubrel(&mut network, &settings).unwrap();
+
+        assert_eq!(pubrel.message_id, 3);
+        assert!(session.retransmissions.is_empty());
+    }
+
+    fn puback(topic_id: u16, message_id: u16) -> MqttSnPacket {
+        MqttSnPacket::Puback(PubackPacket {
+            length: 7,
+            msg_type: MQTT_SN_PUBACK,
+            topic_id,
+            message_id,
+            return_code: MQTT_SN_ACCEPTED,
+        })
+    }
+
+    #[test]
+    fn unmatched_puback_ignored() {
+        let mut settings = test_settings();
+        settings.qos = QoS::AtLeastOnce;
+        let mut session = Session::new();
+        // A late PUBACK for an earlier message comes first
+        let mut network = MockNetwork::new(vec![Some(puback(1, 7)), Some(puback(1, 1))]);
+
+        session.publish(&mut network, &settings, &Topic::TopicId(1), "hi").unwrap();
+
+        assert_eq!(sent_types(&network), vec![(MQTT_SN_PUBLISH, Some(1))]);
+        assert!(session.retransmissions.is_empty());
+    }
+
+    #[test]
+    fn qos2_publish_handshake() {
+        let mut settings = test_settings();
+        settings.qos = QoS::ExactlyOnce;
+        let mut session = Session::new();
+        let mut network = MockNetwork::new(vec![
+            Some(MqttSnPacket::Pubrec(PubrecPacket { length: 4, msg_type: MQTT_SN_PUBREC, message_id: 1 })),
+            Some(MqttSnPacket::Pubcomp(PubcompPacket { length: 4, msg_type: MQTT_SN_PUBCOMP, message_id: 1 })),
+        ]);
+
+        session.publish(&mut network, &settings, &Topic::TopicId(1), "hi").unwrap();
+
+        assert_eq!(sent_types(&network), vec![(MQTT_SN_PUBLISH, Some(1)), (MQTT_SN_PUBREL, Some(1))]);
+        assert!(session.retransmissions.is_empty());
+    }
+
+    #[test]
+    fn qos2_receive_handshake() {
+        let settings = test_settings();
+        let mut session = Session::new();
+        let mut network = MockNetwork::new(vec![Some(publish(4, QoS::ExactlyOnce, false)), Some(pubrel(4))]);
+
+        let received = session.poll(&mut network, &settings).unwrap();
+
+        assert_eq!(received.map(|publish| publish.message_id), Some(4));
+        assert_eq!(sent_types(&network), vec![(MQTT_SN_PUBREC, Some(4)), (MQTT_SN_PUBCOMP, Some(4))]);
+        assert!(session.qos2_received.is_empty());
+        assert!(session.retransmissions.is_empty());
+    }
+
+    #[test]
+    fn qos2_receive_mismatched_pubrel() {
+        let settings = test_settings();
+        let mut session = Session::new();
+        // Message 3 is still waiting for its PUBREL
+        session.qos2_received.insert(3);
+        let mut network = MockNetwork::new(vec![
+            Some(publish(4, QoS::ExactlyOnce, false)),
+            Some(pubrel(3)),
+            Some(pubrel(4)),
+        ]);
+
+        session.poll(&mut network, &settings).unwrap();
+
+        assert_eq!(
+            sent_types(&network),
+            vec![
+                (MQTT_SN_PUBREC, Some(4)),
+                (MQTT_SN_PUBCOMP, Some(3)),
+                (MQTT_SN_PUBCOMP, Some(4)),
+            ]
+        );
+        assert!(session.qos2_received.is_empty());
+        assert!(session.retransmissions.is_empty());
+    }
+
+    // Whether a sent PUBLISH carries the DUP flag
+    fn sent_dup(network: &MockNetwork) -> Vec<bool> {
+        network
+            .sent_packets()
+            .iter()
+            .map(|packet| match packet {
+                MqttSnPacket::Publish(publish) => publish.flags.dup(),
+                _ => false,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn publish_resent_with_dup_after_tretry() {
+        let mut settings = test_settings();
+        settings.qos = QoS::AtLeastOnce;
+        settings.network_timeout = 10;
+        settings.network_retries = 10;
+        let mut session = Session::new();
+        let mut network = MockNetwork::new(vec![None, None, None, Some(puback(1, 1))]);
+
+        session.publish(&mut network, &settings, &Topic::TopicId(1), "hi").unwrap();
+
+        let sent = sent_types(&network);
+        assert!(sent.len() > 1);
+        assert!(sent.iter().all(|sent| *sent == (MQTT_SN_PUBLISH, Some(1))));
+        let dup = sent_dup(&network);
+        assert!(!dup[0]);
+        assert!(dup[1..].iter().all(|dup| *dup));
+    }
+
+    #[test]
+    fn publish_gives_up_after_nretry() {
+        let mut settings = test_settings();
+        settings.qos = QoS::AtLeastOnce;
+        settings.network_timeout = 0;
+        settings.network_retries = 2;
+        let mut session = Session::new();
+        let mut network = MockNetwork::new(Vec::new());
+
+        let result = session.publish(&mut network, &settings, &Topic::TopicId(1), "hi");
+
+        assert!(matches!(result, Err(MqttSnError::Timeout)));
+        // The first send and two retries
+        assert_eq!(sent_types(&network), vec![(MQTT_SN_PUBLISH, Some(1)); 3]);
+    }
+
+    #[test]
+    fn client_registers_and_publishes() {
+        let mut settings = test_settings();
+        settings.qos = QoS::AtLeastOnce;
+        let regack = MqttSnPacket::Regack(RegackPacket {
+            length: 7,
+            msg_type: MQTT_SN_REGACK,
+            topic_id: 9,
+            message_id: 1,
+            return_code: MQTT_SN_ACCEPTED,
+        });
+        let network = MockNetwork::new(vec![Some(connack(MQTT_SN_ACCEPTED)), Some(regack), Some(puback(9, 2))]);
+        let mut client = Client::new(network, settings);
+
+        client.connect().unwrap();
+        client.publish(&Topic::TopicName(b"sensors/temp".to_vec()), "21").unwrap();
+
+        assert_eq!(client.session().topic_id("sensors/temp"), Some(9));
+        assert_eq!(
+            sent_types(&client.into_network()),
+            vec![
+                (MQTT_SN_CONNECT, None),
+                (MQTT_SN_REGISTER, Some(1)),
+                (MQTT_SN_PUBLISH, Some(2)),
+            ]
+        );
+    }
+
+    fn gwinfo(gw_id: u8, gw_add: &[u8]) -> MqttSnPacket {
+        MqttSnPacket::Gwinfo(GwinfoPacket {
+            length: 3 + gw_add.len() as u16,
+            msg_type: MQTT_SN_GWINFO,
+            gw_id,
+            gw_add: gw_add.to_vec(),
+        })
+    }
+
+    #[test]
+    fn discover_gateway_prefers_gwinfo() {
+        let mut settings = test_settings();
+        settings.discovery_timeout = 1;
+        let mut session = Session::new();
+        let advertise = MqttSnPacket::Advertise(AdvertisePacket {
+            length: 5,
+            msg_type: MQTT_SN_ADVERTISE,
+            gw_id: 1,
+            duration: 900,
+        });
+        let mut network = MockNetwork::new(vec![
+            Some(advertise),
+            Some(gwinfo(2, &[192, 168, 1, 10, 0x07, 0x5B])),
+            // Heard again, the first address is kept
+            Some(gwinfo(2, &[10, 0, 0, 1, 0x07, 0x5B])),
+        ]);
+
+        let gateway = session.discover_gateway(&mut network, &settings).unwrap();
+
+        assert_eq!(gateway.gw_id, 2);
+        assert!(!gateway.advertised);
+        assert_eq!(gateway.address, Some(PeerAddress::Socket("192.168.1.10:1883".parse().unwrap())));
+        assert_eq!(sent_types(&network), vec![(MQTT_SN_SEARCHGW, None)]);
+    }
+
+    #[test]
+    fn discover_gateway_falls_back_to_advertise() {
+        let mut settings = test_settings();
+        settings.discovery_timeout = 1;
+        let mut session = Session::new();
+        let advertise = |gw_id| MqttSnPacket::Advertise(AdvertisePacket {
+            length: 5,
+            msg_type: MQTT_SN_ADVERTISE,
+            gw_id,
+            duration: 900,
+        });
+        let mut network = MockNetwork::new(vec![Some(advertise(3)), Some(advertise(4))]);
+
+        let gateway = session.discover_gateway(&mut network, &settings).unwrap();
+
+        assert_eq!(gateway.gw_id, 3);
+        assert!(gateway.advertised);
+    }
+
+    #[test]
+    fn frwdencap_sender_leaves_wireless_node_id() {
+        let mut settings = test_settings();
+        settings.wireless_node_id = 7;
+        let mut session = Session::new();
+        session.enable_frwdencap(&settings);
+        let inner = MqttSnPacket::Pingresp(PingrespPacket { length: 2, msg_type: MQTT_SN_PINGRESP });
+        let frwdencap = MqttSnPacket::Frwdencap(FWDEncapPacket {
+            length: 4,
+            msg_type: MQTT_SN_FRWDENCAP,
+            ctrl: 0,
+            wireless_node_id: vec![0xAB],
+            inner_packet: inner.as_bytes(),
+        });
+        let mut network = MockNetwork::new(vec![Some(frwdencap)]);
+
+        let (packet, sender) = session.receive_frwdencap_packet_from(&mut network, &settings).unwrap();
+
+        assert_eq!(packet.msg_type(), MQTT_SN_PINGRESP);
+        assert_eq!(sender, Some(PeerAddress::WirelessNode(vec![0xAB])));
+        assert_eq!(session.wireless_node_id(), &[0, 7]);
+    }
+
+    #[test]
+    fn connect_sends_will() {
+        let mut settings = test_settings();
+        settings.will_topic = String::from("devices/1/status");
+        settings.will_message = String::from("offline");
+        settings.will_qos = QoS::AtLeastOnce;
+        settings.will_retain = true;
+        let mut session = Session::new();
+        let mut network = MockNetwork::new(vec![
+            Some(MqttSnPacket::Willtopicreq(WilltopicreqPacket { length: 2, msg_type: MQTT_SN_WILLTOPICREQ })),
+            Some(MqttSnPacket::Willmsgreq(WillmsgreqPacket { length: 2, msg_type: MQTT_SN_WILLMSGREQ })),
+            Some(connack(MQTT_SN_ACCEPTED)),
+        ]);
+
+        session.connect(&mut network, &settings).unwrap();
+
+        let sent = network.sent_packets();
+        assert_eq!(sent.len(), 3);
+        match &sent[0] {
+            MqttSnPacket::Connect(connect) => assert!(connect.flags.will()),
+            packet => panic!("Expected CONNECT, sent {:?}", packet),
+        }
+        match &sent[1] {
+            MqttSnPacket::Willtopic(willtopic) => {
+                assert_eq!(willtopic.flags.qos(), QoS::AtLeastOnce);
+                assert!(willtopic.flags.retain());
+                assert_eq!(willtopic.will_topic, b"devices/1/status".to_vec());
+            }
+            packet => panic!("Expected WILLTOPIC, sent {:?}", packet),
+        }
+        match &sent[2] {
+            MqttSnPacket::Willmsg(willmsg) => assert_eq!(willmsg.will_msg, b"offline".to_vec()),
+            packet => panic!("Expected WILLMSG, sent {:?}", packet),
+        }
+        assert!(session.is_connected());
+        assert!(session.retransmissions.is_empty());
+    }
+
+    #[test]
+    fn will_update_rejected() {
+        let mut settings = test_settings();
+        settings.will_topic = String::from("devices/1/status");
+        settings.will_message = String::from("offline");
+        let mut session = Session::new();
+        let mut network = MockNetwork::new(vec![
+            Some(MqttSnPacket::Willtopicresp(WilltopicrespPacket {
+                length: 3,
+                msg_type: MQTT_SN_WILLTOPICRESP,
+                return_code: MQTT_SN_REJECTED_NOT_SUPPORTED,
+            })),
+            Some(MqttSnPacket::Willmsgresp(WillmsgrespPacket {
+                length: 3,
+                msg_type: MQTT_SN_WILLMSGRESP,
+                return_code: MQTT_SN_REJECTED_NOT_SUPPORTED,
+            })),
+        ]);
+
+        let result = session.update_will_topic(&mut network, &settings);
+        assert!(matches!(
+            result,
+            Err(MqttSnError::Rejected { msg_type: MQTT_SN_WILLTOPICUPD, return_code: ReturnCode::RejectedNotSupported })
+        ));
+        let result = session.update_will_message(&mut network, &settings);
+        assert!(matches!(
+            result,
+            Err(MqttSnError::Rejected { msg_type: MQTT_SN_WILLMSGUPD, return_code: ReturnCode::RejectedNotSupported })
+        ));
+        assert_eq!(sent_types(&network), vec![(MQTT_SN_WILLTOPICUPD, None), (MQTT_SN_WILLMSGUPD, None)]);
+        assert!(session.retransmissions.is_empty());
+    }
+
+    fn unsuback(message_id: u16) -> MqttSnPacket {
+        MqttSnPacket::Unsuback(UnsubackPacket { length: 4, msg_type: MQTT_SN_UNSUBACK, message_id })
+    }
+
+    #[test]
+    fn unsubscribe_all_topic_types() {
+        let mut settings = test_settings();
+        settings.topic_list = vec![String::from("sensors/temp"), String::from("ab")];
+        settings.topic_id_list = vec![5];
+        let mut session = Session::new();
+        let mut network = MockNetwork::new(vec![
+            // A late UNSUBACK for an earlier request is not taken for ours
+            Some(unsuback(9)),
+            Some(unsuback(1)),
+            Some(unsuback(2)),
+            Some(unsuback(3)),
+        ]);
+
+        session.unsubscribe_all(&mut network, &settings).unwrap();
+
+        let unsubscribes: Vec<(u16, TopicIdType, Topic)> = network
+            .sent_packets()
+            .into_iter()
+            .map(|packet| match packet {
+                MqttSnPacket::Unsubscribe(unsubscribe) => {
+                    (unsubscribe.message_id, unsubscribe.flags.topic_id_type(), unsubscribe.topic)
+                }
+                packet => panic!("Expected UNSUBSCRIBE, sent {:?}", packet),
+            })
+            .collect();
+        assert_eq!(
+            unsubscribes,
+            vec![
+                (1, TopicIdType::Normal, Topic::TopicName(b"sensors/temp".to_vec())),
+                (2, TopicIdType::Short, Topic::TopicName(b"ab".to_vec())),
+                (3, TopicIdType::Predefined, Topic::TopicId(5)),
+            ]
+        );
+        assert!(session.retransmissions.is_empty());
+    }
+
+    #[test]
+    fn unsubscribe_waits_for_matching_unsuback() {
+        let mut settings = test_settings();
+        settings.network_timeout = 10;
+        settings.network_retries = 10;
+        let mut session = Session::new();
+        let mut network = MockNetwork::new(vec![Some(unsuback(2)), None, None, None, Some(unsuback(1))]);
+
+        session.send_unsubscribe(&mut network, &Topic::TopicId(5)).unwrap();
+        session.receive_unsuback(&mut network, &settings).unwrap();
+
+        // Still unanswered after the unmatched UNSUBACK, so it was resent
+        let sent = sent_types(&network);
+        assert!(sent.len() > 1);
+        assert!(sent.iter().all(|sent| *sent == (MQTT_SN_UNSUBSCRIBE, Some(1))));
+        assert!(session.retransmissions.is_empty());
+    }
+
+    #[test]
+    fn register_from_gateway_while_waiting() {
+        let settings = test_settings();
+        let mut session = Session::new();
+        let register = MqttSnPacket::Register(RegisterPacket {
+            length: 18,
+            msg_type: MQTT_SN_REGISTER,
+            topic_id: 12,
+            message_id: 3,
+            topic_name: b"sensors/temp".to_vec(),
+        });
+        let publish = MqttSnPacket::Publish(PublishPacket {
+            length: 9,
+            msg_type: MQTT_SN_PUBLISH,
+            flags: Flags::new(),
+            topic_id: 12,
+            message_id: 0,
+            data: b"21".to_vec(),
+        });
+        let mut network = MockNetwork::new(vec![Some(register), Some(publish)]);
+
+        let received = session.poll(&mut network, &settings).unwrap();
+
+        assert_eq!(received.map(|publish| publish.topic_id), Some(12));
+        assert_eq!(session.topic_name(12), Some("sensors/temp"));
+        match &network.sent_packets()[..] {
+            [MqttSnPacket::Regack(regack)] => {
+                assert_eq!((regack.topic_id, regack.message_id), (12, 3));
+                assert_eq!(regack.return_code, MQTT_SN_ACCEPTED);
+            }
+            sent => panic!("Expected REGACK, sent {:?}", sent),
+        }
+    }
+
+    fn regack(topic_id: u16, message_id: u16, return_code: u8) -> MqttSnPacket {
+        MqttSnPacket::Regack(RegackPacket { length: 7, msg_type: MQTT_SN_REGACK, topic_id, message_id, return_code })
+    }
+
+    #[test]
+    fn register_rejected() {
+        let settings = test_settings();
+        let mut session = Session::new();
+        let mut network = MockNetwork::new(vec![Some(regack(0, 1, MQTT_SN_REJECTED_NOT_SUPPORTED))]);
+
+        let result = session.register(&mut network, &settings, "sensors/temp");
+
+        assert!(matches!(
+            result,
+            Err(MqttSnError::Rejected { msg_type: MQTT_SN_REGISTER, return_code: ReturnCode::RejectedNotSupported })
+        ));
+        assert_eq!(session.topic_id("sensors/temp"), None);
+        assert!(session.retransmissions.is_empty());
+    }
+
+    #[test]
+    fn publish_registers_again_once_after_invalid_topic_id() {
+        let mut settings = test_settings();
+        settings.qos = QoS::AtLeastOnce;
+        let mut session = Session::new();
+        let invalid_topic_id = |message_id| {
+            MqttSnPacket::Puback(PubackPacket {
+                length: 7,
+                msg_type: MQTT_SN_PUBACK,
+                topic_id: 9,
+                message_id,
+                return_code: MQTT_SN_REJECTED_INVALID_TOPIC_ID,
+            })
+        };
+        let mut network = MockNetwork::new(vec![
+            Some(regack(9, 1, MQTT_SN_ACCEPTED)),
+            Some(invalid_topic_id(2)),
+            Some(regack(9, 3, MQTT_SN_ACCEPTED)),
+            Some(invalid_topic_id(4)),
+        ]);
+
+        let result = session.publish(&mut network, &settings, &Topic::TopicName(b"sensors/temp".to_vec()), "21");
+
+        assert!(matches!(
+            result,
+            Err(MqttSnError::Rejected { msg_type: MQTT_SN_PUBLISH, return_code: ReturnCode::RejectedInvalidTopicId })
+        ));
+        assert_eq!(
+            sent_types(&network),
+            vec![
+                (MQTT_SN_REGISTER, Some(1)),
+                (MQTT_SN_PUBLISH, Some(2)),
+                (MQTT_SN_REGISTER, Some(3)),
+                (MQTT_SN_PUBLISH, Some(4)),
+            ]
+        );
+    }
+}
@@ -7,12 +7,15 @@ use crate::mqttsn::constants::{
     MQTT_SN_DEFAULT_BAUDRATE,
     MQTT_SN_DEFAULT_KEEP_ALIVE,
     MQTT_SN_DEFAULT_TIMEOUT,
-    MQTT_SN_MAX_WIRELESS_NODE_ID_LENGTH,
+    MQTT_SN_DEFAULT_SEARCH_RADIUS,
+    MQTT_SN_DEFAULT_DISCOVERY_TIMEOUT,
+    MQTT_SN_DEFAULT_RETRY_TIMEOUT,
+    MQTT_SN_DEFAULT_RETRIES,
+    MQTT_SN_DEFAULT_T_WAIT,
     MQTT_SN_MAX_CLIENT_ID_LENGTH,
-    MQTT_SN_FLAG_QOS_0,
-    MQTT_SN_FLAG_QOS_1,
-    MQTT_SN_FLAG_QOS_N1,
 };
+use crate::mqttsn::flags::{QoS, TopicIdType};
+use crate::mqttsn::network_abstractions::{AddressFamily, DtlsSecurity, MulticastInterface, SensorNetworkType};
 
 // Define a struct to hold the settings
 
@@ -26,16 +29,21 @@ pub struct Settings {
     pub timeout: u64,
     pub network_timeout: u64,
     pub network_retries: u8,
+    pub t_wait: u64,
     pub sleep_duration: u64,
     pub wireless_node_id: u16,
-    pub qos: i8,
+    pub qos: QoS,
     pub source_port: u16,
+    pub transport: SensorNetworkType,
+    pub address_family: AddressFamily,
+    pub dtls: DtlsSecurity,
     pub topic_id: u16,
-    pub topic_id_type: u8,
+    pub topic_id_type: TopicIdType,
     pub retain: bool,
     pub one_message_per_line: bool,
     pub client_id: String,
     pub forwarder_encapsulation: bool,
+    pub forwarder_radius: u8,
     pub debug_level: u8,
     pub file: String,
     pub message: String,
@@ -51,6 +59,18 @@ pub struct Settings {
     pub loop_count: u64,
     pub single_message: bool,
     pub clean_session: bool,
+    pub will_topic: String,
+    pub will_message: String,
+    pub will_qos: QoS,
+    pub will_retain: bool,
+    pub discover: bool,
+    pub search_radius: u8,
+    pub discovery_timeout: u64,
+    pub multicast_group: String,
+    pub multicast_hops: u32,
+    pub multicast_interface: Option<MulticastInterface>,
+    pub unsubscribe_on_exit: bool,
+    pub sleep_cycle: bool,
 }
 
 
@@ -70,18 +90,23 @@ pub fn default_settings() -> Settings {
         baudrate: MQTT_SN_DEFAULT_BAUDRATE,
         keep_alive: MQTT_SN_DEFAULT_KEEP_ALIVE,
         timeout: MQTT_SN_DEFAULT_TIMEOUT,
-        network_timeout: 100,
-        network_retries: 3,
+        network_timeout: MQTT_SN_DEFAULT_RETRY_TIMEOUT,
+        network_retries: MQTT_SN_DEFAULT_RETRIES,
+        t_wait: MQTT_SN_DEFAULT_T_WAIT,
         sleep_duration: 0,
         wireless_node_id: 0,
-        qos: 0,
+        qos: QoS::AtMostOnce,
         source_port: 0,
+        transport: SensorNetworkType::UDP,
+        address_family: AddressFamily::Any,
+        dtls: DtlsSecurity::default(),
         topic_id: 0,
-        topic_id_type: 0,
+        topic_id_type: TopicIdType::Normal,
         retain: false,
         one_message_per_line: false,
-        client_id: client_id,
+        client_id,
         forwarder_encapsulation: false,
+        forwarder_radius: 0,
         debug_level: 0,
         file: String::from(""),
         message: String::from(""),
@@ -97,65 +122,17 @@ pub fn default_settings() -> Settings {
         loop_count: 0,
         clean_session: true,
         single_message: false,
+        will_topic: String::from(""),
+        will_message: String::from(""),
+        will_qos: QoS::AtMostOnce,
+        will_retain: false,
+        discover: false,
+        search_radius: MQTT_SN_DEFAULT_SEARCH_RADIUS,
+        discovery_timeout: MQTT_SN_DEFAULT_DISCOVERY_TIMEOUT,
+        multicast_group: String::new(),
+        multicast_hops: 1,
+        multicast_interface: None,
+        unsubscribe_on_exit: false,
+        sleep_cycle: false,
     }
 }
-
-thread_local! {
-    static MESSAGE_ID: std::cell::Cell<u16> = std::cell::Cell::new(0);
-    static TOPIC_ID: std::cell::Cell<u16> = std::cell::Cell::new(0);
-    static WIRELESS_NODE_ID: std::cell::Cell<[u8; MQTT_SN_MAX_WIRELESS_NODE_ID_LENGTH]> = std::cell::Cell::new([0; MQTT_SN_MAX_WIRELESS_NODE_ID_LENGTH]);
-}
-
-pub fn get_next_message_id() -> u16 {
-    let current_id = MESSAGE_ID.with(|cell| cell.get());
-    let next_id = current_id + 1;
-    MESSAGE_ID.with(|cell| cell.set(next_id));
-    next_id
-}
-
-pub fn get_last_message_id() -> u16 {
-    let current_id = MESSAGE_ID.with(|cell| cell.get());
-    current_id
-}
-
-pub fn reset_message_id() {
-    MESSAGE_ID.with(|cell| cell.set(0));
-}
-
-pub fn get_topic_id() -> u16 {
-    let current_topic_id: u16 = TOPIC_ID.with(|cell| cell.get());
-    current_topic_id
-}
-
-pub fn set_topic_id(topic_id: u16) {
-    TOPIC_ID.with(|cell| cell.set(topic_id));
-}
-
-pub fn set_wireless_node_id(wireless_node_id: Vec<u8>) {
-    WIRELESS_NODE_ID.with(|cell| {
-        let mut wireless_node_id_array = [0; MQTT_SN_MAX_WIRELESS_NODE_ID_LENGTH];
-        for (i, byte) in wireless_node_id.iter().enumerate() {
-            wireless_node_id_array[i] = *byte;
-            if i == MQTT_SN_MAX_WIRELESS_NODE_ID_LENGTH - 1 {
-                break;
-            }
-        }
-        cell.set(wireless_node_id_array);
-    });
-}
-
-pub fn get_wireless_node_id() -> Vec<u8> {
-    let wireless_node_id = WIRELESS_NODE_ID.with(|cell| cell.get());
-    wireless_node_id.to_vec()
-}
-
-
-pub fn get_qos_flag(qos: i8) -> u8 {
-    match qos {
-        0 => MQTT_SN_FLAG_QOS_0,
-        1 => MQTT_SN_FLAG_QOS_1,
-        2 => 0x40,
-        -1 => MQTT_SN_FLAG_QOS_N1,
-        _ => 0,
-    }
-}
\ No newline at end of file